@@ -88,4 +88,43 @@ fn partial_eval_with_context2(b: &mut Bencher) {
             xn = nx;
         }
     });
-}
\ No newline at end of file
+}
+#[bench]
+fn compiled_eval(b: &mut Bencher) {
+    let mut compiled = Expr::new(EXPR2).unwrap()
+        .set_var("a1", 2.0)
+        .set_var("a2", 3.0)
+        .compile(&["x"]);
+
+    let mut xn = 1.0;
+    b.iter(|| {
+        for _ in 0..N {
+            let nx = compiled.eval(&[xn]).unwrap();
+            xn = nx;
+        }
+    });
+}
+
+#[bench]
+fn compiled_eval2(b: &mut Bencher) {
+    let mut compiled = Expr::new(EXPR3).unwrap().compile(&["x", "y"]);
+
+    let mut xn = 1.0;
+    b.iter(|| {
+        for _ in 0..N {
+            let nx = compiled.eval(&[xn, 1.0]).unwrap();
+            xn = nx;
+        }
+    });
+}
+
+#[bench]
+fn batch_eval(b: &mut Bencher) {
+    let mut compiled = Expr::new("a1 * a2 + x * x - y / 2").unwrap().set_var("a1", 2.0).set_var("a2", 3.0).compile(&["x", "y"]);
+    let xs: Vec<f64> = (0..N).map(|i| i as f64).collect();
+    let mut out = vec![0.0; N];
+
+    b.iter(|| {
+        compiled.eval_batch(&[Column::Slice(&xs), Column::Scalar(1.0)], &mut out).unwrap();
+    });
+}
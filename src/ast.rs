@@ -0,0 +1,201 @@
+use crate::token::{Value, UnaryOp, BinaryOp, Function, Token};
+use crate::cell::CellRef;
+use std::fmt;
+
+/// A node of a parsed expression tree.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Node {
+    Value(Value),
+    /// Number literal whose source text is more precise than its `f64` value, as `0.1000000000000000000001`.
+    Literal(Value, String),
+    Var(String),
+    Unary(UnaryOp, Box<Node>),
+    Binary(BinaryOp, Box<Node>, Box<Node>),
+    Call(Function, Vec<Node>),
+    /// `if(cond, then, otherwise)` or `cond ? then : otherwise`; only the taken branch is evaluated.
+    Cond(Box<Node>, Box<Node>, Box<Node>),
+    /// Cell range `A1:C10`, only valid as an argument of `SUM`, `AVERAGE` and `COUNT`.
+    Range(CellRef, CellRef),
+}
+
+impl Node {
+    pub fn unary(op: UnaryOp, arg: Node) -> Self {
+        Self::Unary(op, Box::new(arg))
+    }
+
+    pub fn binary(op: BinaryOp, lhs: Node, rhs: Node) -> Self {
+        Self::Binary(op, Box::new(lhs), Box::new(rhs))
+    }
+
+    pub fn call(func: Function, args: Vec<Node>) -> Self {
+        Self::Call(func, args)
+    }
+
+    pub fn cond(cond: Node, then: Node, otherwise: Node) -> Self {
+        Self::Cond(Box::new(cond), Box::new(then), Box::new(otherwise))
+    }
+
+    pub fn as_value(&self) -> Option<Value> {
+        match self {
+            Self::Value(v) | Self::Literal(v, _) => Some(*v),
+            _ => None,
+        }
+    }
+
+    pub fn children(&self) -> Vec<&Node> {
+        match self {
+            Self::Value(_) | Self::Literal(..) | Self::Var(_) | Self::Range(..) => vec![],
+            Self::Unary(_, arg) => vec![arg],
+            Self::Binary(_, lhs, rhs) => vec![lhs, rhs],
+            Self::Call(_, args) => args.iter().collect(),
+            Self::Cond(cond, then, otherwise) => vec![cond, then, otherwise],
+        }
+    }
+
+    pub fn accept<V: Visitor + ?Sized>(&self, visitor: &mut V) {
+        visitor.visit_node(self);
+    }
+
+    pub fn fold<F: Fold + ?Sized>(&self, folder: &mut F) -> F::Output {
+        match self {
+            Self::Value(v) => folder.fold_value(*v),
+            Self::Literal(v, s) => folder.fold_literal(*v, s),
+            Self::Var(s) => folder.fold_var(s),
+            Self::Range(from, to) => folder.fold_range(*from, *to),
+            Self::Unary(op, arg) => {
+                let arg = arg.fold(folder);
+                folder.fold_unary(*op, arg)
+            },
+            Self::Binary(op, lhs, rhs) => {
+                let lhs = lhs.fold(folder);
+                let rhs = rhs.fold(folder);
+                folder.fold_binary(*op, lhs, rhs)
+            },
+            Self::Call(func, args) => {
+                let args = args.iter().map(|a| a.fold(folder)).collect();
+                folder.fold_call(func, args)
+            },
+            Self::Cond(cond, then, otherwise) => {
+                let cond = cond.fold(folder);
+                let then = then.fold(folder);
+                let otherwise = otherwise.fold(folder);
+                folder.fold_cond(cond, then, otherwise)
+            },
+        }
+    }
+}
+
+impl Node {
+    /// Binding powers of the outermost operator, as in [`Token::precedence`].
+    pub(crate) fn precedence(&self) -> (u8, u8) {
+        match self {
+            Self::Unary(op, _) => Token::Unary(*op).precedence(),
+            Self::Binary(op, ..) => Token::Binary(*op).precedence(),
+            Self::Value(v) if v.is_sign_negative() && !v.is_nan() => Token::Unary(UnaryOp::Neg).precedence(),
+            Self::Cond(..) => (Token::Question.precedence().0, Token::Colon.precedence().1),
+            _ => (100, 100),
+        }
+    }
+
+    /// Writes `self` as an operand, with parentheses if `parens`.
+    fn fmt_operand(&self, f: &mut fmt::Formatter, parens: bool) -> fmt::Result {
+        if parens {write!(f, "({})", self)}
+        else {write!(f, "{}", self)}
+    }
+}
+
+/// Formats a number so that it parses back to the same value.
+fn fmt_value(v: Value, f: &mut fmt::Formatter) -> fmt::Result {
+    if v.is_nan() {
+        write!(f, "(0 / 0)")
+    }
+    else if v.is_infinite() {
+        write!(f, "{}1e999", if v < 0.0 {"-"} else {""})
+    }
+    else {
+        let decimal = v.to_string();
+        let scientific = format!("{:e}", v);
+        // plain decimals are easier to read unless they are much longer
+        if scientific.len() + 2 < decimal.len() {write!(f, "{}", scientific)}
+        else {write!(f, "{}", decimal)}
+    }
+}
+
+/// Infix notation with only the necessary parentheses; it parses back to an equal tree.
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Value(v) => fmt_value(*v, f),
+            Self::Literal(_, s) => write!(f, "{}", s),
+            Self::Var(s) => write!(f, "{}", s),
+            Self::Range(from, to) => write!(f, "{}:{}", from, to),
+            Self::Unary(op, arg) => {
+                let (_, r) = Token::Unary(*op).precedence();
+                write!(f, "{}", op)?;
+                arg.fmt_operand(f, arg.precedence().0 <= r)
+            },
+            Self::Binary(op, lhs, rhs) => {
+                let (l, r) = Token::Binary(*op).precedence();
+                lhs.fmt_operand(f, lhs.precedence().1 < l)?;
+                if *op == BinaryOp::Pow {write!(f, "{}", op)?;}
+                else {write!(f, " {} ", op)?;}
+                rhs.fmt_operand(f, rhs.precedence().0 <= r)
+            },
+            // parses back as the imaginary literal
+            Self::Call(Function::ImaginaryUnit, args) if args.is_empty() => write!(f, "1i"),
+            Self::Call(func, args) => {
+                write!(f, "{}(", func.name())?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {write!(f, ", ")?;}
+                    write!(f, "{}", arg)?;
+                }
+                write!(f, ")")
+            },
+            Self::Cond(cond, then, otherwise) => {
+                let (l, _) = Token::Question.precedence();
+                let (_, r) = Token::Colon.precedence();
+                cond.fmt_operand(f, cond.precedence().1 < l)?;
+                write!(f, " ? ")?;
+                then.fmt_operand(f, matches!(**then, Self::Cond(..)))?;
+                write!(f, " : ")?;
+                otherwise.fmt_operand(f, otherwise.precedence().0 <= r)
+            },
+        }
+    }
+}
+
+/// Read-only traversal of a [`Node`] tree.
+///
+/// Override `visit_node` and call [`walk_node`] to continue into the children.
+pub trait Visitor {
+    fn visit_node(&mut self, node: &Node) {
+        walk_node(self, node);
+    }
+}
+
+pub fn walk_node<V: Visitor + ?Sized>(visitor: &mut V, node: &Node) {
+    for child in node.children() {
+        visitor.visit_node(child);
+    }
+}
+
+/// Bottom-up transformation of a [`Node`] tree.
+///
+/// Children are folded first and their results are passed to the parent's method.
+pub trait Fold {
+    type Output;
+    fn fold_value(&mut self, v: Value) -> Self::Output;
+    /// Defaults to folding the `f64` value of the literal.
+    fn fold_literal(&mut self, v: Value, _text: &str) -> Self::Output {
+        self.fold_value(v)
+    }
+    fn fold_var(&mut self, name: &str) -> Self::Output;
+    /// Defaults to folding the range like a variable named `A1:C10`.
+    fn fold_range(&mut self, from: CellRef, to: CellRef) -> Self::Output {
+        self.fold_var(&format!("{}:{}", from, to))
+    }
+    fn fold_unary(&mut self, op: UnaryOp, arg: Self::Output) -> Self::Output;
+    fn fold_binary(&mut self, op: BinaryOp, lhs: Self::Output, rhs: Self::Output) -> Self::Output;
+    fn fold_call(&mut self, func: &Function, args: Vec<Self::Output>) -> Self::Output;
+    fn fold_cond(&mut self, cond: Self::Output, then: Self::Output, otherwise: Self::Output) -> Self::Output;
+}
@@ -0,0 +1,73 @@
+use crate::error::{EvalError, Span};
+use crate::token::Value;
+use std::str::FromStr;
+
+/// A spreadsheet cell such as `B2`, with zero-based column and row indices.
+///
+/// `$` markers of absolute references are accepted when parsing and dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CellRef {
+    pub col: usize,
+    pub row: usize,
+}
+
+impl CellRef {
+    pub fn new(col: usize, row: usize) -> Self {
+        Self {col, row}
+    }
+}
+
+impl FromStr for CellRef {
+    type Err = EvalError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || EvalError::InvalidString(s.to_owned(), Span::new(0, s.len()));
+        let rest = s.strip_prefix('$').unwrap_or(s);
+        let split = rest.find(|c: char| !c.is_ascii_uppercase()).ok_or_else(invalid)?;
+        let (letters, rest) = rest.split_at(split);
+        let digits = rest.strip_prefix('$').unwrap_or(rest);
+        if letters.is_empty() || digits.is_empty() || digits.starts_with('0') || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        // bijective base 26: A = 1, Z = 26, AA = 27
+        let col = letters.bytes().try_fold(0usize, |acc, b| {
+            acc.checked_mul(26)?.checked_add((b - b'A') as usize + 1)
+        });
+        let row = digits.parse::<usize>().ok();
+        match (col, row) {
+            (Some(col), Some(row)) => Ok(Self::new(col - 1, row - 1)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl std::fmt::Display for CellRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut letters = vec![];
+        let mut col = self.col + 1;
+        while col > 0 {
+            letters.push(b'A' + ((col - 1) % 26) as u8);
+            col = (col - 1) / 26;
+        }
+        letters.reverse();
+        write!(f, "{}{}", String::from_utf8_lossy(&letters), self.row + 1)
+    }
+}
+
+/// Supplies the values of cell references, see [`Context::set_resolver`](crate::Context::set_resolver).
+pub trait CellResolver: Send + Sync {
+    /// Value of `cell`, or `None` if it is empty.
+    fn cell(&self, cell: CellRef) -> Option<Value>;
+
+    /// Values of the non-empty cells in the rectangle spanned by `from` and `to`, row by row.
+    ///
+    /// The default calls [`CellResolver::cell`] for every cell of the rectangle, so a range
+    /// such as `A1:XFD1048576` visits billions of cells. Resolvers for large or sparse sheets,
+    /// or evaluating untrusted formulas, should override this to visit only the stored cells
+    /// or to bound the size of the rectangle.
+    fn range(&self, from: CellRef, to: CellRef) -> Vec<Value> {
+        let cols = from.col.min(to.col)..=from.col.max(to.col);
+        let rows = from.row.min(to.row)..=from.row.max(to.row);
+        rows.flat_map(|row| cols.clone().filter_map(move |col| self.cell(CellRef::new(col, row))))
+            .collect()
+    }
+}
@@ -0,0 +1,655 @@
+use crate::token::{Function, Value, UnaryOp, BinaryOp, to_bool, from_bool};
+use crate::ast::Node;
+use crate::context::{Context, FuncClosure};
+use crate::numeric::check_supported;
+use crate::error::EvalError;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Instr {
+    Const(Value),
+    Load(usize),
+    Unary(UnaryOp),
+    Binary(BinaryOp),
+    Call1(Function),
+    Call2(Function),
+    CallN(Function, usize),
+    Custom(usize, usize),
+    /// Call with cell range arguments, by its index in the range calls and the number of values it pops.
+    CallRanges(Function, usize, usize),
+    /// Normalizes the top of the stack to `1.0` or `0.0`.
+    Truthy,
+    Jump(usize),
+    /// Pops the condition and jumps if it is false.
+    JumpIfFalse(usize),
+    /// Short circuit of `&&`: leaves `0.0` and jumps if the top is false, otherwise pops it.
+    AndJump(usize),
+    /// Short circuit of `||`: leaves `1.0` and jumps if the top is true, otherwise pops it.
+    OrJump(usize),
+    /// Replaces the condition below the two branch values with the selected one.
+    Select,
+    Fail(usize),
+}
+
+/// An argument of a call taking cell ranges.
+#[derive(Debug, Clone, PartialEq)]
+enum RangeArg {
+    /// Popped from the stack.
+    Stack,
+    /// Values of the cells, resolved at compile time.
+    Cells(Vec<Value>),
+}
+
+/// Whether conditionals are compiled to jumps or to an eager `Select`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Branching {
+    Jump,
+    Select,
+}
+
+/// Number of rows evaluated by each instruction in the batch evaluator.
+const CHUNK: usize = 256;
+
+/// Number of rows handed to each rayon task.
+#[cfg(feature="rayon")]
+const PAR_CHUNK: usize = 16 * CHUNK;
+
+/// A variable binding for batch evaluation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Column<'a> {
+    Slice(&'a [Value]),
+    Scalar(Value),
+}
+
+impl<'a> From<&'a [Value]> for Column<'a> {
+    fn from(s: &'a [Value]) -> Self {
+        Self::Slice(s)
+    }
+}
+
+impl<'a> From<&'a Vec<Value>> for Column<'a> {
+    fn from(s: &'a Vec<Value>) -> Self {
+        Self::Slice(s)
+    }
+}
+
+impl From<Value> for Column<'_> {
+    fn from(v: Value) -> Self {
+        Self::Scalar(v)
+    }
+}
+
+/// An `Expr` compiled to bytecode, with variables resolved to slot indices.
+///
+/// Variables that are not given a slot are taken from the context at compile time.
+#[derive(Debug, Clone)]
+pub struct CompiledExpr {
+    programs: Vec<Vec<Instr>>,
+    batch_programs: Vec<Vec<Instr>>,
+    /// Whether each batch program calls custom functions in a branch that may not be taken.
+    rowwise: Vec<bool>,
+    funcs: Vec<FuncClosure>,
+    range_calls: Vec<Vec<RangeArg>>,
+    errors: Vec<EvalError>,
+    slots: Vec<String>,
+    stack: Vec<Value>,
+    batch_stack: Vec<Vec<Value>>,
+    args: Vec<Value>,
+}
+
+struct Compiler<'a> {
+    context: &'a Context,
+    slots: &'a [String],
+    branching: Branching,
+    /// Number of enclosing branches that are only evaluated for some rows.
+    lazy: usize,
+    /// Set when a custom function is called inside such a branch of a `Select` program.
+    rowwise: bool,
+    funcs: Vec<FuncClosure>,
+    func_names: Vec<String>,
+    range_calls: Vec<Vec<RangeArg>>,
+    errors: Vec<EvalError>,
+}
+
+impl Compiler<'_> {
+    fn fail(&mut self, err: EvalError, program: &mut Vec<Instr>) {
+        program.push(Instr::Fail(self.errors.len()));
+        self.errors.push(err);
+    }
+
+    /// Emits a call with cell range arguments.
+    ///
+    /// Cell ranges are resolved at compile time like the variables in the context and kept
+    /// out of the stack. Calls taking nothing but ranges are folded to a constant.
+    fn emit_range_call(&mut self, func: &Function, args: &[Node], program: &mut Vec<Instr>) {
+        let mut layout = vec![];
+        for arg in args.iter() {
+            match arg {
+                Node::Range(from, to) => {
+                    match self.context.resolve_range(*from, *to) {
+                        Ok(values) => layout.push(RangeArg::Cells(values)),
+                        Err(e) => {
+                            self.fail(e, program);
+                            layout.push(RangeArg::Stack);
+                        }
+                    }
+                },
+                arg => {
+                    self.emit(arg, program);
+                    layout.push(RangeArg::Stack);
+                }
+            }
+        }
+        let n = layout.iter().filter(|arg| **arg == RangeArg::Stack).count();
+        if n == 0 {
+            let mut values = vec![];
+            fill_range_args(&layout, std::iter::empty(), &mut values);
+            program.push(Instr::Const(func.evaln(&values)));
+        }
+        else {
+            program.push(Instr::CallRanges(func.clone(), self.range_calls.len(), n));
+            self.range_calls.push(layout);
+        }
+    }
+
+    fn emit(&mut self, node: &Node, program: &mut Vec<Instr>) {
+        match node {
+            Node::Value(v) | Node::Literal(v, _) => {
+                program.push(Instr::Const(*v));
+            },
+            Node::Var(s) => {
+                if let Some(i) = self.slots.iter().position(|slot| slot == s) {
+                    program.push(Instr::Load(i));
+                }
+                else if let Some(v) = self.context.lookup(s) {
+                    program.push(Instr::Const(v));
+                }
+                else {
+                    self.fail(EvalError::UndefinedVariable(s.clone()), program);
+                }
+            },
+            Node::Range(from, to) => {
+                self.fail(EvalError::MisplacedRange(format!("{}:{}", from, to)), program);
+            },
+            Node::Unary(op, arg) => {
+                self.emit(arg, program);
+                program.push(Instr::Unary(*op));
+            },
+            Node::Binary(op @ (BinaryOp::And | BinaryOp::Or), lhs, rhs) if self.branching == Branching::Jump => {
+                self.emit(lhs, program);
+                let jump = program.len();
+                program.push(if *op == BinaryOp::And {Instr::AndJump(0)} else {Instr::OrJump(0)});
+                self.emit(rhs, program);
+                program.push(Instr::Truthy);
+                let end = program.len();
+                if let Some(Instr::AndJump(target) | Instr::OrJump(target)) = program.get_mut(jump) {
+                    *target = end;
+                }
+            },
+            Node::Binary(op, lhs, rhs) => {
+                self.emit(lhs, program);
+                let lazy = matches!(op, BinaryOp::And | BinaryOp::Or);
+                self.lazy += lazy as usize;
+                self.emit(rhs, program);
+                self.lazy -= lazy as usize;
+                program.push(Instr::Binary(*op));
+            },
+            Node::Cond(cond, then, otherwise) => {
+                self.emit(cond, program);
+                if self.branching == Branching::Select {
+                    self.lazy += 1;
+                    self.emit(then, program);
+                    self.emit(otherwise, program);
+                    self.lazy -= 1;
+                    program.push(Instr::Select);
+                    return;
+                }
+                let jump_else = program.len();
+                program.push(Instr::JumpIfFalse(0));
+                self.emit(then, program);
+                let jump_end = program.len();
+                program.push(Instr::Jump(0));
+                program[jump_else] = Instr::JumpIfFalse(program.len());
+                self.emit(otherwise, program);
+                program[jump_end] = Instr::Jump(program.len());
+            },
+            Node::Call(Function::Custom(s), args) => {
+                let Some(fc) = self.context.get_func(s) else {
+                    self.fail(EvalError::UndefinedFunction(s.clone()), program);
+                    return;
+                };
+                if !fc.arity().accepts(args.len()) {
+                    let err = EvalError::WrongArguments {
+                        name: s.clone(),
+                        expected: fc.arity(),
+                        found: args.len(),
+                        span: None
+                    };
+                    self.fail(err, program);
+                    return;
+                }
+                for arg in args.iter() {
+                    self.emit(arg, program);
+                }
+                if self.branching == Branching::Select && self.lazy > 0 {
+                    self.rowwise = true;
+                }
+                let id = match self.func_names.iter().position(|name| name == s) {
+                    Some(id) => id,
+                    None => {
+                        self.funcs.push(fc.clone());
+                        self.func_names.push(s.clone());
+                        self.funcs.len() - 1
+                    }
+                };
+                program.push(Instr::Custom(id, args.len()));
+            },
+            Node::Call(func, args) => {
+                if !func.arity().accepts(args.len()) {
+                    let err = EvalError::WrongArguments {
+                        name: func.name().to_owned(),
+                        expected: func.arity(),
+                        found: args.len(),
+                        span: None
+                    };
+                    self.fail(err, program);
+                    return;
+                }
+                if let Err(e) = check_supported::<Value>(func) {
+                    self.fail(e, program);
+                    return;
+                }
+                if args.iter().any(|arg| matches!(arg, Node::Range(..))) {
+                    self.emit_range_call(func, args, program);
+                    return;
+                }
+                for arg in args.iter() {
+                    self.emit(arg, program);
+                }
+                match args.len() {
+                    1 => program.push(Instr::Call1(func.clone())),
+                    2 => program.push(Instr::Call2(func.clone())),
+                    n => program.push(Instr::CallN(func.clone(), n)),
+                }
+            },
+        }
+    }
+}
+
+fn max_depth(program: &[Instr]) -> usize {
+    // jumps never increase the depth reached by the straight-line sequence
+    let mut depth: isize = 0;
+    let mut max = 0;
+    for instr in program.iter() {
+        depth += match instr {
+            Instr::Const(_) | Instr::Load(_) | Instr::Fail(_) => 1,
+            Instr::Binary(_) | Instr::Call2(_) | Instr::JumpIfFalse(_) | Instr::AndJump(_) | Instr::OrJump(_) => -1,
+            Instr::CallN(_, n) | Instr::Custom(_, n) | Instr::CallRanges(_, _, n) => 1 - *n as isize,
+            Instr::Select => -2,
+            _ => 0,
+        };
+        max = max.max(depth);
+    }
+    max as usize
+}
+
+impl CompiledExpr {
+    pub(crate) fn new(nodes: &[Node], context: &Context, vars: &[&str]) -> Self {
+        let slots = vars.iter().map(|v| v.to_string()).collect::<Vec<String>>();
+        let mut compiler = Compiler {
+            context,
+            slots: &slots,
+            branching: Branching::Jump,
+            lazy: 0,
+            rowwise: false,
+            funcs: vec![],
+            func_names: vec![],
+            range_calls: vec![],
+            errors: vec![],
+        };
+        let mut programs = vec![];
+        for node in nodes.iter() {
+            let mut program = vec![];
+            compiler.emit(node, &mut program);
+            programs.push(program);
+        }
+        compiler.branching = Branching::Select;
+        let mut batch_programs = vec![];
+        let mut rowwise = vec![];
+        for node in nodes.iter() {
+            let mut program = vec![];
+            compiler.rowwise = false;
+            compiler.emit(node, &mut program);
+            rowwise.push(compiler.rowwise || program.iter().any(|instr| matches!(instr, Instr::Fail(_))));
+            batch_programs.push(program);
+        }
+        let depth = programs.iter().map(|p| max_depth(p)).max().unwrap_or(0);
+        let Compiler {funcs, range_calls, errors, ..} = compiler;
+        Self {
+            programs,
+            batch_programs,
+            rowwise,
+            funcs,
+            range_calls,
+            errors,
+            slots,
+            stack: Vec::with_capacity(depth),
+            batch_stack: vec![],
+            args: vec![],
+        }
+    }
+
+    pub fn slots(&self) -> &[String] {
+        &self.slots
+    }
+
+    pub fn len(&self) -> usize {
+        self.programs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.programs.is_empty()
+    }
+
+    pub fn eval(&mut self, slots: &[Value]) -> Result<Value, EvalError> {
+        self.eval_index(0, slots)
+    }
+
+    pub fn evals(&mut self, slots: &[Value]) -> Result<Vec<Value>, EvalError> {
+        (0..self.programs.len()).map(|id| self.eval_index(id, slots)).collect()
+    }
+
+    pub fn eval_index(&mut self, id: usize, slots: &[Value]) -> Result<Value, EvalError> {
+        if self.programs.len() <= id {
+            return Err(EvalError::WrongExprIndex(id));
+        }
+        if slots.len() != self.slots.len() {
+            return Err(EvalError::WrongSlots(self.slots.len()));
+        }
+        let program = &self.programs[id];
+        let stack = &mut self.stack;
+        stack.clear();
+        let mut pc = 0;
+        while let Some(instr) = program.get(pc) {
+            pc += 1;
+            match instr {
+                Instr::Const(v) => {stack.push(*v);},
+                Instr::Load(i) => {stack.push(slots[*i]);},
+                Instr::Unary(op) => {
+                    let v = top(stack)?;
+                    *v = op.eval(*v);
+                },
+                Instr::Binary(op) => {
+                    let v2 = pop(stack)?;
+                    let v1 = top(stack)?;
+                    *v1 = op.eval(*v1, v2);
+                },
+                Instr::Call1(func) => {
+                    let v = top(stack)?;
+                    *v = func.eval1(*v);
+                },
+                Instr::Call2(func) => {
+                    let v2 = pop(stack)?;
+                    let v1 = top(stack)?;
+                    *v1 = func.eval2((*v1, v2));
+                },
+                Instr::CallN(func, n) => {
+                    let base = args_base(stack, *n)?;
+                    let v = func.evaln(&stack[base..]);
+                    stack.truncate(base);
+                    stack.push(v);
+                },
+                Instr::Custom(f, n) => {
+                    let base = args_base(stack, *n)?;
+                    let v = self.funcs[*f].call(&stack[base..]);
+                    stack.truncate(base);
+                    stack.push(v);
+                },
+                Instr::CallRanges(func, id, n) => {
+                    let base = args_base(stack, *n)?;
+                    fill_range_args(&self.range_calls[*id], stack[base..].iter().copied(), &mut self.args);
+                    let v = func.evaln(&self.args);
+                    stack.truncate(base);
+                    stack.push(v);
+                },
+                Instr::Truthy => {
+                    let v = top(stack)?;
+                    *v = from_bool(to_bool(*v));
+                },
+                Instr::Jump(target) => {
+                    pc = *target;
+                },
+                Instr::JumpIfFalse(target) => {
+                    if !to_bool(pop(stack)?) {
+                        pc = *target;
+                    }
+                },
+                Instr::AndJump(target) | Instr::OrJump(target) => {
+                    let is_or = matches!(instr, Instr::OrJump(_));
+                    let v = top(stack)?;
+                    if to_bool(*v) == is_or {
+                        *v = from_bool(is_or);
+                        pc = *target;
+                    }
+                    else {
+                        stack.pop();
+                    }
+                },
+                Instr::Select => {
+                    let otherwise = pop(stack)?;
+                    let then = pop(stack)?;
+                    let cond = top(stack)?;
+                    *cond = if to_bool(*cond) {then} else {otherwise};
+                },
+                Instr::Fail(e) => {
+                    return Err(self.errors[*e].clone());
+                },
+            }
+        }
+        if stack.len() != 1 {
+            return Err(stack_error());
+        }
+        pop(stack)
+    }
+
+    /// Evaluates the first expression for every row, binding `columns[i]` to the slot `i`.
+    ///
+    /// Both branches of conditionals are evaluated for every row. Expressions that fail to
+    /// compile in some branch, such as with an undefined variable, or that call custom functions
+    /// inside a branch are evaluated row by row instead, so that only the branches taken can
+    /// fail or run the custom functions.
+    pub fn eval_batch(&mut self, columns: &[Column], out: &mut [Value]) -> Result<(), EvalError> {
+        self.eval_batch_index(0, columns, out)
+    }
+
+    pub fn eval_batch_index(&mut self, id: usize, columns: &[Column], out: &mut [Value]) -> Result<(), EvalError> {
+        if self.batch_programs.len() <= id {
+            return Err(EvalError::WrongExprIndex(id));
+        }
+        if columns.len() != self.slots.len() {
+            return Err(EvalError::WrongSlots(self.slots.len()));
+        }
+        if columns.iter().any(|c| matches!(c, Column::Slice(s) if s.len() != out.len())) {
+            return Err(EvalError::WrongColumnLength(out.len()));
+        }
+        if self.rowwise[id] {
+            return self.eval_rows(id, columns, out);
+        }
+        let depth = max_depth(&self.batch_programs[id]);
+        if self.batch_stack.len() < depth {
+            self.batch_stack.resize(depth, vec![0.0; CHUNK]);
+        }
+        let mut start = 0;
+        for out_chunk in out.chunks_mut(CHUNK) {
+            let end = start + out_chunk.len();
+            self.eval_chunk(id, columns, start..end)?;
+            out_chunk.copy_from_slice(&self.batch_stack[0][..out_chunk.len()]);
+            start = end;
+        }
+        Ok(())
+    }
+
+    /// Same as [`CompiledExpr::eval_batch`], but the rows are split across the rayon thread pool.
+    #[cfg(feature="rayon")]
+    pub fn par_eval_batch(&self, columns: &[Column], out: &mut [Value]) -> Result<(), EvalError> {
+        self.par_eval_batch_index(0, columns, out)
+    }
+
+    #[cfg(feature="rayon")]
+    pub fn par_eval_batch_index(&self, id: usize, columns: &[Column], out: &mut [Value]) -> Result<(), EvalError> {
+        use rayon::prelude::*;
+
+        if self.batch_programs.len() <= id {
+            return Err(EvalError::WrongExprIndex(id));
+        }
+        if columns.len() != self.slots.len() {
+            return Err(EvalError::WrongSlots(self.slots.len()));
+        }
+        if columns.iter().any(|c| matches!(c, Column::Slice(s) if s.len() != out.len())) {
+            return Err(EvalError::WrongColumnLength(out.len()));
+        }
+        out.par_chunks_mut(PAR_CHUNK).enumerate().try_for_each_init(
+            || self.clone(),
+            |compiled, (i, out_chunk)| {
+                let rows = i * PAR_CHUNK..i * PAR_CHUNK + out_chunk.len();
+                let columns: Vec<Column> = columns.iter()
+                    .map(|c| match c {
+                        Column::Slice(s) => Column::Slice(&s[rows.clone()]),
+                        Column::Scalar(v) => Column::Scalar(*v),
+                    })
+                    .collect();
+                compiled.eval_batch_index(id, &columns, out_chunk)
+            }
+        )
+    }
+
+    fn eval_rows(&mut self, id: usize, columns: &[Column], out: &mut [Value]) -> Result<(), EvalError> {
+        let mut slots = vec![0.0; columns.len()];
+        for (row, v) in out.iter_mut().enumerate() {
+            for (slot, column) in slots.iter_mut().zip(columns) {
+                *slot = match column {
+                    Column::Slice(s) => s[row],
+                    Column::Scalar(v) => *v,
+                };
+            }
+            *v = self.eval_index(id, &slots)?;
+        }
+        Ok(())
+    }
+
+    fn eval_chunk(&mut self, id: usize, columns: &[Column], rows: std::ops::Range<usize>) -> Result<(), EvalError> {
+        let n = rows.len();
+        let stack = &mut self.batch_stack;
+        let mut sp = 0;
+        for instr in self.batch_programs[id].iter() {
+            match instr {
+                Instr::Const(v) => {
+                    stack[sp][..n].fill(*v);
+                    sp += 1;
+                },
+                Instr::Load(i) => {
+                    match columns[*i] {
+                        Column::Slice(s) => stack[sp][..n].copy_from_slice(&s[rows.clone()]),
+                        Column::Scalar(v) => stack[sp][..n].fill(v),
+                    }
+                    sp += 1;
+                },
+                Instr::Unary(op) => {
+                    op.eval_slice(&mut stack[sp - 1][..n]);
+                },
+                Instr::Binary(op) => {
+                    let (lhs, rhs) = binary_operands(stack, sp, n);
+                    op.eval_slice(lhs, rhs);
+                    sp -= 1;
+                },
+                Instr::Call1(func) => {
+                    for x in stack[sp - 1][..n].iter_mut() {
+                        *x = func.eval1(*x);
+                    }
+                },
+                Instr::Call2(func) => {
+                    let (lhs, rhs) = binary_operands(stack, sp, n);
+                    for (l, r) in lhs.iter_mut().zip(rhs) {
+                        *l = func.eval2((*l, *r));
+                    }
+                    sp -= 1;
+                },
+                Instr::CallN(_, k) | Instr::Custom(_, k) => {
+                    let base = sp - k;
+                    for row in 0..n {
+                        self.args.clear();
+                        self.args.extend(stack[base..sp].iter().map(|arg| arg[row]));
+                        let v = match instr {
+                            Instr::Custom(f, _) => self.funcs[*f].call(&self.args),
+                            Instr::CallN(func, _) => func.evaln(&self.args),
+                            _ => unreachable!(),
+                        };
+                        stack[base][row] = v;
+                    }
+                    sp = base + 1;
+                },
+                Instr::CallRanges(func, id, k) => {
+                    let base = sp - k;
+                    for row in 0..n {
+                        fill_range_args(&self.range_calls[*id], stack[base..sp].iter().map(|arg| arg[row]), &mut self.args);
+                        stack[base][row] = func.evaln(&self.args);
+                    }
+                    sp = base + 1;
+                },
+                Instr::Truthy => {
+                    for x in stack[sp - 1][..n].iter_mut() {
+                        *x = from_bool(to_bool(*x));
+                    }
+                },
+                Instr::Select => {
+                    let (conds, branches) = stack.split_at_mut(sp - 2);
+                    let cond = &mut conds[sp - 3][..n];
+                    let (then, otherwise) = (&branches[0][..n], &branches[1][..n]);
+                    for ((c, t), o) in cond.iter_mut().zip(then).zip(otherwise) {
+                        *c = if to_bool(*c) {*t} else {*o};
+                    }
+                    sp -= 2;
+                },
+                Instr::Fail(e) => {
+                    return Err(self.errors[*e].clone());
+                },
+                Instr::Jump(_) | Instr::JumpIfFalse(_) | Instr::AndJump(_) | Instr::OrJump(_) => {
+                    unreachable!("batch programs have no jumps")
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Collects the arguments of a call taking cell ranges into `args`, in order,
+/// taking the values of the arguments that are not ranges from `stack_args`.
+fn fill_range_args(layout: &[RangeArg], mut stack_args: impl Iterator<Item=Value>, args: &mut Vec<Value>) {
+    args.clear();
+    for arg in layout.iter() {
+        match arg {
+            RangeArg::Stack => args.extend(stack_args.next()),
+            RangeArg::Cells(values) => args.extend_from_slice(values),
+        }
+    }
+}
+
+fn binary_operands(stack: &mut [Vec<Value>], sp: usize, n: usize) -> (&mut [Value], &[Value]) {
+    let (lhs, rhs) = stack.split_at_mut(sp - 1);
+    (&mut lhs[sp - 2][..n], &rhs[0][..n])
+}
+
+fn stack_error() -> EvalError {
+    EvalError::WrongExpression(Default::default())
+}
+
+fn pop(stack: &mut Vec<Value>) -> Result<Value, EvalError> {
+    stack.pop().ok_or_else(stack_error)
+}
+
+fn top(stack: &mut [Value]) -> Result<&mut Value, EvalError> {
+    stack.last_mut().ok_or_else(stack_error)
+}
+
+fn args_base(stack: &[Value], n: usize) -> Result<usize, EvalError> {
+    stack.len().checked_sub(n).ok_or_else(stack_error)
+}
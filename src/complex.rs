@@ -0,0 +1,156 @@
+use crate::numeric::Numeric;
+use crate::token::{Value, UnaryOp, BinaryOp, Function};
+use crate::error::EvalError;
+use num_complex::Complex64;
+
+/// Complex numbers with the imaginary unit `i` or `j`.
+///
+/// Comparisons, `min` and `max` order by the real parts, and `abs`, `hypot`, `re`, `im`
+/// and `arg` return real numbers.
+impl Numeric for Complex64 {
+    fn from_f64(v: Value) -> Self {
+        Complex64::new(v, 0.0)
+    }
+
+    fn to_bool(&self) -> bool {
+        self.re != 0.0 || self.im != 0.0
+    }
+
+    fn imaginary_unit() -> Option<Self> {
+        Some(Complex64::i())
+    }
+
+    fn unary(op: UnaryOp, arg: Self) -> Result<Self, EvalError> {
+        let v = match op {
+            // `0 - z` rather than `-z`, so that `sqrt(-1)` is `i` and not `-i` across the branch cut
+            UnaryOp::Neg => real(0.0) - arg,
+            UnaryOp::Not => Self::from_bool(!arg.to_bool()),
+        };
+        Ok(v)
+    }
+
+    fn binary(op: BinaryOp, lhs: Self, rhs: Self) -> Result<Self, EvalError> {
+        let v = match op {
+            BinaryOp::Add => lhs + rhs,
+            BinaryOp::Sub => lhs - rhs,
+            BinaryOp::Mul => lhs * rhs,
+            BinaryOp::Div => lhs / rhs,
+            BinaryOp::Rem => lhs % rhs,
+            BinaryOp::Pow => pow(lhs, rhs),
+            BinaryOp::Eq => Self::from_bool(lhs == rhs),
+            BinaryOp::Ne => Self::from_bool(lhs != rhs),
+            BinaryOp::Lt => Self::from_bool(lhs.re < rhs.re),
+            BinaryOp::Le => Self::from_bool(lhs.re <= rhs.re),
+            BinaryOp::Gt => Self::from_bool(lhs.re > rhs.re),
+            BinaryOp::Ge => Self::from_bool(lhs.re >= rhs.re),
+            BinaryOp::And => Self::from_bool(lhs.to_bool() && rhs.to_bool()),
+            BinaryOp::Or => Self::from_bool(lhs.to_bool() || rhs.to_bool()),
+        };
+        Ok(v)
+    }
+
+    /// Every built-in function.
+    fn supports(_func: &Function) -> bool {
+        true
+    }
+
+    fn call(func: &Function, args: &[Self]) -> Result<Self, EvalError> {
+        let v = match (func, args) {
+            (Function::Min, _) => {
+                args.iter().copied().reduce(|a, b| if b.re < a.re {b} else {a}).unwrap_or(real(Value::INFINITY))
+            },
+            (Function::Max, _) => {
+                args.iter().copied().reduce(|a, b| if b.re > a.re {b} else {a}).unwrap_or(real(Value::NEG_INFINITY))
+            },
+            (Function::Hypot, _) => real(args.iter().fold(0.0, |acc, z| acc.hypot(z.norm()))),
+            (Function::Sum, _) => args.iter().sum(),
+            (Function::Average, _) => args.iter().sum::<Complex64>() / args.len() as Value,
+            (Function::Count, _) => real(args.len() as Value),
+            (Function::ImaginaryUnit, _) => Complex64::i(),
+            (_, [z]) => call1(func, *z),
+            (_, [lhs, rhs]) => call2(func, *lhs, *rhs),
+            _ => {unreachable!()}
+        };
+        Ok(v)
+    }
+}
+
+fn real(v: Value) -> Complex64 {
+    Complex64::new(v, 0.0)
+}
+
+fn call1(func: &Function, z: Complex64) -> Complex64 {
+    let parts = |f: fn(Value) -> Value| Complex64::new(f(z.re), f(z.im));
+    match func {
+        Function::Sin => z.sin(),
+        Function::Cos => z.cos(),
+        Function::Tan => z.tan(),
+        Function::Asin => z.asin(),
+        Function::Acos => z.acos(),
+        Function::Atan => z.atan(),
+        Function::Sinh => z.sinh(),
+        Function::Cosh => z.cosh(),
+        Function::Tanh => z.tanh(),
+        Function::Sqrt => z.sqrt(),
+        Function::Cbrt => z.cbrt(),
+        Function::Ln => z.ln(),
+        Function::Log10 => z.log10(),
+        Function::Log2 => z.log2(),
+        Function::Exp => z.exp(),
+        Function::Exp2 => z.exp2(),
+        Function::Floor => parts(Value::floor),
+        Function::Ceil => parts(Value::ceil),
+        Function::Round => parts(Value::round),
+        Function::Fract => parts(Value::fract),
+        Function::Trunc => parts(Value::trunc),
+        Function::Abs => real(z.norm()),
+        Function::Signum => {
+            if z.re == 0.0 && z.im == 0.0 {z}
+            else {z / z.norm()}
+        },
+        Function::Re => real(z.re),
+        Function::Im => real(z.im),
+        Function::Conj => z.conj(),
+        Function::Arg => real(z.arg()),
+        _ => {unreachable!()}
+    }
+}
+
+fn call2(func: &Function, lhs: Complex64, rhs: Complex64) -> Complex64 {
+    match func {
+        Function::Log => lhs.ln() / rhs.ln(),
+        Function::Powi => lhs.powi(rhs.re as i32), // unchecked
+        Function::Powf => pow(lhs, rhs),
+        Function::DivEuclid => div_euclid(lhs, rhs),
+        Function::RemEuclid => lhs - rhs * div_euclid(lhs, rhs),
+        _ => {unreachable!()}
+    }
+}
+
+/// Principal value of `z^w`, exact for integer exponents.
+fn pow(z: Complex64, w: Complex64) -> Complex64 {
+    if w.im == 0.0 && w.re.fract() == 0.0 && w.re.abs() <= i32::MAX as Value {
+        z.powi(w.re as i32)
+    }
+    else if z.im == 0.0 && w.im == 0.0 && z.re >= 0.0 {
+        real(z.re.powf(w.re))
+    }
+    else if z.re == 0.0 && z.im == 0.0 && w.re > 0.0 {
+        // `powc` goes through `ln(0)`
+        real(0.0)
+    }
+    else {
+        z.powc(w)
+    }
+}
+
+/// Euclidean division of real numbers, and the componentwise floor of the quotient otherwise.
+fn div_euclid(lhs: Complex64, rhs: Complex64) -> Complex64 {
+    if lhs.im == 0.0 && rhs.im == 0.0 {
+        real(lhs.re.div_euclid(rhs.re))
+    }
+    else {
+        let q = lhs / rhs;
+        Complex64::new(q.re.floor(), q.im.floor())
+    }
+}
@@ -1,100 +1,238 @@
 use crate::eval::Expr;
+use crate::cell::{CellRef, CellResolver};
 use crate::error::EvalError;
-use crate::token::Value;
+use crate::numeric::Numeric;
+use crate::token::{Value, Arity};
+use std::sync::Arc;
 #[cfg(not(feature="fxhash"))]
 use std::collections::HashMap;
 #[cfg(feature="fxhash")]
 use fxhash::FxHashMap;
 
-#[derive(Debug, Clone)]
-pub struct FuncClosure {
-    arg_len: usize,
-    func: fn(&[Value]) -> Value
+pub type SharedFunc<T = Value> = Arc<dyn Fn(&[T]) -> T + Send + Sync>;
+
+pub struct FuncClosure<T = Value> {
+    arity: Arity,
+    func: SharedFunc<T>
+}
+
+// derived `Clone` would require `T: Clone`
+impl<T> Clone for FuncClosure<T> {
+    fn clone(&self) -> Self {
+        Self {
+            arity: self.arity,
+            func: self.func.clone()
+        }
+    }
 }
 
-impl FuncClosure {
-    pub(crate) fn new(f: fn(&[Value]) -> Value, n: usize) -> Self {
+impl<T> FuncClosure<T> {
+    pub(crate) fn new(f: SharedFunc<T>, arity: Arity) -> Self {
         Self {
-            arg_len: n,
+            arity,
             func: f
         }
     }
 
-    pub(crate) fn get_arg_len(&self) -> usize {
-        self.arg_len
+    pub fn arity(&self) -> Arity {
+        self.arity
     }
     
-    pub(crate) fn call(&self, x: &[Value]) -> Value {
+    pub fn call(&self, x: &[T]) -> T {
         (self.func)(x)
     }
 }
 
+impl<T> std::fmt::Debug for FuncClosure<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("FuncClosure")
+            .field("arity", &self.arity)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Clone)]
+struct Resolver(Arc<dyn CellResolver>);
+
+impl std::fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Resolver").finish_non_exhaustive()
+    }
+}
+
+/// Variables, custom functions and cell values of type `T`.
+///
+/// Use `Context::<T>::default()` for number types other than `f64`.
 #[cfg(not(feature="fxhash"))]
-#[derive(Debug, Clone, Default)]
-pub struct Context {
-    value_map: HashMap<String, Value>,
-    func_map: HashMap<String, FuncClosure>,
+#[derive(Debug, Clone)]
+pub struct Context<T = Value> {
+    value_map: HashMap<String, T>,
+    func_map: HashMap<String, FuncClosure<T>>,
+    deriv_map: HashMap<String, Vec<String>>,
+    resolver: Option<Resolver>,
+    float_fallback: bool,
 }
 
+/// Variables, custom functions and cell values of type `T`.
+///
+/// Use `Context::<T>::default()` for number types other than `f64`.
 #[cfg(feature="fxhash")]
-#[derive(Debug, Clone, Default)]
-pub struct Context {
-    value_map: FxHashMap<String, Value>,
-    func_map: FxHashMap<String, FuncClosure>,
+#[derive(Debug, Clone)]
+pub struct Context<T = Value> {
+    value_map: FxHashMap<String, T>,
+    func_map: FxHashMap<String, FuncClosure<T>>,
+    deriv_map: FxHashMap<String, Vec<String>>,
+    resolver: Option<Resolver>,
+    float_fallback: bool,
 }
 
-impl Context {
+impl<T> Default for Context<T> {
     #[cfg(not(feature="fxhash"))]
-    pub fn new() -> Self {
+    fn default() -> Self {
         Self {
             value_map: HashMap::new(),
-            func_map: HashMap::new()
+            func_map: HashMap::new(),
+            deriv_map: HashMap::new(),
+            resolver: None,
+            float_fallback: false
         }
     }
 
     #[cfg(feature="fxhash")]
-    pub fn new() -> Self {
+    fn default() -> Self {
         Self {
             value_map: FxHashMap::default(),
-            func_map: FxHashMap::default()
+            func_map: FxHashMap::default(),
+            deriv_map: FxHashMap::default(),
+            resolver: None,
+            float_fallback: false
         }
     }
+}
 
-    pub fn set_value(&mut self, key: &str, val: Value) -> &mut Self {
+impl Context {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<T: Numeric> Context<T> {
+    pub fn set_value(&mut self, key: &str, val: T) -> &mut Self {
         self.value_map.insert(key.to_owned(), val);
         self
     }
 
-    pub fn get_value(&self, key: &str) -> Option<&Value> {
+    pub fn get_value(&self, key: &str) -> Option<&T> {
         self.value_map.get(key)
     }
 
-    pub fn set_func(&mut self, key: &str, n: usize, f: fn(&[Value]) -> Value) -> &mut Self {
-        self.func_map.insert(key.to_owned(), FuncClosure::new(f, n));
+    pub fn set_func<F>(&mut self, key: &str, arity: impl Into<Arity>, f: F) -> &mut Self
+    where F: Fn(&[T]) -> T + Send + Sync + 'static {
+        self.set_shared_func(key, arity, Arc::new(f))
+    }
+
+    pub fn set_shared_func(&mut self, key: &str, arity: impl Into<Arity>, f: SharedFunc<T>) -> &mut Self {
+        self.func_map.insert(key.to_owned(), FuncClosure::new(f, arity.into()));
         self
     }
 
-    pub fn get_func(&self, key: &str) -> Option<&FuncClosure> {
+    pub fn get_func(&self, key: &str) -> Option<&FuncClosure<T>> {
         self.func_map.get(key)
     }
 
-    
-    pub fn ctx_merge(lhs: &Context, rhs: &Context) -> Self {
+    /// Registers the partial derivatives of the custom function `key`.
+    ///
+    /// `partials[i]` names a function taking the same arguments that computes the
+    /// derivative with respect to the `i`-th argument.
+    pub fn set_derivative(&mut self, key: &str, partials: &[&str]) -> &mut Self {
+        self.deriv_map.insert(key.to_owned(), partials.iter().map(|s| s.to_string()).collect());
+        self
+    }
+
+    pub fn get_derivative(&self, key: &str) -> Option<&[String]> {
+        self.deriv_map.get(key).map(|v| v.as_slice())
+    }
+
+    /// Sets the source of cell values for expressions parsed by [`Expr::with_cell_refs`].
+    ///
+    /// Values set with [`Context::set_value`] take precedence over the resolver.
+    pub fn set_resolver<R: CellResolver + 'static>(&mut self, resolver: R) -> &mut Self {
+        self.resolver = Some(Resolver(Arc::new(resolver)));
+        self
+    }
+
+    pub fn resolver(&self) -> Option<&dyn CellResolver> {
+        self.resolver.as_ref().map(|r| r.0.as_ref())
+    }
+
+    /// Computes built-in functions and operators without an exact result in `T`, and built-in
+    /// functions `T` does not support, with `f64` instead of failing with [`EvalError::Inexact`]
+    /// or [`EvalError::UnsupportedFunction`].
+    ///
+    /// Only applies to number types that implement [`Numeric::to_f64`].
+    pub fn set_float_fallback(&mut self, fallback: bool) -> &mut Self {
+        self.float_fallback = fallback;
+        self
+    }
+
+    pub fn float_fallback(&self) -> bool {
+        self.float_fallback
+    }
+
+    /// Whether no values, functions or resolver are set.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.value_map.is_empty() && self.func_map.is_empty() && self.resolver.is_none()
+    }
+
+    /// Value of the variable `key`, falling back to the resolver if `key` is a cell
+    /// and to the imaginary unit if `key` is `i` or `j`.
+    pub(crate) fn lookup(&self, key: &str) -> Option<T> {
+        match self.get_value(key) {
+            Some(v) => Some(v.clone()),
+            None if key == "i" || key == "j" => T::imaginary_unit(),
+            None => self.resolver()?.cell(key.parse::<CellRef>().ok()?).map(T::from_f64),
+        }
+    }
+
+    pub(crate) fn resolve_range(&self, from: CellRef, to: CellRef) -> Result<Vec<Value>, EvalError> {
+        match self.resolver() {
+            Some(resolver) => Ok(resolver.range(from, to)),
+            None => Err(EvalError::UndefinedVariable(format!("{}:{}", from, to))),
+        }
+    }
+
+    /// Same context for the number type `U`, converting values and custom functions.
+    pub(crate) fn map<U>(&self, value: impl Fn(&T) -> U, func: impl Fn(&SharedFunc<T>) -> SharedFunc<U>) -> Context<U> {
+        let mut context = Context::<U>::default();
+        context.value_map.extend(self.value_map.iter().map(|(k, v)| (k.clone(), value(v))));
+        context.func_map.extend(self.func_map.iter().map(|(k, fc)| (k.clone(), FuncClosure::new(func(&fc.func), fc.arity))));
+        context.deriv_map = self.deriv_map.clone();
+        context.resolver = self.resolver.clone();
+        context.float_fallback = self.float_fallback;
+        context
+    }
+
+    pub fn ctx_merge(lhs: &Context<T>, rhs: &Context<T>) -> Self {
         let mut value_map = rhs.value_map.clone();
         value_map.extend(lhs.value_map.clone());
         let mut func_map = rhs.func_map.clone();
         func_map.extend(lhs.func_map.clone());
+        let mut deriv_map = rhs.deriv_map.clone();
+        deriv_map.extend(lhs.deriv_map.clone());
         Self {
             value_map,
             func_map,
+            deriv_map,
+            resolver: lhs.resolver.clone().or_else(|| rhs.resolver.clone()),
+            float_fallback: lhs.float_fallback || rhs.float_fallback,
         }
     }
 
-    pub fn eval(&mut self, expr: &str) -> Result<Value, EvalError> {
-        Expr::new(expr)?.apply_context(self).eval()
+    pub fn eval(&mut self, expr: &str) -> Result<T, EvalError> {
+        Expr::<T>::parse(expr)?.apply_context(self)?.eval()
     }
 
-    pub fn evals(&mut self, expr: &str) -> Result<Vec<Value>, EvalError> {
-        Expr::new(expr)?.apply_context(self).evals()
+    pub fn evals(&mut self, expr: &str) -> Result<Vec<T>, EvalError> {
+        Expr::<T>::parse(expr)?.apply_context(self)?.evals()
     }
 }
\ No newline at end of file
@@ -0,0 +1,292 @@
+use crate::ast::Node;
+use crate::token::{Value, UnaryOp, BinaryOp, Function};
+use crate::context::Context;
+use crate::numeric::check_supported;
+use crate::error::EvalError;
+
+/// Differentiates `node` with respect to the variable `var`.
+///
+/// Variables other than `var` are treated as constants, and the derivatives of
+/// custom functions are looked up in `context`.
+pub(crate) fn differentiate(node: &Node, var: &str, context: &Context) -> Result<Node, EvalError> {
+    Differentiator {var, context}.diff(node)
+}
+
+struct Differentiator<'a> {
+    var: &'a str,
+    context: &'a Context,
+}
+
+impl Differentiator<'_> {
+    fn diff(&self, node: &Node) -> Result<Node, EvalError> {
+        let d = match node {
+            // cells of a range are constants
+            Node::Value(_) | Node::Literal(..) | Node::Range(..) => zero(),
+            Node::Var(s) => {
+                if s == self.var {one()}
+                else {zero()}
+            },
+            Node::Unary(UnaryOp::Neg, arg) => neg(self.diff(arg)?),
+            // logical values are piecewise constant
+            Node::Unary(UnaryOp::Not, _) => zero(),
+            Node::Binary(op, lhs, rhs) => self.diff_binary(*op, lhs, rhs)?,
+            Node::Call(func, args) => self.diff_call(func, args)?,
+            Node::Cond(cond, then, otherwise) => {
+                let then = self.diff(then)?;
+                let otherwise = self.diff(otherwise)?;
+                if is_value(&then, 0.0) && is_value(&otherwise, 0.0) {zero()}
+                else {Node::cond((**cond).clone(), then, otherwise)}
+            },
+        };
+        Ok(d)
+    }
+
+    fn diff_binary(&self, op: BinaryOp, lhs: &Node, rhs: &Node) -> Result<Node, EvalError> {
+        let d = match op {
+            BinaryOp::Add => add(self.diff(lhs)?, self.diff(rhs)?),
+            BinaryOp::Sub => sub(self.diff(lhs)?, self.diff(rhs)?),
+            BinaryOp::Mul => {
+                add(mul(self.diff(lhs)?, rhs.clone()), mul(lhs.clone(), self.diff(rhs)?))
+            },
+            BinaryOp::Div => {
+                let dl = self.diff(lhs)?;
+                let dr = self.diff(rhs)?;
+                if is_value(&dr, 0.0) {div(dl, rhs.clone())}
+                else {
+                    div(
+                        sub(mul(dl, rhs.clone()), mul(lhs.clone(), dr)),
+                        pow(rhs.clone(), Node::Value(2.0))
+                    )
+                }
+            },
+            // a % b == a - b * trunc(a / b)
+            BinaryOp::Rem => {
+                let quotient = Node::call(Function::Trunc, vec![div(lhs.clone(), rhs.clone())]);
+                sub(self.diff(lhs)?, mul(self.diff(rhs)?, quotient))
+            },
+            BinaryOp::Pow => self.diff_pow(lhs, rhs, self.diff(lhs)?, self.diff(rhs)?),
+            BinaryOp::Eq | BinaryOp::Ne | BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge
+            | BinaryOp::And | BinaryOp::Or => zero(),
+        };
+        Ok(d)
+    }
+
+    /// Derivative of `base^exp`, where `db` and `de` are the derivatives of `base` and `exp`.
+    fn diff_pow(&self, base: &Node, exp: &Node, db: Node, de: Node) -> Node {
+        if is_value(&de, 0.0) {
+            let power = pow(base.clone(), sub(exp.clone(), one()));
+            mul(mul(exp.clone(), power), db)
+        }
+        else {
+            // d(a^b) = a^b * (b' * ln(a) + b * a' / a)
+            let ln = Node::call(Function::Ln, vec![base.clone()]);
+            mul(
+                pow(base.clone(), exp.clone()),
+                add(mul(de, ln), div(mul(exp.clone(), db), base.clone()))
+            )
+        }
+    }
+
+    fn diff_call(&self, func: &Function, args: &[Node]) -> Result<Node, EvalError> {
+        let not_differentiable = || EvalError::NotDifferentiable(func.name().to_owned());
+        if !matches!(func, Function::Custom(_)) && !func.arity().accepts(args.len()) {
+            return Err(EvalError::WrongArguments {
+                name: func.name().to_owned(),
+                expected: func.arity(),
+                found: args.len(),
+                span: None
+            });
+        }
+        if !matches!(func, Function::Custom(_)) {
+            check_supported::<Value>(func)?;
+        }
+        let dargs = args.iter().map(|arg| self.diff(arg)).collect::<Result<Vec<Node>, EvalError>>()?;
+        // constant in `var`, even for functions that are not differentiable
+        if dargs.iter().all(|d| is_value(d, 0.0)) {
+            return Ok(zero());
+        }
+        if let [arg] = args {
+            if !matches!(func, Function::Min | Function::Max | Function::Hypot | Function::Custom(_)) && !func.is_aggregate() {
+                let outer = self.outer_derivative(func, arg).ok_or_else(not_differentiable)?;
+                return Ok(mul(outer, dargs[0].clone()));
+            }
+        }
+        let d = match func {
+            Function::Powf => self.diff_pow(&args[0], &args[1], dargs[0].clone(), dargs[1].clone()),
+            Function::Powi => {
+                if !is_value(&dargs[1], 0.0) {
+                    return Err(not_differentiable());
+                }
+                // the exponent is truncated like in evaluation
+                let n = Node::call(Function::Trunc, vec![args[1].clone()]);
+                let power = Node::call(Function::Powi, vec![args[0].clone(), sub(n.clone(), one())]);
+                mul(mul(n, power), dargs[0].clone())
+            },
+            // log(a, b) == ln(a) / ln(b)
+            Function::Log => {
+                let ln = |arg: &Node| Node::call(Function::Ln, vec![arg.clone()]);
+                let (ln_a, ln_b) = (ln(&args[0]), ln(&args[1]));
+                let dl = div(dargs[0].clone(), args[0].clone());
+                let dr = div(dargs[1].clone(), args[1].clone());
+                if is_value(&dr, 0.0) {div(dl, ln_b)}
+                else {div(sub(mul(dl, ln_b.clone()), mul(ln_a, dr)), pow(ln_b, Node::Value(2.0)))}
+            },
+            // rem_euclid(a, b) == a - b * div_euclid(a, b)
+            Function::RemEuclid => {
+                let quotient = Node::call(Function::DivEuclid, args.to_vec());
+                sub(dargs[0].clone(), mul(dargs[1].clone(), quotient))
+            },
+            Function::Hypot => {
+                let mut sum = zero();
+                for (arg, d) in args.iter().zip(dargs) {
+                    sum = add(sum, mul(arg.clone(), d));
+                }
+                div(sum, Node::call(Function::Hypot, args.to_vec()))
+            },
+            Function::Min | Function::Max => {
+                let (first, rest) = args.split_first().ok_or_else(not_differentiable)?;
+                let (rest, otherwise) = match rest {
+                    [] => return Ok(dargs[0].clone()),
+                    [arg] => (arg.clone(), dargs[1].clone()),
+                    _ => {
+                        let rest = Node::call(func.clone(), rest.to_vec());
+                        let d = self.diff(&rest)?;
+                        (rest, d)
+                    },
+                };
+                let op = if *func == Function::Min {BinaryOp::Le} else {BinaryOp::Ge};
+                let then = dargs[0].clone();
+                if is_value(&then, 0.0) && is_value(&otherwise, 0.0) {zero()}
+                else {
+                    // `rest != rest` when `rest` is NaN, which `min` and `max` skip
+                    let cond = Node::binary(
+                        BinaryOp::Or,
+                        Node::binary(op, first.clone(), rest.clone()),
+                        Node::binary(BinaryOp::Ne, rest.clone(), rest)
+                    );
+                    Node::cond(cond, then, otherwise)
+                }
+            },
+            Function::Sum | Function::Average => {
+                let sum = dargs.into_iter().fold(zero(), add);
+                if *func == Function::Sum {sum}
+                else {div(sum, Node::call(Function::Count, args.to_vec()))}
+            },
+            Function::Count => zero(),
+            Function::Custom(name) => {
+                let partials = self.context.get_derivative(name)
+                    .filter(|partials| partials.len() == args.len())
+                    .ok_or_else(not_differentiable)?;
+                let mut sum = zero();
+                for (partial, d) in partials.iter().zip(dargs) {
+                    let outer = Node::call(Function::Custom(partial.clone()), args.to_vec());
+                    sum = add(sum, mul(outer, d));
+                }
+                sum
+            },
+            _ => return Err(not_differentiable()),
+        };
+        Ok(d)
+    }
+
+    /// Derivative of a single-argument built-in function evaluated at `arg`.
+    fn outer_derivative(&self, func: &Function, arg: &Node) -> Option<Node> {
+        let call = |func: Function| Node::call(func, vec![arg.clone()]);
+        let square = |node: Node| pow(node, Node::Value(2.0));
+        let d = match func {
+            Function::Sin => call(Function::Cos),
+            Function::Cos => neg(call(Function::Sin)),
+            Function::Tan => add(one(), square(call(Function::Tan))),
+            Function::Asin => div(one(), call_sqrt(sub(one(), square(arg.clone())))),
+            Function::Acos => neg(div(one(), call_sqrt(sub(one(), square(arg.clone()))))),
+            Function::Atan => div(one(), add(one(), square(arg.clone()))),
+            Function::Sinh => call(Function::Cosh),
+            Function::Cosh => call(Function::Sinh),
+            Function::Tanh => sub(one(), square(call(Function::Tanh))),
+            Function::Sqrt => div(one(), mul(Node::Value(2.0), call(Function::Sqrt))),
+            Function::Cbrt => div(one(), mul(Node::Value(3.0), square(call(Function::Cbrt)))),
+            Function::Exp => call(Function::Exp),
+            Function::Exp2 => mul(call(Function::Exp2), Node::Value(std::f64::consts::LN_2)),
+            Function::Ln => div(one(), arg.clone()),
+            Function::Log10 => div(one(), mul(arg.clone(), Node::Value(std::f64::consts::LN_10))),
+            Function::Log2 => div(one(), mul(arg.clone(), Node::Value(std::f64::consts::LN_2))),
+            Function::Fract => one(),
+            Function::Abs => call(Function::Signum),
+            _ => return None,
+        };
+        Some(d)
+    }
+}
+
+fn call_sqrt(arg: Node) -> Node {
+    Node::call(Function::Sqrt, vec![arg])
+}
+
+fn zero() -> Node {
+    Node::Value(0.0)
+}
+
+fn one() -> Node {
+    Node::Value(1.0)
+}
+
+fn is_value(node: &Node, v: Value) -> bool {
+    node.as_value() == Some(v)
+}
+
+// The constructors below drop the trivial terms produced by the chain rule.
+
+fn neg(arg: Node) -> Node {
+    match arg {
+        Node::Value(v) => Node::Value(-v),
+        Node::Unary(UnaryOp::Neg, arg) => *arg,
+        arg => Node::unary(UnaryOp::Neg, arg),
+    }
+}
+
+fn add(lhs: Node, rhs: Node) -> Node {
+    match (lhs.as_value(), rhs.as_value()) {
+        (Some(l), Some(r)) => Node::Value(l + r),
+        (Some(0.0), _) => rhs,
+        (_, Some(0.0)) => lhs,
+        _ => Node::binary(BinaryOp::Add, lhs, rhs),
+    }
+}
+
+fn sub(lhs: Node, rhs: Node) -> Node {
+    match (lhs.as_value(), rhs.as_value()) {
+        (Some(l), Some(r)) => Node::Value(l - r),
+        (Some(0.0), _) => neg(rhs),
+        (_, Some(0.0)) => lhs,
+        _ => Node::binary(BinaryOp::Sub, lhs, rhs),
+    }
+}
+
+fn mul(lhs: Node, rhs: Node) -> Node {
+    match (lhs.as_value(), rhs.as_value()) {
+        (Some(l), Some(r)) => Node::Value(l * r),
+        (Some(0.0), _) | (_, Some(0.0)) => zero(),
+        (Some(1.0), _) => rhs,
+        (_, Some(1.0)) => lhs,
+        (Some(-1.0), _) => neg(rhs),
+        (_, Some(-1.0)) => neg(lhs),
+        _ => Node::binary(BinaryOp::Mul, lhs, rhs),
+    }
+}
+
+fn div(lhs: Node, rhs: Node) -> Node {
+    match (lhs.as_value(), rhs.as_value()) {
+        (Some(l), Some(r)) => Node::Value(l / r),
+        (Some(0.0), _) => zero(),
+        (_, Some(1.0)) => lhs,
+        _ => Node::binary(BinaryOp::Div, lhs, rhs),
+    }
+}
+
+fn pow(base: Node, exp: Node) -> Node {
+    match exp.as_value() {
+        Some(0.0) => one(),
+        Some(1.0) => base,
+        _ => Node::binary(BinaryOp::Pow, base, exp),
+    }
+}
@@ -0,0 +1,270 @@
+use crate::ast::Node;
+use crate::token::{Value, UnaryOp, BinaryOp, Function, to_bool};
+use crate::context::{Context, FuncClosure};
+use crate::eval::{Evaluator, eval_node};
+use crate::numeric::check_supported;
+use crate::error::EvalError;
+use std::f64::consts::{LN_2, LN_10};
+
+/// A value together with its partial derivatives.
+///
+/// An empty `grad` stands for a constant, so constants do not allocate.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Dual {
+    value: Value,
+    grad: Vec<Value>,
+}
+
+impl Dual {
+    fn constant(value: Value) -> Self {
+        Self {value, grad: vec![]}
+    }
+
+    /// Chain rule for `f(self)` where `f'(self.value) == d`.
+    fn map(mut self, value: Value, d: Value) -> Self {
+        self.value = value;
+        self.grad.iter_mut().for_each(|g| *g = scale(*g, d));
+        self
+    }
+
+    /// Chain rule for `f(self, rhs)` with the partial derivatives `dl` and `dr`.
+    fn zip(self, rhs: Self, value: Value, dl: Value, dr: Value) -> Self {
+        let grad = match (self.grad.is_empty(), rhs.grad.is_empty()) {
+            (true, true) => vec![],
+            (false, true) => return self.map(value, dl),
+            (true, false) => return rhs.map(value, dr),
+            (false, false) => {
+                self.grad.iter().zip(rhs.grad.iter()).map(|(l, r)| scale(*l, dl) + scale(*r, dr)).collect()
+            },
+        };
+        Self {value, grad}
+    }
+
+    fn select(self, rhs: Self, first: bool) -> Self {
+        if first {self} else {rhs}
+    }
+
+    /// Whether every partial derivative is zero.
+    fn is_constant(&self) -> bool {
+        self.grad.iter().all(|g| *g == 0.0)
+    }
+
+    fn pow(self, rhs: Self) -> Self {
+        let (a, b) = (self.value, rhs.value);
+        let value = a.powf(b);
+        let dr = if rhs.grad.is_empty() {0.0} else {value * a.ln()};
+        self.zip(rhs, value, power_rule(a, b), dr)
+    }
+}
+
+/// Partial derivative `g` scaled by the local derivative `d`.
+///
+/// Zero partials stay zero even if `d` is infinite or NaN, so that they agree with the
+/// symbolic derivative, where the factor is dropped.
+fn scale(g: Value, d: Value) -> Value {
+    if g == 0.0 {0.0} else {g * d}
+}
+
+/// Derivative of `a^b` with respect to `a`, which is zero for `b == 0` as in the symbolic derivative.
+fn power_rule(a: Value, b: Value) -> Value {
+    if b == 0.0 {0.0}
+    else {b * a.powf(b - 1.0)}
+}
+
+/// Error for functions that are not differentiable, unless their arguments are constant.
+fn step(func: &Function, args: &[&Dual]) -> Result<(), EvalError> {
+    if args.iter().all(|arg| arg.is_constant()) {Ok(())}
+    else {Err(EvalError::NotDifferentiable(func.name().to_owned()))}
+}
+
+/// Evaluates `node` and its gradient with respect to `vars`.
+pub(crate) fn eval_gradient(node: &Node, context: &Context, vars: &[&str]) -> Result<(Value, Vec<Value>), EvalError> {
+    for var in vars.iter() {
+        if context.lookup(var).is_none() {
+            return Err(EvalError::UndefinedVariable(var.to_string()));
+        }
+    }
+    let Dual {value, mut grad} = eval_node(node, &GradientEval {context, vars})?;
+    grad.resize(vars.len(), 0.0);
+    Ok((value, grad))
+}
+
+struct GradientEval<'a> {
+    context: &'a Context,
+    vars: &'a [&'a str],
+}
+
+impl Evaluator for GradientEval<'_> {
+    type Output = Dual;
+    type Number = Value;
+
+    fn context(&self) -> &Context {
+        self.context
+    }
+
+    fn value(&self, v: Value) -> Result<Dual, EvalError> {
+        Ok(Dual::constant(v))
+    }
+
+    fn var(&self, name: &str, v: Value) -> Dual {
+        match self.vars.iter().position(|var| *var == name) {
+            Some(i) => {
+                let mut grad = vec![0.0; self.vars.len()];
+                grad[i] = 1.0;
+                Dual {value: v, grad}
+            },
+            None => Dual::constant(v),
+        }
+    }
+
+    fn truth(&self, v: &Dual) -> Option<bool> {
+        Some(to_bool(v.value))
+    }
+
+    fn join(&self, then: Dual, _otherwise: Dual) -> Dual {
+        // the truth value of a dual number is always known
+        then
+    }
+
+    fn unary(&self, op: UnaryOp, arg: Dual) -> Result<Dual, EvalError> {
+        let result = match op {
+            UnaryOp::Neg => {
+                let value = -arg.value;
+                arg.map(value, -1.0)
+            },
+            UnaryOp::Not => Dual::constant(op.eval(arg.value)),
+        };
+        Ok(result)
+    }
+
+    fn binary(&self, op: BinaryOp, lhs: Dual, rhs: Dual) -> Result<Dual, EvalError> {
+        let (a, b) = (lhs.value, rhs.value);
+        let result = match op {
+            BinaryOp::Add => lhs.zip(rhs, a + b, 1.0, 1.0),
+            BinaryOp::Sub => lhs.zip(rhs, a - b, 1.0, -1.0),
+            BinaryOp::Mul => lhs.zip(rhs, a * b, b, a),
+            BinaryOp::Div => lhs.zip(rhs, a / b, 1.0 / b, -a / (b * b)),
+            BinaryOp::Rem => lhs.zip(rhs, a % b, 1.0, -(a / b).trunc()),
+            BinaryOp::Pow => lhs.pow(rhs),
+            BinaryOp::Eq | BinaryOp::Ne | BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge
+            | BinaryOp::And | BinaryOp::Or => Dual::constant(op.eval(a, b)),
+        };
+        Ok(result)
+    }
+
+    fn call1(&self, func: &Function, arg: Dual) -> Result<Dual, EvalError> {
+        check_supported::<Value>(func)?;
+        let x = arg.value;
+        let value = func.eval1(x);
+        let d = match func {
+            Function::Sin => x.cos(),
+            Function::Cos => -x.sin(),
+            Function::Tan => 1.0 + value * value,
+            Function::Asin => 1.0 / (1.0 - x * x).sqrt(),
+            Function::Acos => -1.0 / (1.0 - x * x).sqrt(),
+            Function::Atan => 1.0 / (1.0 + x * x),
+            Function::Sinh => x.cosh(),
+            Function::Cosh => x.sinh(),
+            Function::Tanh => 1.0 - value * value,
+            Function::Sqrt => 0.5 / value,
+            Function::Cbrt => 1.0 / (3.0 * value * value),
+            Function::Exp => value,
+            Function::Exp2 => value * LN_2,
+            Function::Ln => 1.0 / x,
+            Function::Log10 => 1.0 / (x * LN_10),
+            Function::Log2 => 1.0 / (x * LN_2),
+            Function::Fract | Function::Min | Function::Max | Function::Sum | Function::Average => 1.0,
+            Function::Abs | Function::Hypot => x.signum(),
+            Function::Count => 0.0,
+            // piecewise constant, which `derivative` does not differentiate either
+            _ => {
+                step(func, &[&arg])?;
+                0.0
+            },
+        };
+        Ok(arg.map(value, d))
+    }
+
+    fn call2(&self, func: &Function, lhs: Dual, rhs: Dual) -> Result<Dual, EvalError> {
+        check_supported::<Value>(func)?;
+        let (a, b) = (lhs.value, rhs.value);
+        let value = func.eval2((a, b));
+        let result = match func {
+            // like `f64::min` and `f64::max`, a NaN operand selects the other one
+            Function::Min => lhs.select(rhs, a <= b || b.is_nan()),
+            Function::Max => lhs.select(rhs, a >= b || b.is_nan()),
+            Function::Powf => lhs.pow(rhs),
+            Function::Powi => {
+                step(func, &[&rhs])?;
+                lhs.map(value, power_rule(a, b.trunc()))
+            },
+            Function::Log => lhs.zip(rhs, value, 1.0 / (a * b.ln()), -value / (b * b.ln())),
+            Function::RemEuclid => lhs.zip(rhs, value, 1.0, -a.div_euclid(b)),
+            Function::Hypot => lhs.zip(rhs, value, a / value, b / value),
+            Function::Sum => lhs.zip(rhs, value, 1.0, 1.0),
+            Function::Average => lhs.zip(rhs, value, 0.5, 0.5),
+            Function::Count => Dual::constant(value),
+            _ => {
+                step(func, &[&lhs, &rhs])?;
+                Dual::constant(value)
+            },
+        };
+        Ok(result)
+    }
+
+    fn calln(&self, func: &Function, args: Vec<Dual>) -> Result<Dual, EvalError> {
+        check_supported::<Value>(func)?;
+        match func {
+            Function::Count => return Ok(Dual::constant(args.len() as Value)),
+            Function::Average => {
+                let n = args.len() as Value;
+                let sum = self.calln(&Function::Sum, args)?;
+                let value = sum.value / n;
+                return Ok(sum.map(value, 1.0 / n));
+            },
+            _ => {},
+        }
+        let mut args = args.into_iter();
+        // only aggregates over empty ranges get no arguments
+        let first = match args.next() {
+            Some(first) => first,
+            None => return Ok(Dual::constant(func.evaln(&[]))),
+        };
+        let first = match func {
+            Function::Hypot => self.call1(func, first)?,
+            _ => first,
+        };
+        args.try_fold(first, |acc, arg| self.call2(func, acc, arg))
+    }
+
+    fn custom(&self, name: &str, func: &FuncClosure, args: Vec<Dual>) -> Result<Dual, EvalError> {
+        let values: Vec<Value> = args.iter().map(|arg| arg.value).collect();
+        let mut result = Dual::constant(func.call(&values));
+        if args.iter().all(|arg| arg.is_constant()) {
+            return Ok(result);
+        }
+        let partials = self.context.get_derivative(name)
+            .filter(|partials| partials.len() == args.len())
+            .ok_or_else(|| EvalError::NotDifferentiable(name.to_owned()))?;
+        for (partial, arg) in partials.iter().zip(args) {
+            if arg.is_constant() {
+                continue;
+            }
+            let fc = self.context.get_func(partial)
+                .ok_or_else(|| EvalError::UndefinedFunction(partial.clone()))?;
+            if !fc.arity().accepts(values.len()) {
+                return Err(EvalError::WrongArguments {
+                    name: partial.clone(),
+                    expected: fc.arity(),
+                    found: values.len(),
+                    span: None
+                });
+            }
+            let d = fc.call(&values);
+            let value = result.value;
+            result = result.zip(arg, value, 1.0, d);
+        }
+        Ok(result)
+    }
+}
+
@@ -1,14 +1,102 @@
+use crate::parse::{parse_str_to_pretokens, Syntax};
+use crate::token::{PreToken, Arity};
+
+/// Byte range `start..end` in the source string.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Self {start, end}
+    }
+
+    pub fn to(self, other: Span) -> Self {
+        Self::new(self.start.min(other.start), self.end.max(other.end))
+    }
+
+    /// Renders the source line containing this span with a caret underline.
+    ///
+    /// ```text
+    /// 1 + 2 $ 3
+    ///       ^
+    /// ```
+    pub fn render(&self, source: &str) -> String {
+        let start = self.start.min(source.len());
+        let end = self.end.clamp(start, source.len());
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..].find('\n').map_or(source.len(), |i| start + i);
+        let column = source[line_start..start].chars().count();
+        let width = source[start..end.min(line_end)].chars().count().max(1);
+        format!(
+            "{}\n{}{}",
+            &source[line_start..line_end],
+            " ".repeat(column),
+            "^".repeat(width)
+        )
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum EvalError {
     FunctionNotFound(String),
     ConstantNotFound,
-    InvalidString(String),
-    UnexpectedParenthesis,
+    InvalidString(String, Span),
+    UnexpectedParenthesis(Span),
     UndefinedFunction(String),
     UndefinedVariable(String),
-    WrongExpression,
-    WrongArguments(usize),
+    WrongExpression(Span),
+    WrongArguments {
+        name: String,
+        expected: Arity,
+        found: usize,
+        span: Option<Span>
+    },
     WrongExprIndex(usize),
+    WrongSlots(usize),
+    WrongColumnLength(usize),
+    NotDifferentiable(String),
+    UndefinedOutput(String),
+    CyclicDependency(Vec<String>),
+    UnsupportedFunction(String),
+    Inexact(String),
+    MisplacedRange(String),
+}
+
+impl EvalError {
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Self::InvalidString(_, span) | Self::UnexpectedParenthesis(span) | Self::WrongExpression(span) => Some(*span),
+            Self::WrongArguments {span, ..} => *span,
+            _ => None,
+        }
+    }
+
+    /// Formats the error message followed by the offending part of `source`.
+    ///
+    /// Errors raised during evaluation point at the first occurrence of the
+    /// undefined variable or function name.
+    pub fn diagnostic(&self, source: &str) -> String {
+        let span = self.span().or_else(|| {
+            let name = match self {
+                Self::UndefinedFunction(s) | Self::UndefinedVariable(s) | Self::NotDifferentiable(s)
+                | Self::MisplacedRange(s) | Self::WrongArguments {name: s, ..} => s,
+                _ => return None,
+            };
+            let syntax = Syntax {cells: matches!(self, Self::MisplacedRange(_)), ..Syntax::default()};
+            parse_str_to_pretokens(source, syntax).ok()?
+                .into_iter()
+                // cells may be written as absolute references such as `$A$1`
+                .find(|(pretoken, _)| matches!(pretoken, PreToken::Literal(s) if s.replace('$', "") == *name))
+                .map(|(_, span)| span)
+        });
+        match span {
+            Some(span) => format!("{}\n{}", self, span.render(source)),
+            None => self.to_string(),
+        }
+    }
 }
 
 impl std::error::Error for EvalError {}
@@ -16,15 +104,25 @@ impl std::error::Error for EvalError {}
 impl std::fmt::Display for EvalError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
-            Self::InvalidString(s) => {write!(f, "string {} is Invalid", s)},
+            Self::InvalidString(s, span) => {write!(f, "string {} at {}..{} is Invalid", s, span.start, span.end)},
             Self::ConstantNotFound => {write!(f, "Consts is not found", )},
             Self::FunctionNotFound(s) => {write!(f, "function {} is unimplemented", s)},
-            Self::UnexpectedParenthesis => {write!(f, "unexpected or unbalanced parehthesis",)},
+            Self::UnexpectedParenthesis(span) => {write!(f, "unexpected or unbalanced parehthesis at {}..{}", span.start, span.end)},
             Self::UndefinedFunction(s) => {write!(f, "function {} is undefined", s)},
             Self::UndefinedVariable(s) => {write!(f, "variable {} is undefined", s)},
-            Self::WrongExpression => {write!(f, "Wrong expression.")},
-            Self::WrongArguments(n) => {write!(f, "Expected number of arguments is {}", n)},
+            Self::WrongExpression(span) => {write!(f, "Wrong expression at {}..{}.", span.start, span.end)},
+            Self::WrongArguments {name, expected, found, ..} => {
+                write!(f, "function {} expects {} arguments, but {} were given", name, expected, found)
+            },
             Self::WrongExprIndex(n) => {write!(f, "Wrong Expr Vec index: {}", n)},
-        }   
+            Self::WrongSlots(n) => {write!(f, "Expected number of slot values is {}", n)},
+            Self::WrongColumnLength(n) => {write!(f, "Expected length of columns is {}", n)},
+            Self::NotDifferentiable(s) => {write!(f, "function {} is not differentiable", s)},
+            Self::UndefinedOutput(s) => {write!(f, "no expression is named {}", s)},
+            Self::CyclicDependency(names) => {write!(f, "formulas form a cycle: {}", names.join(" -> "))},
+            Self::UnsupportedFunction(s) => {write!(f, "function {} is not supported by this number type", s)},
+            Self::Inexact(s) => {write!(f, "{} has no exact result", s)},
+            Self::MisplacedRange(s) => {write!(f, "range {} is not an argument of SUM, AVERAGE or COUNT", s)},
+        }
     }
 }
@@ -1,128 +1,311 @@
-use crate::token::{Function, Token, Value, UnaryOp, BinaryOp};
-use crate::parse::{parse_str_to_rpn};
-use crate::context::Context;
+use crate::token::{Arity, Function, Value, UnaryOp, BinaryOp, to_bool, from_bool};
+use crate::ast::{Node, Visitor, Fold, walk_node};
+use crate::parse::{parse_str_to_ast, Syntax};
+use crate::context::{Context, FuncClosure};
+use crate::compile::{CompiledExpr, Column};
+use crate::diff::differentiate;
+use crate::dual::eval_gradient;
+use crate::simplify::simplify;
+use crate::markup::{to_latex, to_mathml};
+use crate::cell::{CellRef, CellResolver};
+use crate::numeric::{Numeric, check_supported};
+use crate::interval::{Interval, interval_context};
 use crate::error::EvalError;
 
+use std::borrow::Cow;
 use std::ops;
 
+/// Parsed expressions together with the context they are evaluated in.
+///
+/// `T` is the number type of the evaluation, see [`Expr::parse`] for types other than `f64`.
 #[derive(Debug, Clone)]
-pub struct Expr {
-    expr: Vec<Vec<Token>>,
-    context: Context
+pub struct Expr<T = Value> {
+    expr: Vec<Node>,
+    names: Vec<Option<String>>,
+    context: Context<T>
+}
+
+/// The expressions in infix notation, separated by `; `.
+///
+/// The context is not included.
+impl<T: Numeric> std::fmt::Display for Expr<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (i, expr) in self.expr.iter().enumerate() {
+            if i > 0 {write!(f, "; ")?;}
+            if let Some(name) = self.name(i) {write!(f, "{} = ", name)?;}
+            write!(f, "{}", expr)?;
+        }
+        Ok(())
+    }
 }
 
 impl Expr {
     pub fn new(expr: &str) -> Result<Self, EvalError> {
+        Self::parse(expr)
+    }
+
+    /// Same as [`Expr::new`], but reads `A1`, `$B$2` and `A1:C10` as cell references
+    /// and provides `SUM`, `AVERAGE` and `COUNT`.
+    ///
+    /// A range is written without spaces and is only allowed as an argument of these functions.
+    /// Cells take their values from the context first and then from its [`CellResolver`].
+    pub fn with_cell_refs(expr: &str) -> Result<Self, EvalError> {
+        Self::parse_with_cell_refs(expr)
+    }
+
+    pub fn from_nodes(nodes: Vec<Node>) -> Self {
+        Self::with_nodes(nodes)
+    }
+}
+
+impl<T: Numeric> Expr<T> {
+    /// Same as [`Expr::new`] for any number type, as in `Expr::<f32>::parse("x / 3")`.
+    pub fn parse(expr: &str) -> Result<Self, EvalError> {
+        let (names, expr) = parse_str_to_ast(expr, syntax::<T>(false))?.into_iter().unzip();
         Ok(
             Self {
-                expr: parse_str_to_rpn(expr)?,
-                context: Context::new()
+                expr,
+                names,
+                context: Context::default()
             }
         )
     }
-    pub fn set_var(&mut self, var: &str, val: Value) -> &mut Self {
+
+    /// Same as [`Expr::with_cell_refs`] for any number type.
+    pub fn parse_with_cell_refs(expr: &str) -> Result<Self, EvalError> {
+        let (names, expr) = parse_str_to_ast(expr, syntax::<T>(true))?.into_iter().unzip();
+        Ok(
+            Self {
+                expr,
+                names,
+                context: Context::default()
+            }
+        )
+    }
+
+    fn with_nodes(nodes: Vec<Node>) -> Self {
+        Self {
+            expr: nodes,
+            names: vec![],
+            context: Context::default()
+        }
+    }
+
+    /// Output name of the `id`-th expression, given by `name = ...` in the source.
+    pub fn name(&self, id: usize) -> Option<&str> {
+        self.names.get(id).and_then(|name| name.as_deref())
+    }
+
+    /// Index of the expression named `name`. The last one wins if the name is reassigned.
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        (0..self.expr.len()).rev().find(|&i| self.name(i) == Some(name))
+    }
+
+    pub fn nodes(&self) -> &[Node] {
+        &self.expr
+    }
+
+    pub fn nodes_mut(&mut self) -> &mut Vec<Node> {
+        &mut self.expr
+    }
+
+    pub fn context(&self) -> &Context<T> {
+        &self.context
+    }
+
+    pub fn set_var(&mut self, var: &str, val: T) -> &mut Self {
         self.context.set_value(var, val);
         self
     }
 
-    pub fn set_func(&mut self, name: &str, n: usize, f: fn(&[Value]) -> Value) -> &mut Self {
-        self.context.set_func(name, n, f);
+    pub fn set_func<F>(&mut self, name: &str, arity: impl Into<Arity>, f: F) -> &mut Self
+    where F: Fn(&[T]) -> T + Send + Sync + 'static {
+        self.context.set_func(name, arity, f);
         self
     }
 
-    pub fn apply_context(&mut self, ctx: &Context) -> &mut Self {
-        self.context = Context::ctx_merge(&self.context, ctx);
+    /// See [`Context::set_resolver`].
+    pub fn set_resolver<R: CellResolver + 'static>(&mut self, resolver: R) -> &mut Self {
+        self.context.set_resolver(resolver);
         self
     }
 
-    pub fn eval(&self) -> Result<Value, EvalError> {
-        self.eval_index(0)
+    /// See [`Context::set_float_fallback`].
+    pub fn set_float_fallback(&mut self, fallback: bool) -> &mut Self {
+        self.context.set_float_fallback(fallback);
+        self
+    }
+
+    /// See [`Context::set_derivative`].
+    pub fn set_derivative(&mut self, name: &str, partials: &[&str]) -> &mut Self {
+        self.context.set_derivative(name, partials);
+        self
     }
 
-    pub fn evals(&self) -> Result<Vec<Value>, EvalError> {
-        let mut values = vec![];
+    pub fn apply_context(&mut self, ctx: &Context<T>) -> Result<&mut Self, EvalError> {
+        self.context = Context::ctx_merge(&self.context, ctx);
         for expr in self.expr.iter() {
-            values.push(eval_with_context(expr, &self.context)?);
+            check_arity(expr, &self.context)?;
         }
-        Ok(values)
+        Ok(self)
+    }
+
+    pub fn eval(&self) -> Result<T, EvalError> {
+        self.eval_index(0)
     }
 
-    pub fn eval_index(&self, id: usize) -> Result<Value, EvalError> {
+    pub fn evals(&self) -> Result<Vec<T>, EvalError> {
+        eval_program(&self.expr, &self.names, &self.context)
+    }
+
+    /// Evaluates the named expressions and pairs the results with their names.
+    pub fn evals_named(&self) -> Result<Vec<(String, T)>, EvalError> {
+        let values = self.evals()?;
+        Ok(
+            values.into_iter()
+                .enumerate()
+                .filter_map(|(i, v)| self.name(i).map(|name| (name.to_owned(), v)))
+                .collect()
+        )
+    }
+
+    pub fn eval_index(&self, id: usize) -> Result<T, EvalError> {
+        self.eval_index_with(id, &self.context)
+    }
+
+    /// Same as [`Expr::eval_index`], but looks the names up in `context` instead.
+    pub(crate) fn eval_index_with(&self, id: usize, context: &Context<T>) -> Result<T, EvalError> {
         if self.expr.len() <= id {
-            Err(EvalError::WrongExprIndex(id))
+            return Err(EvalError::WrongExprIndex(id));
         }
-        else {
-            eval_with_context(&self.expr[id], &self.context)
+        // only the named expressions before `id` can be referenced
+        let mut context = Cow::Borrowed(context);
+        for i in 0..id {
+            if let Some(name) = self.name(i) {
+                let v = eval_with_context(&self.expr[i], &context)?;
+                context.to_mut().set_value(name, v);
+            }
         }
+        eval_with_context(&self.expr[id], &context)
     }
 
-    pub fn partial_eval(&mut self) -> Result<&mut Self, EvalError> {
-        self.partial_eval_index(0)
+    /// Evaluates the expression named `name`.
+    pub fn eval_named(&self, name: &str) -> Result<T, EvalError> {
+        match self.index_of(name) {
+            Some(id) => self.eval_index(id),
+            None => Err(EvalError::UndefinedOutput(name.to_owned())),
+        }
     }
 
-    pub fn partial_evals(&mut self) -> Result<&mut Self, EvalError> {
-        for i in 0..self.expr.len() {
-            self.expr[i] = partial_eval_with_context(&self.expr[i], &self.context)?;
-        }
-        Ok(self)
+    /// Renders each expression as LaTeX math.
+    pub fn to_latex(&self) -> Vec<String> {
+        self.expr.iter().map(to_latex).collect()
     }
 
-    pub fn partial_eval_index(&mut self, id: usize) -> Result<&mut Self, EvalError> {
-        if self.expr.len() <= id {
-            Err(EvalError::WrongExprIndex(id))
+    /// Renders each expression as a MathML `<math>` element.
+    pub fn to_mathml(&self) -> Vec<String> {
+        self.expr.iter().map(to_mathml).collect()
+    }
+
+    /// The names of the expressions before `id`, which shadow the context in the expression `id`.
+    fn bound_before(&self, id: usize) -> Vec<String> {
+        (0..id).filter_map(|i| self.name(i).map(str::to_owned)).collect()
+    }
+
+    /// The expressions with the references to earlier named expressions replaced by their definitions.
+    fn inlined(&self) -> Cow<'_, [Node]> {
+        if self.names.iter().all(|name| name.is_none()) {
+            return Cow::Borrowed(&self.expr);
         }
-        else {
-            self.expr[id] = partial_eval_with_context(&self.expr[id], &self.context)?;
-            Ok(self)
+        let mut defined: Vec<(&str, Node)> = vec![];
+        let mut nodes = vec![];
+        for (i, expr) in self.expr.iter().enumerate() {
+            let mut node = expr.clone();
+            for (var, replacement) in defined.iter() {
+                node = node.fold(&mut Substitute {var, replacement});
+            }
+            if let Some(name) = self.name(i) {
+                defined.retain(|(var, _)| *var != name);
+                defined.push((name, node.clone()));
+            }
+            nodes.push(node);
         }
+        Cow::Owned(nodes)
+    }
+
+    pub fn accept<V: Visitor + ?Sized>(&self, visitor: &mut V) {
+        for expr in self.expr.iter() {
+            expr.accept(visitor);
+        }
+    }
+
+    pub fn fold<F: Fold + ?Sized>(&self, folder: &mut F) -> Vec<F::Output> {
+        self.expr.iter().map(|expr| expr.fold(folder)).collect()
     }
     
+    /// Replaces every occurrence of the variable `var` with `other` and merges the contexts.
+    ///
+    /// The expressions are paired up like the operands of `+`, and fail with
+    /// [`EvalError::WrongExprIndex`] at the first one without a partner.
+    /// References to the named expressions of `other` are replaced by their definitions.
+    pub fn substitute(&mut self, var: &str, other: &Self) -> Result<&mut Self, EvalError> {
+        if self.expr.len() == 1 && other.expr.len() > 1 {
+            self.expr.resize(other.expr.len(), self.expr[0].clone());
+        }
+        else if self.expr.len() != other.expr.len() && other.expr.len() != 1 {
+            return Err(EvalError::WrongExprIndex(self.expr.len().min(other.expr.len())));
+        }
+        for (node, replacement) in self.expr.iter_mut().zip(other.inlined().iter().cycle()) {
+            *node = node.fold(&mut Substitute {var, replacement});
+        }
+        self.context = Context::ctx_merge(&self.context, &other.context);
+        Ok(self)
+    }
+
     pub fn vars(&self) -> Vec<String> {
-        let mut vars = self.expr.iter()
-                    .flatten()
-                    .filter_map(|t| {
-                        if let Token::Var(v) = t {Some(v.to_owned())}
-                        else {None}
-                    })
-                    .collect::<Vec<String>>();
+        let mut collector = VarCollector::default();
+        for node in self.inlined().iter() {
+            node.accept(&mut collector);
+        }
+        let mut vars = collector.vars;
         vars.sort();
         vars.dedup();
         vars
     }
 
-    pub fn var_items(&self) -> Vec<(String, Option<Value>)> {
-        let mut vars = self.expr.iter()
-                    .flatten()
-                    .filter_map(|t| {
-                        if let Token::Var(v) = t {
-                            Some((v.to_owned(), self.context.get_value(v).copied()))
-                        }
-                        else {None}
-                    })
-                    .collect::<Vec<(String, Option<Value>)>>();
-        vars.sort_by(|a, b| a.partial_cmp(b).unwrap());
-        vars.dedup();
-        vars
+    pub fn var_items(&self) -> Vec<(String, Option<T>)> {
+        self.vars()
+            .into_iter()
+            .map(|v| {
+                let val = self.context.lookup(&v);
+                (v, val)
+            })
+            .collect()
     }
 
-    fn apply_operator(&mut self, other: Vec<Vec<Token>>, op: Token) {
+    /// Combines every output with `other`, whose references to named expressions are already inlined.
+    ///
+    /// The named expressions are inlined and their names dropped, so that the operator applies
+    /// to their outputs but not to the values the later expressions refer to.
+    fn apply_operator(&mut self, other: Vec<Node>, op: BinaryOp) {
+        if self.names.iter().any(Option::is_some) {
+            self.expr = self.inlined().into_owned();
+            self.names.clear();
+        }
         if self.expr.len() == other.len() {
-            for (l, r) in self.expr.iter_mut().zip(other.into_iter()) {
-                l.extend(r);
-                l.push(op.clone());
+            for (l, r) in self.expr.iter_mut().zip(other) {
+                *l = Node::binary(op, std::mem::replace(l, Node::Value(0.0)), r);
             }
         }
         else if self.expr.len() == 1 {
             self.expr.resize(other.len(), self.expr[0].clone());
-            for (l, r) in self.expr.iter_mut().zip(other.into_iter()) {
-                l.extend(r);
-                l.push(op.clone());
+            for (l, r) in self.expr.iter_mut().zip(other) {
+                *l = Node::binary(op, std::mem::replace(l, Node::Value(0.0)), r);
             }
         }
         else if other.len() == 1 {
             for (l, r) in self.expr.iter_mut().zip(other.iter().cycle().cloned()) {
-                l.extend(r);
-                l.push(op.clone());
+                *l = Node::binary(op, std::mem::replace(l, Node::Value(0.0)), r);
             }
         }
         else {
@@ -131,6 +314,103 @@ impl Expr {
     }
 }
 
+impl Expr {
+    /// Rewrites every expression algebraically: constants are folded, like terms
+    /// and factors are collected and identities such as `x * 1` are removed.
+    ///
+    /// The rewrites assume that every subexpression is finite, so the domain may grow:
+    /// `x / x` becomes `1` and `0 * ln(x)` becomes `0` even where they were NaN. Since NaN
+    /// is true in conditions and skipped by `min` and `max`, this can also change finite results.
+    pub fn simplify(&mut self) -> &mut Self {
+        for expr in self.expr.iter_mut() {
+            *expr = simplify(expr);
+        }
+        self
+    }
+
+    /// Differentiates every expression with respect to `var`.
+    ///
+    /// The result is simplified and shares the context of `self`. Step functions such as
+    /// `floor` of an argument depending on `var` fail with [`EvalError::NotDifferentiable`].
+    pub fn derivative(&self, var: &str) -> Result<Self, EvalError> {
+        let mut expr = vec![];
+        for node in self.inlined().iter() {
+            expr.push(simplify(&differentiate(node, var, &self.context)?));
+        }
+        Ok(Self {expr, names: self.names.clone(), context: self.context.clone()})
+    }
+
+    pub fn partial_eval(&mut self) -> Result<&mut Self, EvalError> {
+        self.partial_eval_index(0)
+    }
+
+    pub fn partial_evals(&mut self) -> Result<&mut Self, EvalError> {
+        for i in 0..self.expr.len() {
+            self.expr[i] = partial_eval_with_context(&self.expr[i], &self.context, &self.bound_before(i))?;
+        }
+        Ok(self)
+    }
+
+    pub fn partial_eval_index(&mut self, id: usize) -> Result<&mut Self, EvalError> {
+        if self.expr.len() <= id {
+            Err(EvalError::WrongExprIndex(id))
+        }
+        else {
+            self.expr[id] = partial_eval_with_context(&self.expr[id], &self.context, &self.bound_before(id))?;
+            Ok(self)
+        }
+    }
+
+    /// Evaluates the first expression and its gradient with respect to `vars` using dual numbers.
+    ///
+    /// Custom functions on the path of the gradient need derivatives registered
+    /// with [`Expr::set_derivative`]. The gradient agrees with [`Expr::derivative`],
+    /// including its errors for step functions.
+    pub fn eval_with_gradient(&self, vars: &[&str]) -> Result<(Value, Vec<Value>), EvalError> {
+        match self.expr.first() {
+            Some(node) => eval_gradient(node, &self.context, vars),
+            None => Err(EvalError::WrongExprIndex(0)),
+        }
+    }
+
+    /// Encloses the values of the first expression when each variable of `vars` ranges over its interval.
+    ///
+    /// Other variables take their values from the context. Custom functions give unbounded
+    /// results unless all their arguments are single values.
+    pub fn eval_interval(&self, vars: &[(&str, Interval)]) -> Result<Interval, EvalError> {
+        let mut context = interval_context(&self.context);
+        for (name, v) in vars.iter() {
+            context.set_value(name, *v);
+        }
+        match self.expr.first() {
+            Some(node) => eval_with_context(node, &context),
+            None => Err(EvalError::WrongExprIndex(0)),
+        }
+    }
+
+    /// Compiles the expressions to bytecode, giving `vars[i]` the slot `i`.
+    pub fn compile(&self, vars: &[&str]) -> CompiledExpr {
+        CompiledExpr::new(&self.inlined(), &self.context, vars)
+    }
+
+    /// Evaluates the first expression for every row of the bound columns.
+    ///
+    /// Variables that are not bound are looked up in the context.
+    pub fn eval_batch(&self, bindings: &[(&str, Column)], out: &mut [Value]) -> Result<(), EvalError> {
+        let vars: Vec<&str> = bindings.iter().map(|(name, _)| *name).collect();
+        let columns: Vec<Column> = bindings.iter().map(|(_, column)| *column).collect();
+        self.compile(&vars).eval_batch(&columns, out)
+    }
+
+    /// Same as [`Expr::eval_batch`], but the rows are split across the rayon thread pool.
+    #[cfg(feature="rayon")]
+    pub fn par_eval_batch(&self, bindings: &[(&str, Column)], out: &mut [Value]) -> Result<(), EvalError> {
+        let vars: Vec<&str> = bindings.iter().map(|(name, _)| *name).collect();
+        let columns: Vec<Column> = bindings.iter().map(|(_, column)| *column).collect();
+        self.compile(&vars).par_eval_batch(&columns, out)
+    }
+}
+
 #[macro_export]
 macro_rules! ex {
     ($s:expr) => {
@@ -139,524 +419,515 @@ macro_rules! ex {
 }
 
 macro_rules! expr_op {
-    (Expr, $op:path, $name:ident, $token:expr) => {
-        impl $op for Expr {
-            type Output = Expr;
-            fn $name(mut self, other: Self) -> Expr {
+    (Expr, $op:path, $name:ident, $binop:expr) => {
+        impl<T: Numeric> $op for Expr<T> {
+            type Output = Self;
+            fn $name(mut self, other: Self) -> Self {
                 self.context = Context::ctx_merge(&self.context, &other.context);
-                self.apply_operator(other.expr, $token);
+                self.apply_operator(other.inlined().into_owned(), $binop);
                 self
             }
         }
     };
-    (f64, $op:path, $name:ident, $token:expr) => {
-        impl $op for Expr {
-            type Output = Expr;
-            fn $name(mut self, other: f64) -> Expr {
-                self.apply_operator(vec![vec![Token::Value(other)]], $token);
+    (f64, $op:path, $name:ident, $binop:expr) => {
+        impl<T: Numeric> $op for Expr<T> {
+            type Output = Self;
+            fn $name(mut self, other: f64) -> Self {
+                self.apply_operator(vec![Node::Value(other)], $binop);
                 self
             }
         }
     };
-    (f64r, $op:path, $name:ident, $token:expr) => {
-        impl $op for f64 {
-            type Output = Expr;
-            fn $name(self, other: Expr) -> Expr {
-                let mut vexpr = Expr::new(&self.to_string()).unwrap();
+    (f64r, $op:path, $name:ident, $binop:expr) => {
+        impl<T: Numeric> $op for f64 {
+            type Output = Expr<T>;
+            fn $name(self, other: Expr<T>) -> Expr<T> {
+                let mut vexpr = Expr::with_nodes(vec![Node::Value(self)]);
                 vexpr.context = other.context.clone();
-                vexpr.apply_operator(other.expr, $token);
+                vexpr.apply_operator(other.inlined().into_owned(), $binop);
                 vexpr
             }
         }
     };
-    (assign Expr, $op:path, $name:ident, $token:expr) => {
-        impl $op for Expr {
+    (assign Expr, $op:path, $name:ident, $binop:expr) => {
+        impl<T: Numeric> $op for Expr<T> {
             fn $name(&mut self, other: Self) {
                 self.context = Context::ctx_merge(&self.context, &other.context);
-                self.apply_operator(other.expr, $token);
+                self.apply_operator(other.inlined().into_owned(), $binop);
             }
         }
     };
-    (assign f64, $op:path, $name:ident, $token:expr) => {
-        impl $op for Expr {
+    (assign f64, $op:path, $name:ident, $binop:expr) => {
+        impl<T: Numeric> $op for Expr<T> {
             fn $name(&mut self, other: f64) {
-                self.apply_operator(vec![vec![Token::Value(other)]], $token);
+                self.apply_operator(vec![Node::Value(other)], $binop);
             }
         }
     };
 }
 
-expr_op!(Expr, ops::Add<Expr>, add, Token::Binary(BinaryOp::Add));
-expr_op!(Expr, ops::Sub<Expr>, sub, Token::Binary(BinaryOp::Sub));
-expr_op!(Expr, ops::Mul<Expr>, mul, Token::Binary(BinaryOp::Mul));
-expr_op!(Expr, ops::Div<Expr>, div, Token::Binary(BinaryOp::Div));
+expr_op!(Expr, ops::Add<Expr<T>>, add, BinaryOp::Add);
+expr_op!(Expr, ops::Sub<Expr<T>>, sub, BinaryOp::Sub);
+expr_op!(Expr, ops::Mul<Expr<T>>, mul, BinaryOp::Mul);
+expr_op!(Expr, ops::Div<Expr<T>>, div, BinaryOp::Div);
+expr_op!(Expr, ops::BitXor<Expr<T>>, bitxor, BinaryOp::Pow);
 
-expr_op!(assign Expr, ops::AddAssign<Expr>, add_assign, Token::Binary(BinaryOp::Add));
-expr_op!(assign Expr, ops::SubAssign<Expr>, sub_assign, Token::Binary(BinaryOp::Sub));
-expr_op!(assign Expr, ops::MulAssign<Expr>, mul_assign, Token::Binary(BinaryOp::Mul));
-expr_op!(assign Expr, ops::DivAssign<Expr>, div_assign, Token::Binary(BinaryOp::Div));
+expr_op!(assign Expr, ops::AddAssign<Expr<T>>, add_assign, BinaryOp::Add);
+expr_op!(assign Expr, ops::SubAssign<Expr<T>>, sub_assign, BinaryOp::Sub);
+expr_op!(assign Expr, ops::MulAssign<Expr<T>>, mul_assign, BinaryOp::Mul);
+expr_op!(assign Expr, ops::DivAssign<Expr<T>>, div_assign, BinaryOp::Div);
+expr_op!(assign Expr, ops::BitXorAssign<Expr<T>>, bitxor_assign, BinaryOp::Pow);
 
-expr_op!(f64, ops::Add<f64>, add, Token::Binary(BinaryOp::Add));
-expr_op!(f64, ops::Sub<f64>, sub, Token::Binary(BinaryOp::Sub));
-expr_op!(f64, ops::Mul<f64>, mul, Token::Binary(BinaryOp::Mul));
-expr_op!(f64, ops::Div<f64>, div, Token::Binary(BinaryOp::Div));
+expr_op!(f64, ops::Add<f64>, add, BinaryOp::Add);
+expr_op!(f64, ops::Sub<f64>, sub, BinaryOp::Sub);
+expr_op!(f64, ops::Mul<f64>, mul, BinaryOp::Mul);
+expr_op!(f64, ops::Div<f64>, div, BinaryOp::Div);
+expr_op!(f64, ops::BitXor<f64>, bitxor, BinaryOp::Pow);
 
-expr_op!(assign f64, ops::AddAssign<f64>, add_assign, Token::Binary(BinaryOp::Add));
-expr_op!(assign f64, ops::SubAssign<f64>, sub_assign, Token::Binary(BinaryOp::Sub));
-expr_op!(assign f64, ops::MulAssign<f64>, mul_assign, Token::Binary(BinaryOp::Mul));
-expr_op!(assign f64, ops::DivAssign<f64>, div_assign, Token::Binary(BinaryOp::Div));
+expr_op!(assign f64, ops::AddAssign<f64>, add_assign, BinaryOp::Add);
+expr_op!(assign f64, ops::SubAssign<f64>, sub_assign, BinaryOp::Sub);
+expr_op!(assign f64, ops::MulAssign<f64>, mul_assign, BinaryOp::Mul);
+expr_op!(assign f64, ops::DivAssign<f64>, div_assign, BinaryOp::Div);
+expr_op!(assign f64, ops::BitXorAssign<f64>, bitxor_assign, BinaryOp::Pow);
 
-expr_op!(f64r, ops::Add<Expr>, add, Token::Binary(BinaryOp::Add));
-expr_op!(f64r, ops::Sub<Expr>, sub, Token::Binary(BinaryOp::Sub));
-expr_op!(f64r, ops::Mul<Expr>, mul, Token::Binary(BinaryOp::Mul));
-expr_op!(f64r, ops::Div<Expr>, div, Token::Binary(BinaryOp::Div));
+expr_op!(f64r, ops::Add<Expr<T>>, add, BinaryOp::Add);
+expr_op!(f64r, ops::Sub<Expr<T>>, sub, BinaryOp::Sub);
+expr_op!(f64r, ops::Mul<Expr<T>>, mul, BinaryOp::Mul);
+expr_op!(f64r, ops::Div<Expr<T>>, div, BinaryOp::Div);
+expr_op!(f64r, ops::BitXor<Expr<T>>, bitxor, BinaryOp::Pow);
 
-impl ops::Neg for Expr {
+impl<T: Numeric> ops::Neg for Expr<T> {
     type Output = Self;
     fn neg(mut self) -> Self::Output {
         for expr in self.expr.iter_mut() {
-            expr.push(Token::Unary(UnaryOp::Neg));
+            *expr = Node::unary(UnaryOp::Neg, std::mem::replace(expr, Node::Value(0.0)));
         }
         self
     }
 }
 
 pub fn eval_from_str(expr: &str) -> Result<Vec<Value>, EvalError> {
-    let tokens_vec = parse_str_to_rpn(expr)?;
-    let mut values = vec![];
-    for tokens in tokens_vec {
-        values.push(eval(&tokens)?);
+    eval_from_str_with_context(expr, &Context::new())
+}
+
+pub fn eval_from_str_with_context<T: Numeric>(expr: &str, context: &Context<T>) -> Result<Vec<T>, EvalError> {
+    let (names, nodes): (Vec<_>, Vec<_>) = parse_str_to_ast(expr, syntax::<T>(false))?.into_iter().unzip();
+    for node in nodes.iter() {
+        check_arity(node, context)?;
     }
-    Ok(values)
+    eval_program(&nodes, &names, context)
 }
 
-pub fn eval_from_str_with_context(expr: &str, context: &Context) -> Result<Vec<Value>, EvalError> {
-    let tokens_vec = parse_str_to_rpn(expr)?;
+/// Syntax of expressions evaluated with `T`, whose imaginary unit enables imaginary literals.
+fn syntax<T: Numeric>(cells: bool) -> Syntax {
+    Syntax {cells, imaginary: T::imaginary_unit().is_some()}
+}
+
+/// Evaluates `nodes` in order, binding each named result for the following ones.
+fn eval_program<T: Numeric>(nodes: &[Node], names: &[Option<String>], context: &Context<T>) -> Result<Vec<T>, EvalError> {
+    let mut context = Cow::Borrowed(context);
     let mut values = vec![];
-    for tokens in tokens_vec {
-        values.push(eval_with_context(&tokens, context)?);
+    for (i, node) in nodes.iter().enumerate() {
+        let v = eval_with_context(node, &context)?;
+        if let Some(Some(name)) = names.get(i) {
+            if i + 1 < nodes.len() {
+                context.to_mut().set_value(name, v.clone());
+            }
+        }
+        values.push(v);
     }
     Ok(values)
 }
 
-pub(crate) fn eval(tokens: &[Token]) -> Result<Value, EvalError> {
-    let mut output = Vec::with_capacity(8);
-    for token in tokens.iter() {
-        match token {
-            Token::Value(v) => {output.push(*v);},
-            Token::Var(s) => {
-                return Err(EvalError::UndefinedVariable(s.clone()));
-            },
-            Token::Unary(op) => {
-                if let Some(v) = output.pop() {
-                    output.push(op.eval(v));
-                }
-                else {
-                    return Err(EvalError::WrongExpression);
-                }
-            },
-            Token::Binary(op) => {
-                if let (Some(v2), Some(v1)) = (output.pop(), output.pop()) {
-                    output.push(op.eval(v1, v2));
-                }
-                else {
-                    return Err(EvalError::WrongExpression);
-                }
-            },
-            Token::Function(Function::Custom(s)) => {
-                return Err(EvalError::UndefinedFunction(s.clone()));
-            }
-            Token::Function(func) => {
-                // builtin func
-                let n_args = func.num_args();
-                if n_args == 1 {
-                    if let Some(v) = output.pop() {
-                        output.push(func.eval1(v));
-                    }
-                    else {
-                        return Err(EvalError::WrongExpression);
-                    }
-                }
-                else if n_args == 2 {
-                    if let (Some(v2), Some(v1)) = (output.pop(), output.pop()) {
-                        output.push(func.eval2((v1, v2)));
-                    }
-                    else {
-                        return Err(EvalError::WrongExpression);
-                    }
-                }
-                else {
-                    let args = output.iter().rev().take(n_args).copied().rev().collect::<Vec<Value>>();
-                    if args.len() == n_args {
-                        output.truncate(output.len() - n_args);
-                        output.push(func.evaln(&args));
-                    }
-                    else {
-                        return Err(EvalError::WrongExpression);
-                    }
-                }
-            },
-            _ => {
-                return Err(EvalError::WrongExpression);
-            }
+/// Number type the tree evaluator computes with.
+///
+/// [`eval_node`] handles the traversal, variable lookup, arity checks and short circuits.
+pub(crate) trait Evaluator {
+    type Output;
+    /// Number type of the context variables and custom functions.
+    type Number: Numeric;
+    fn context(&self) -> &Context<Self::Number>;
+    fn value(&self, v: Value) -> Result<Self::Output, EvalError>;
+    fn literal(&self, v: Value, _text: &str) -> Result<Self::Output, EvalError> {
+        self.value(v)
+    }
+    fn var(&self, name: &str, v: Self::Number) -> Self::Output;
+    /// Truth value of a condition, or `None` if it may be either.
+    fn truth(&self, v: &Self::Output) -> Option<bool>;
+    /// Value enclosing both branches of a condition whose truth value is unknown.
+    fn join(&self, then: Self::Output, otherwise: Self::Output) -> Self::Output;
+    fn unary(&self, op: UnaryOp, arg: Self::Output) -> Result<Self::Output, EvalError>;
+    fn binary(&self, op: BinaryOp, lhs: Self::Output, rhs: Self::Output) -> Result<Self::Output, EvalError>;
+    fn call1(&self, func: &Function, arg: Self::Output) -> Result<Self::Output, EvalError>;
+    fn call2(&self, func: &Function, lhs: Self::Output, rhs: Self::Output) -> Result<Self::Output, EvalError>;
+    fn calln(&self, func: &Function, args: Vec<Self::Output>) -> Result<Self::Output, EvalError>;
+    fn custom(&self, name: &str, func: &FuncClosure<Self::Number>, args: Vec<Self::Output>) -> Result<Self::Output, EvalError>;
+}
+
+impl<T: Numeric> Evaluator for Context<T> {
+    type Output = T;
+    type Number = T;
+
+    fn context(&self) -> &Context<T> {
+        self
+    }
+
+    fn value(&self, v: Value) -> Result<T, EvalError> {
+        T::try_from_f64(v)
+    }
+
+    fn literal(&self, v: Value, text: &str) -> Result<T, EvalError> {
+        T::from_literal(v, text)
+    }
+
+    fn var(&self, _name: &str, v: T) -> T {
+        v
+    }
+
+    fn truth(&self, v: &T) -> Option<bool> {
+        v.truth()
+    }
+
+    fn join(&self, then: T, otherwise: T) -> T {
+        T::join(then, otherwise)
+    }
+
+    fn unary(&self, op: UnaryOp, arg: T) -> Result<T, EvalError> {
+        if !self.float_fallback() {
+            return T::unary(op, arg);
         }
+        let exact = T::unary(op, arg.clone());
+        approximate(exact, &[arg], |args| f64::unary(op, args[0]))
+    }
+
+    fn binary(&self, op: BinaryOp, lhs: T, rhs: T) -> Result<T, EvalError> {
+        if !self.float_fallback() {
+            return T::binary(op, lhs, rhs);
+        }
+        let exact = T::binary(op, lhs.clone(), rhs.clone());
+        approximate(exact, &[lhs, rhs], |args| f64::binary(op, args[0], args[1]))
+    }
+
+    fn call1(&self, func: &Function, arg: T) -> Result<T, EvalError> {
+        call_builtin(self, func, &[arg])
+    }
+
+    fn call2(&self, func: &Function, lhs: T, rhs: T) -> Result<T, EvalError> {
+        call_builtin(self, func, &[lhs, rhs])
+    }
+
+    fn calln(&self, func: &Function, args: Vec<T>) -> Result<T, EvalError> {
+        call_builtin(self, func, &args)
     }
-    if output.len() != 1 {
-        Err(EvalError::WrongExpression)
+
+    fn custom(&self, _name: &str, func: &FuncClosure<T>, args: Vec<T>) -> Result<T, EvalError> {
+        Ok(func.call(&args))
     }
-    else if let Some(v) = output.pop() {
-        Ok(v)
+}
+
+fn call_builtin<T: Numeric>(context: &Context<T>, func: &Function, args: &[T]) -> Result<T, EvalError> {
+    if let Err(e) = check_supported::<T>(func) {
+        // the fallback computes functions the type does not support like inexact results
+        if context.float_fallback() && f64::supports(func) {
+            return approximate(Err(EvalError::Inexact(func.name().to_owned())), args, |args| f64::call(func, args));
+        }
+        return Err(e);
+    }
+    let exact = T::call(func, args);
+    if context.float_fallback() {
+        approximate(exact, args, |args| f64::call(func, args))
     }
     else {
-        Err(EvalError::WrongExpression)
+        exact
     }
 }
 
-#[allow(dead_code)]
-fn partial_eval(tokens: &[Token]) -> Result<Vec<Token>, EvalError> {
-    let mut output = Vec::with_capacity(8);
-    for token in tokens.iter() {
-        match token {
-            Token::Value(_) | Token::Var(_) => {output.push(token.clone());},
-            Token::Unary(op) => {
-                let top = output.pop();
-                if let Some(Token::Value(v)) = top {
-                    output.push(Token::Value(op.eval(v)));
-                }
-                else if let Some(t) = top {
-                    output.push(t);
-                    output.push(token.clone());
-                }
-                else {
-                    return Err(EvalError::WrongExpression);
-                }
-            },
-            Token::Binary(op) => {
-                let top = (output.pop(), output.pop());
-                if let (Some(Token::Value(v2)), Some(Token::Value(v1))) = top {
-                    output.push(Token::Value(op.eval(v1, v2)));
-                }
-                else if let (Some(t1), Some(t2)) = top {
-                    output.push(t2);
-                    output.push(t1);
-                    output.push(token.clone());
-                }
-                else {
-                    return Err(EvalError::WrongExpression);
-                }
-            },
-            Token::Function(Function::Custom(_)) => {
-                output.push(token.clone());
+/// Replaces an [`EvalError::Inexact`] by the finite result of `approx` on the `f64` values of `args`.
+fn approximate<T: Numeric>(exact: Result<T, EvalError>, args: &[T], approx: impl FnOnce(&[Value]) -> Result<Value, EvalError>) -> Result<T, EvalError> {
+    match exact {
+        Err(EvalError::Inexact(name)) => {
+            let v = args.iter().map(T::to_f64).collect::<Option<Vec<_>>>().map(|args| approx(&args));
+            match v {
+                Some(Ok(v)) if v.is_finite() => Ok(T::from_f64(v)),
+                _ => Err(EvalError::Inexact(name)),
             }
-            Token::Function(func) => {
-                let n_args = func.num_args();
-                if n_args == 1 {
-                    let top = output.pop();
-                    if let Some(Token::Value(v)) = top {
-                        output.push(Token::Value(func.eval1(v)));
-                    }
-                    else if let Some(t) = top {
-                        output.push(t);
-                        output.push(token.clone());
-                    }
-                    else {
-                        return Err(EvalError::WrongExpression);
-                    }
-                }
-                else if n_args == 2 {
-                    let top = (output.pop(), output.pop());
-                    if let (Some(Token::Value(v2)), Some(Token::Value(v1))) = top {
-                        output.push(Token::Value(func.eval2((v1, v2))));
-                    }
-                    else if let (Some(t1), Some(t2)) = top {
-                        output.push(t2);
-                        output.push(t1);
-                        output.push(token.clone());
-                    }
-                    else {
-                        return Err(EvalError::WrongExpression);
-                    }
-                }
-                else {
-                    let args = output.iter().rev().take(n_args)
-                                .filter_map(|t| match t {
-                                    Token::Value(v) => Some(v),
-                                    _ => None
-                                }).copied().rev().collect::<Vec<Value>>();
-                    if args.len() == n_args {
-                        output.truncate(output.len() - n_args);
-                        output.push(Token::Value(func.evaln(&args)));
-                    }
+        },
+        exact => exact,
+    }
+}
+
+pub(crate) fn eval_with_context<T: Numeric>(node: &Node, context: &Context<T>) -> Result<T, EvalError> {
+    eval_node(node, context)
+}
+
+/// Evaluates `args`, expanding each cell range into the values of its cells.
+fn eval_args<E: Evaluator + ?Sized>(args: &[Node], ev: &E) -> Result<Vec<E::Output>, EvalError> {
+    let mut values = Vec::with_capacity(args.len());
+    for arg in args.iter() {
+        match arg {
+            Node::Range(from, to) => {
+                for v in ev.context().resolve_range(*from, *to)? {
+                    values.push(ev.value(v)?);
                 }
             },
-            _ => {
-                return Err(EvalError::WrongExpression);
-            }
+            arg => values.push(eval_node(arg, ev)?),
         }
     }
-    Ok(output)
+    Ok(values)
 }
 
-pub(crate) fn eval_with_context(tokens: &[Token], context: &Context) -> Result<Value, EvalError> {
-    let mut output = Vec::with_capacity(8);
-    for token in tokens.iter() {
-        match token {
-            Token::Value(v) => {output.push(*v);},
-            Token::Var(s) => {
-                if let Some(&v) = context.get_value(s) {
-                    output.push(v);
-                }
-                else {
-                    return Err(EvalError::UndefinedVariable(s.clone()));
-                }
+pub(crate) fn eval_node<E: Evaluator + ?Sized>(node: &Node, ev: &E) -> Result<E::Output, EvalError> {
+    match node {
+        Node::Value(v) => ev.value(*v),
+        Node::Literal(v, s) => ev.literal(*v, s),
+        Node::Var(s) => {
+            if let Some(v) = ev.context().lookup(s) {
+                Ok(ev.var(s, v))
             }
-            Token::Unary(op) => {
-                if let Some(v) = output.pop() {
-                    output.push(op.eval(v));
-                }
-                else {
-                    return Err(EvalError::WrongExpression);
-                }
-            },
-            Token::Binary(op) => {
-                if let (Some(v2), Some(v1)) = (output.pop(), output.pop()) {
-                    output.push(op.eval(v1, v2));
-                }
-                else {
-                    return Err(EvalError::WrongExpression);
-                }
-            },
-            Token::Function(Function::Custom(s)) => {
-                if let Some(fc) = context.get_func(s) {
-                    match fc.get_arg_len() {
-                        0 => {
-                            output.push(fc.call(&[]));
-                        },
-                        1 => {
-                            if let Some(v) = output.pop() {
-                                output.push(fc.call(&[v]));
-                            }
-                            else {
-                                return Err(EvalError::WrongExpression);
-                            }
-                        },
-                        2 => {
-                            if let (Some(v2), Some(v1)) = (output.pop(), output.pop()) {
-                                output.push(fc.call(&[v1, v2]));
-                            }
-                            else {
-                                return Err(EvalError::WrongExpression);
-                            }
-                        },
-                        n => {
-                            let args = output.iter().rev().take(n).copied().rev().collect::<Vec<Value>>();
-                            if args.len() == n {
-                                output.truncate(output.len() - n);
-                                output.push(fc.call(&args));
-                            }
-                            else {
-                                return Err(EvalError::WrongExpression);
-                            }
-                        }
-                    }
-                }
-                else {
-                    return Err(EvalError::UndefinedFunction(s.clone()));
-                }
+            else {
+                Err(EvalError::UndefinedVariable(s.clone()))
             }
-            Token::Function(func) => {
-                let n_args = func.num_args();
-                if n_args == 1 {
-                    if let Some(v) = output.pop() {
-                        output.push(func.eval1(v));
-                    }
-                    else {
-                        return Err(EvalError::WrongExpression);
-                    }
-                }
-                else if n_args == 2 {
-                    if let (Some(v2), Some(v1)) = (output.pop(), output.pop()) {
-                        output.push(func.eval2((v1, v2)));
-                    }
-                    else {
-                        return Err(EvalError::WrongExpression);
-                    }
-                }
-                else {
-                    let args = output.iter().rev().take(n_args).copied().rev().collect::<Vec<Value>>();
-                    if args.len() == n_args {
-                        output.truncate(output.len() - n_args);
-                        output.push(func.evaln(&args));
-                    }
-                    else {
-                        return Err(EvalError::WrongExpression);
-                    }
+        },
+        // ranges are expanded by the calls taking them
+        Node::Range(from, to) => Err(EvalError::MisplacedRange(format!("{}:{}", from, to))),
+        Node::Unary(op, arg) => {
+            ev.unary(*op, eval_node(arg, ev)?)
+        },
+        Node::Binary(op @ (BinaryOp::And | BinaryOp::Or), lhs, rhs) => {
+            let v1 = eval_node(lhs, ev)?;
+            let b1 = ev.truth(&v1);
+            if b1 == Some(*op == BinaryOp::Or) {
+                // short circuit
+                ev.value(from_bool(*op == BinaryOp::Or))
+            }
+            else {
+                ev.binary(*op, v1, eval_node(rhs, ev)?)
+            }
+        },
+        Node::Binary(op, lhs, rhs) => {
+            let v1 = eval_node(lhs, ev)?;
+            let v2 = eval_node(rhs, ev)?;
+            ev.binary(*op, v1, v2)
+        },
+        Node::Cond(cond, then, otherwise) => {
+            let cond = eval_node(cond, ev)?;
+            match ev.truth(&cond) {
+                Some(true) => eval_node(then, ev),
+                Some(false) => eval_node(otherwise, ev),
+                None => Ok(ev.join(eval_node(then, ev)?, eval_node(otherwise, ev)?)),
+            }
+        },
+        Node::Call(Function::Custom(s), args) => {
+            if let Some(fc) = ev.context().get_func(s) {
+                if !fc.arity().accepts(args.len()) {
+                    return Err(EvalError::WrongArguments {
+                        name: s.clone(),
+                        expected: fc.arity(),
+                        found: args.len(),
+                        span: None
+                    });
+                }
+                ev.custom(s, fc, eval_args(args, ev)?)
+            }
+            else {
+                Err(EvalError::UndefinedFunction(s.clone()))
+            }
+        },
+        Node::Call(func, args) => {
+            // builtin func
+            if !func.arity().accepts(args.len()) {
+                return Err(EvalError::WrongArguments {
+                    name: func.name().to_owned(),
+                    expected: func.arity(),
+                    found: args.len(),
+                    span: None
+                });
+            }
+            match args.as_slice() {
+                args if args.iter().any(|arg| matches!(arg, Node::Range(..))) => {
+                    ev.calln(func, eval_args(args, ev)?)
+                },
+                [arg] => {
+                    ev.call1(func, eval_node(arg, ev)?)
+                },
+                [lhs, rhs] => {
+                    let v1 = eval_node(lhs, ev)?;
+                    let v2 = eval_node(rhs, ev)?;
+                    ev.call2(func, v1, v2)
+                },
+                _ => {
+                    ev.calln(func, eval_args(args, ev)?)
                 }
-            },
-            _ => {
-                return Err(EvalError::WrongExpression);
             }
+        },
+    }
+}
+
+struct PartialEval<'a> {
+    context: &'a Context,
+    /// Names of earlier expressions, left as variables.
+    bound: &'a [String],
+}
+
+impl Fold for PartialEval<'_> {
+    type Output = Node;
+
+    fn fold_value(&mut self, v: Value) -> Node {
+        Node::Value(v)
+    }
+
+    fn fold_var(&mut self, name: &str) -> Node {
+        match self.context.lookup(name) {
+            Some(v) if !self.bound.iter().any(|b| b == name) => Node::Value(v),
+            _ => Node::Var(name.to_owned()),
         }
     }
-    if output.len() != 1 {
-        Err(EvalError::WrongExpression)
+
+    fn fold_range(&mut self, from: CellRef, to: CellRef) -> Node {
+        Node::Range(from, to)
     }
-    else if let Some(v) = output.pop() {
-        Ok(v)
+
+    fn fold_unary(&mut self, op: UnaryOp, arg: Node) -> Node {
+        if let Node::Value(v) = arg {
+            Node::Value(op.eval(v))
+        }
+        else {
+            Node::unary(op, arg)
+        }
     }
-    else {
-        Err(EvalError::WrongExpression)
+
+    fn fold_binary(&mut self, op: BinaryOp, lhs: Node, rhs: Node) -> Node {
+        if let (Node::Value(v1), Node::Value(v2)) = (&lhs, &rhs) {
+            Node::Value(op.eval(*v1, *v2))
+        }
+        else {
+            Node::binary(op, lhs, rhs)
+        }
     }
-}
 
-pub(crate) fn partial_eval_with_context(tokens: &[Token], context: &Context) -> Result<Vec<Token>, EvalError> {
-    let mut output = Vec::with_capacity(8);
-    for token in tokens.iter() {
-        match token {
-            Token::Value(_) => {output.push(token.clone());},
-            Token::Var(s) => {
-                if let Some(&v) = context.get_value(s) {
-                    output.push(Token::Value(v));
-                }
-                else {
-                    output.push(token.clone());
-                }
-            }
-            Token::Unary(op) => {
-                let top = output.pop();
-                if let Some(Token::Value(v)) = top {
-                    output.push(Token::Value(op.eval(v)));
-                }
-                else if let Some(t) = top {
-                    output.push(t);
-                    output.push(token.clone());
-                }
-                else {
-                    return Err(EvalError::WrongExpression);
-                }
-            },
-            Token::Binary(op) => {
-                let top = (output.pop(), output.pop());
-                if let (Some(Token::Value(v2)), Some(Token::Value(v1))) = top {
-                    output.push(Token::Value(op.eval(v1, v2)));
-                }
-                else if let (Some(t1), Some(t2)) = top {
-                    output.push(t2);
-                    output.push(t1);
-                    output.push(token.clone());
-                }
-                else {
-                    return Err(EvalError::WrongExpression);
+    fn fold_call(&mut self, func: &Function, args: Vec<Node>) -> Node {
+        let values = args.iter().map(Node::as_value).collect::<Option<Vec<Value>>>();
+        match (func, values) {
+            (Function::Custom(s), Some(values)) => {
+                match self.context.get_func(s) {
+                    Some(fc) if fc.arity().accepts(values.len()) => {
+                        Node::Value(fc.call(&values))
+                    },
+                    _ => Node::call(func.clone(), args)
                 }
             },
-            Token::Function(Function::Custom(s)) => {
-                if let Some(fc) = context.get_func(s) {
-                    match fc.get_arg_len() {
-                        0 => {
-                            output.push(Token::Value(fc.call(&[])));
-                        },
-                        1 => {
-                            let top = output.pop();
-                            if let Some(Token::Value(v)) = top {
-                                output.push(Token::Value(fc.call(&[v])));
-                            }
-                            else if let Some(t) = top {
-                                output.push(t);
-                                output.push(token.clone());
-                            }
-                            else {
-                                return Err(EvalError::WrongExpression);
-                            }
-                        },
-                        2 => {
-                            let top = (output.pop(), output.pop());
-                            if let (Some(Token::Value(v2)), Some(Token::Value(v1))) = top {
-                                output.push(Token::Value(fc.call(&[v1, v2])));
-                            }
-                            else if let (Some(t1), Some(t2)) = top {
-                                output.push(t2);
-                                output.push(t1);
-                                output.push(token.clone());
-                            }
-                            else {
-                                return Err(EvalError::WrongExpression);
-                            }
-                        },
-                        n => {
-                            if output.len() >= n {
-                                let args = output.iter()
-                                    .rev()
-                                    .take(n)
-                                    .filter_map(|t| match t {
-                                        Token::Value(v) => Some(*v),
-                                        _ => None
-                                    })
-                                    .rev()
-                                    .collect::<Vec<Value>>();
-                                if args.len() == n {
-                                    output.truncate(output.len() - n);
-                                    output.push(Token::Value(fc.call(&args)));
-                                }
-                                else {
-                                    output.push(token.clone());
-                                }
-                            }
-                            else {
-                                return Err(EvalError::WrongExpression);
-                            }
-                        }
-                    }
-                }
-                else {
-                    output.push(token.clone());
-                }
-            }
-            Token::Function(func) => {
-                // builtin func
-                let n_args = func.num_args();
-                if n_args == 1 {
-                    let top = output.pop();
-                    if let Some(Token::Value(v)) = top {
-                        output.push(Token::Value(func.eval1(v)));
-                    }
-                    else if let Some(t) = top {
-                        output.push(t);
-                        output.push(token.clone());
-                    }
-                    else {
-                        return Err(EvalError::WrongExpression);
-                    }
-                }
-                else if n_args == 2 {
-                    let top = (output.pop(), output.pop());
-                    if let (Some(Token::Value(v2)), Some(Token::Value(v1))) = top {
-                        output.push(Token::Value(func.eval2((v1, v2))));
-                    }
-                    else if let (Some(t1), Some(t2)) = top {
-                        output.push(t2);
-                        output.push(t1);
-                        output.push(token.clone());
-                    }
-                    else {
-                        return Err(EvalError::WrongExpression);
-                    }
-                }
-                else {
-                    let args = output.iter().rev().take(n_args)
-                                .filter_map(|t| match t {
-                                    Token::Value(v) => Some(v),
-                                    _ => None
-                                }).copied().rev().collect::<Vec<Value>>();
-                    if args.len() == n_args {
-                        output.truncate(output.len() - n_args);
-                        output.push(Token::Value(func.evaln(&args)));
-                    }
+            (_, Some(values)) if func.arity().accepts(values.len()) && Value::supports(func) => {
+                match values.as_slice() {
+                    [v] => Node::Value(func.eval1(*v)),
+                    [v1, v2] => Node::Value(func.eval2((*v1, *v2))),
+                    _ => Node::Value(func.evaln(&values)),
                 }
             },
-            _ => {
-                return Err(EvalError::WrongExpression);
+            _ => Node::call(func.clone(), args)
+        }
+    }
+
+    fn fold_cond(&mut self, cond: Node, then: Node, otherwise: Node) -> Node {
+        match cond {
+            Node::Value(v) if to_bool(v) => then,
+            Node::Value(_) => otherwise,
+            _ => Node::cond(cond, then, otherwise)
+        }
+    }
+}
+
+pub(crate) fn partial_eval_with_context(node: &Node, context: &Context, bound: &[String]) -> Result<Node, EvalError> {
+    Ok(node.fold(&mut PartialEval { context, bound }))
+}
+
+struct Substitute<'a> {
+    var: &'a str,
+    replacement: &'a Node,
+}
+
+impl Fold for Substitute<'_> {
+    type Output = Node;
+
+    fn fold_value(&mut self, v: Value) -> Node {
+        Node::Value(v)
+    }
+
+    fn fold_literal(&mut self, v: Value, text: &str) -> Node {
+        Node::Literal(v, text.to_owned())
+    }
+
+    fn fold_var(&mut self, name: &str) -> Node {
+        if name == self.var {
+            self.replacement.clone()
+        }
+        else {
+            Node::Var(name.to_owned())
+        }
+    }
+
+    fn fold_range(&mut self, from: CellRef, to: CellRef) -> Node {
+        Node::Range(from, to)
+    }
+
+    fn fold_unary(&mut self, op: UnaryOp, arg: Node) -> Node {
+        Node::unary(op, arg)
+    }
+
+    fn fold_binary(&mut self, op: BinaryOp, lhs: Node, rhs: Node) -> Node {
+        Node::binary(op, lhs, rhs)
+    }
+
+    fn fold_call(&mut self, func: &Function, args: Vec<Node>) -> Node {
+        Node::call(func.clone(), args)
+    }
+
+    fn fold_cond(&mut self, cond: Node, then: Node, otherwise: Node) -> Node {
+        Node::cond(cond, then, otherwise)
+    }
+}
+
+#[derive(Default)]
+struct VarCollector {
+    vars: Vec<String>,
+}
+
+impl Visitor for VarCollector {
+    fn visit_node(&mut self, node: &Node) {
+        if let Node::Var(v) = node {
+            self.vars.push(v.to_owned());
+        }
+        walk_node(self, node);
+    }
+}
+
+struct ArityChecker<'a, T> {
+    context: &'a Context<T>,
+    error: Option<EvalError>,
+}
+
+impl<T: Numeric> Visitor for ArityChecker<'_, T> {
+    fn visit_node(&mut self, node: &Node) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Node::Call(Function::Custom(s), args) = node {
+            if let Some(fc) = self.context.get_func(s).filter(|fc| !fc.arity().accepts(args.len())) {
+                self.error = Some(EvalError::WrongArguments {
+                    name: s.clone(),
+                    expected: fc.arity(),
+                    found: args.len(),
+                    span: None
+                });
+                return;
             }
         }
+        walk_node(self, node);
     }
-    Ok(output)
-}
\ No newline at end of file
+}
+
+/// Checks the argument counts of the custom functions defined in `context`.
+pub(crate) fn check_arity<T: Numeric>(node: &Node, context: &Context<T>) -> Result<(), EvalError> {
+    let mut checker = ArityChecker {context, error: None};
+    node.accept(&mut checker);
+    checker.error.map_or(Ok(()), Err)
+}
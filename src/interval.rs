@@ -0,0 +1,523 @@
+use crate::numeric::Numeric;
+use crate::context::{Context, SharedFunc};
+use crate::token::{Value, UnaryOp, BinaryOp, Function};
+use crate::error::EvalError;
+use std::f64::consts::{PI, FRAC_PI_2, TAU};
+use std::sync::Arc;
+
+/// Closed interval `[lo, hi]` of real numbers, whose bounds may be infinite.
+///
+/// Arithmetic rounds the bounds outward, so that a result encloses every value
+/// the expression takes for arguments in the intervals. The empty interval,
+/// the result where no argument is in the domain, has NaN bounds and stands
+/// for NaN wherever `f64` gives a number for it, as in comparisons, `min` and `max`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Interval {
+    pub lo: Value,
+    pub hi: Value,
+}
+
+/// Results of the math library are assumed to be within this many ulps.
+const LIBM_ULPS: u32 = 2;
+
+const BOOL: Interval = Interval {lo: 0.0, hi: 1.0};
+
+impl Interval {
+    pub const ENTIRE: Self = Self {lo: Value::NEG_INFINITY, hi: Value::INFINITY};
+    pub const EMPTY: Self = Self {lo: Value::NAN, hi: Value::NAN};
+
+    /// `[lo, hi]`, or the empty interval if `lo > hi` or either is NaN.
+    pub fn new(lo: Value, hi: Value) -> Self {
+        if lo <= hi {Self {lo, hi}}
+        else {Self::EMPTY}
+    }
+
+    pub fn point(v: Value) -> Self {
+        Self::new(v, v)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lo.is_nan()
+    }
+
+    pub fn is_point(&self) -> bool {
+        self.lo == self.hi
+    }
+
+    pub fn contains(&self, v: Value) -> bool {
+        self.lo <= v && v <= self.hi
+    }
+
+    pub fn width(&self) -> Value {
+        self.hi - self.lo
+    }
+
+    /// Smallest interval containing both.
+    pub fn hull(self, other: Self) -> Self {
+        if self.is_empty() {other}
+        else if other.is_empty() {self}
+        else {Self {lo: self.lo.min(other.lo), hi: self.hi.max(other.hi)}}
+    }
+
+    fn is_bounded(&self) -> bool {
+        self.lo.is_finite() && self.hi.is_finite()
+    }
+
+    fn intersect(self, other: Self) -> Self {
+        Self::new(self.lo.max(other.lo), self.hi.min(other.hi))
+    }
+
+    /// Smallest and largest absolute values.
+    fn abs(self) -> Self {
+        if self.lo >= 0.0 {self}
+        else if self.hi <= 0.0 {Self {lo: -self.hi, hi: -self.lo}}
+        else {Self {lo: 0.0, hi: self.hi.max(-self.lo)}}
+    }
+}
+
+impl std::fmt::Display for Interval {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.is_empty() {write!(f, "[]")}
+        else {write!(f, "[{}, {}]", self.lo, self.hi)}
+    }
+}
+
+impl Numeric for Interval {
+    fn from_f64(v: Value) -> Self {
+        Self::point(v)
+    }
+
+    fn to_bool(&self) -> bool {
+        self.truth() != Some(false)
+    }
+
+    fn truth(&self) -> Option<bool> {
+        if self.lo == 0.0 && self.hi == 0.0 {Some(false)}
+        else if self.is_empty() || self.contains(0.0) {None}
+        else {Some(true)}
+    }
+
+    fn join(lhs: Self, rhs: Self) -> Self {
+        lhs.hull(rhs)
+    }
+
+    fn unary(op: UnaryOp, arg: Self) -> Result<Self, EvalError> {
+        let v = match op {
+            UnaryOp::Neg => -arg,
+            UnaryOp::Not => boolean(arg.truth() == Some(false), arg.truth() == Some(true)),
+        };
+        Ok(v)
+    }
+
+    fn binary(op: BinaryOp, lhs: Self, rhs: Self) -> Result<Self, EvalError> {
+        let nan = lhs.is_empty() || rhs.is_empty();
+        let v = match op {
+            // NaN compares unequal to everything
+            BinaryOp::Ne if nan => Self::point(1.0),
+            BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge | BinaryOp::Eq if nan => Self::point(0.0),
+            BinaryOp::Pow if nan => pow_nan(lhs, rhs),
+            BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem if nan => Self::EMPTY,
+            BinaryOp::Add => add(lhs, rhs),
+            BinaryOp::Sub => add(lhs, -rhs),
+            BinaryOp::Mul => mul(lhs, rhs),
+            BinaryOp::Div => div(lhs, rhs),
+            BinaryOp::Rem => rem(lhs, rhs),
+            BinaryOp::Pow => pow(lhs, rhs),
+            BinaryOp::Lt => boolean(lhs.hi < rhs.lo, lhs.lo >= rhs.hi),
+            BinaryOp::Le => boolean(lhs.hi <= rhs.lo, lhs.lo > rhs.hi),
+            BinaryOp::Gt => boolean(lhs.lo > rhs.hi, lhs.hi <= rhs.lo),
+            BinaryOp::Ge => boolean(lhs.lo >= rhs.hi, lhs.hi < rhs.lo),
+            BinaryOp::Eq => boolean(lhs.is_point() && lhs == rhs, lhs.hi < rhs.lo || rhs.hi < lhs.lo),
+            BinaryOp::Ne => boolean(lhs.hi < rhs.lo || rhs.hi < lhs.lo, lhs.is_point() && lhs == rhs),
+            BinaryOp::And => {
+                let (l, r) = (lhs.truth(), rhs.truth());
+                boolean(l == Some(true) && r == Some(true), l == Some(false) || r == Some(false))
+            },
+            BinaryOp::Or => {
+                let (l, r) = (lhs.truth(), rhs.truth());
+                boolean(l == Some(true) || r == Some(true), l == Some(false) && r == Some(false))
+            },
+        };
+        Ok(v)
+    }
+
+    fn supports(func: &Function) -> bool {
+        f64::supports(func)
+    }
+
+    fn call(func: &Function, args: &[Self]) -> Result<Self, EvalError> {
+        let nan = args.iter().any(Self::is_empty);
+        let v = match (func, args) {
+            // `f64::min` and `f64::max` ignore NaN, so empty arguments are skipped
+            (Function::Min, _) => Self {
+                lo: args.iter().filter(|x| !x.is_empty()).fold(Value::INFINITY, |acc, x| acc.min(x.lo)),
+                hi: args.iter().filter(|x| !x.is_empty()).fold(Value::INFINITY, |acc, x| acc.min(x.hi)),
+            },
+            (Function::Max, _) => Self {
+                lo: args.iter().filter(|x| !x.is_empty()).fold(Value::NEG_INFINITY, |acc, x| acc.max(x.lo)),
+                hi: args.iter().filter(|x| !x.is_empty()).fold(Value::NEG_INFINITY, |acc, x| acc.max(x.hi)),
+            },
+            (Function::Count, _) => Self::point(args.len() as Value),
+            // `hypot(NaN, inf)` is infinite
+            (Function::Hypot, _) if nan => {
+                if args.iter().any(|x| x.abs().hi == Value::INFINITY) {Self::point(Value::INFINITY)}
+                else {Self::EMPTY}
+            },
+            (Function::Powf | Function::Powi, [lhs, rhs]) if nan => pow_nan(*lhs, *rhs),
+            _ if nan => Self::EMPTY,
+            (Function::Hypot, _) => {
+                let (lo, hi) = args.iter().map(|x| x.abs()).fold((0.0, 0.0), |(lo, hi), x| (x.lo.hypot(lo), x.hi.hypot(hi)));
+                clamp(widen(lo, hi, LIBM_ULPS), 0.0, Value::INFINITY)
+            },
+            (Function::Sum, _) => args.iter().fold(Self::point(0.0), |acc, x| add(acc, *x)),
+            (Function::Average, _) => {
+                let sum = args.iter().fold(Self::point(0.0), |acc, x| add(acc, *x));
+                div(sum, Self::point(args.len() as Value))
+            },
+            (_, [x]) => call1(func, *x),
+            (_, [lhs, rhs]) => call2(func, *lhs, *rhs),
+            _ => {unreachable!()}
+        };
+        Ok(v)
+    }
+}
+
+/// `[lo, hi]` widened by `ulps` in both directions, with NaN bounds made unbounded.
+fn widen(mut lo: Value, mut hi: Value, ulps: u32) -> Interval {
+    if lo.is_nan() {lo = Value::NEG_INFINITY;}
+    if hi.is_nan() {hi = Value::INFINITY;}
+    for _ in 0..ulps {
+        lo = lo.next_down();
+        hi = hi.next_up();
+    }
+    Interval {lo, hi}
+}
+
+fn clamp(x: Interval, lo: Value, hi: Value) -> Interval {
+    if x.is_empty() {x}
+    else {Interval {lo: x.lo.max(lo), hi: x.hi.min(hi)}}
+}
+
+/// Truth value that is 1 if the relation holds `always`, 0 if `never` and unknown otherwise.
+fn boolean(always: bool, never: bool) -> Interval {
+    if always {Interval::point(1.0)}
+    else if never {Interval::point(0.0)}
+    else {BOOL}
+}
+
+fn min_max(values: &[Value]) -> (Value, Value) {
+    values.iter().fold((Value::INFINITY, Value::NEG_INFINITY), |(lo, hi), v| (lo.min(*v), hi.max(*v)))
+}
+
+/// `x`, moved to its neighbour `up` or down if the exact result is on that side.
+///
+/// `err` has the sign of the exact result minus `x`, and `exact` is whether `x` came from finite operands.
+fn round(x: Value, err: Value, up: bool, exact: bool) -> Value {
+    if x.is_infinite() && exact {
+        // overflow, so the inner bound is the largest finite value of that sign
+        if up == (x > 0.0) {x}
+        else {Value::MAX.copysign(x)}
+    }
+    else if up && err > 0.0 {x.next_up()}
+    else if !up && err < 0.0 {x.next_down()}
+    else {x}
+}
+
+fn add_rounded(a: Value, b: Value, up: bool) -> Value {
+    let s = a + b;
+    if !s.is_finite() {
+        return round(s, 0.0, up, a.is_finite() && b.is_finite());
+    }
+    // TwoSum
+    let bb = s - a;
+    round(s, (a - (s - bb)) + (b - bb), up, true)
+}
+
+fn mul_rounded(a: Value, b: Value, up: bool) -> Value {
+    // `0 * inf` is 0 for the bounds of intervals
+    if a == 0.0 || b == 0.0 {
+        return 0.0;
+    }
+    let p = a * b;
+    if !p.is_finite() {
+        return round(p, 0.0, up, a.is_finite() && b.is_finite());
+    }
+    round(p, a.mul_add(b, -p), up, true)
+}
+
+fn div_rounded(a: Value, b: Value, up: bool) -> Value {
+    let q = a / b;
+    if !q.is_finite() || !a.is_finite() || !b.is_finite() {
+        return round(q, 0.0, up, a.is_finite() && b.is_finite());
+    }
+    // the exact quotient is `q + r / b`
+    let r = q.mul_add(-b, a);
+    round(q, r * b.signum(), up, true)
+}
+
+/// Interval of the extreme values of `f` over the corners of `lhs` and `rhs`, rounded outward.
+fn corners(lhs: Interval, rhs: Interval, f: fn(Value, Value, bool) -> Value) -> Interval {
+    let pairs = [(lhs.lo, rhs.lo), (lhs.lo, rhs.hi), (lhs.hi, rhs.lo), (lhs.hi, rhs.hi)];
+    let (lo, _) = min_max(&pairs.map(|(a, b)| f(a, b, false)));
+    let (_, hi) = min_max(&pairs.map(|(a, b)| f(a, b, true)));
+    Interval {lo, hi}
+}
+
+fn add(lhs: Interval, rhs: Interval) -> Interval {
+    widen(add_rounded(lhs.lo, rhs.lo, false), add_rounded(lhs.hi, rhs.hi, true), 0)
+}
+
+fn mul(lhs: Interval, rhs: Interval) -> Interval {
+    corners(lhs, rhs, mul_rounded)
+}
+
+fn div(lhs: Interval, rhs: Interval) -> Interval {
+    let inf = Value::INFINITY;
+    if rhs.lo == 0.0 && rhs.hi == 0.0 {
+        // infinite with the sign of the dividend, or NaN for zero
+        Interval::ENTIRE
+    }
+    else if rhs.lo > 0.0 || rhs.hi < 0.0 {
+        let quotients = [lhs.lo / rhs.lo, lhs.lo / rhs.hi, lhs.hi / rhs.lo, lhs.hi / rhs.hi];
+        if quotients.iter().any(|q| q.is_nan()) {
+            return Interval::ENTIRE;
+        }
+        corners(lhs, rhs, div_rounded)
+    }
+    else if lhs.lo == 0.0 && lhs.hi == 0.0 {
+        lhs
+    }
+    // the divisor approaches zero from one side only
+    else if rhs.lo == 0.0 && lhs.lo >= 0.0 {
+        Interval {lo: div_rounded(lhs.lo, rhs.hi, false), hi: inf}
+    }
+    else if rhs.lo == 0.0 && lhs.hi <= 0.0 {
+        Interval {lo: -inf, hi: div_rounded(lhs.hi, rhs.hi, true)}
+    }
+    else if rhs.hi == 0.0 && lhs.lo >= 0.0 {
+        Interval {lo: -inf, hi: div_rounded(lhs.lo, rhs.lo, true)}
+    }
+    else if rhs.hi == 0.0 && lhs.hi <= 0.0 {
+        Interval {lo: div_rounded(lhs.hi, rhs.lo, false), hi: inf}
+    }
+    else {
+        Interval::ENTIRE
+    }
+}
+
+/// Remainder of the truncated division, as `%` on `f64`.
+fn rem(lhs: Interval, rhs: Interval) -> Interval {
+    if rhs == Interval::point(0.0) {
+        // NaN, which the hull of a divisor containing zero leaves out
+        return Interval::EMPTY;
+    }
+    if rhs.is_point() && rhs.lo != 0.0 && rhs.lo.is_finite() && lhs.lo.is_finite() && lhs.hi.is_finite() {
+        // `%` is exact, and continuous between multiples of the divisor
+        let (lo, hi) = (lhs.lo % rhs.lo, lhs.hi % rhs.lo);
+        let continuous = if lhs.lo >= 0.0 || lhs.hi <= 0.0 {
+            lo <= hi && lhs.width().next_up() < rhs.lo.abs()
+        }
+        else {
+            lo == lhs.lo && hi == lhs.hi
+        };
+        if continuous {
+            return Interval {lo, hi};
+        }
+    }
+    // smaller than the divisor in magnitude, with the sign of the dividend
+    let m = rhs.abs().hi;
+    Interval {
+        lo: if lhs.lo >= 0.0 {0.0} else {lhs.lo.max(-m)},
+        hi: if lhs.hi <= 0.0 {0.0} else {lhs.hi.min(m)},
+    }
+}
+
+fn pow(base: Interval, exp: Interval) -> Interval {
+    if exp.is_point() && exp.lo.fract() == 0.0 && exp.lo.abs() <= i32::MAX as Value {
+        return powi(base, exp.lo as i32);
+    }
+    if base.lo < 0.0 && exp.lo.ceil() <= exp.hi {
+        // negative bases give `±|x|^n` for the integers `n` in the exponent
+        let m = pow_nonneg(base.abs(), exp).hi;
+        return Interval {lo: -m, hi: m};
+    }
+    pow_nonneg(base.intersect(Interval {lo: 0.0, hi: Value::INFINITY}), exp)
+}
+
+fn powi(base: Interval, n: i32) -> Interval {
+    if n == 0 {
+        Interval::point(1.0)
+    }
+    else if n < 0 {
+        div(Interval::point(1.0), powi(base, -n))
+    }
+    else {
+        let (lo, hi) = (base.lo.powf(n as Value), base.hi.powf(n as Value));
+        if n % 2 == 1 {
+            widen(lo, hi, LIBM_ULPS)
+        }
+        else if base.contains(0.0) {
+            clamp(widen(0.0, lo.max(hi), LIBM_ULPS), 0.0, Value::INFINITY)
+        }
+        else {
+            clamp(widen(lo.min(hi), lo.max(hi), LIBM_ULPS), 0.0, Value::INFINITY)
+        }
+    }
+}
+
+/// `x^y` where `x` or `y` is empty, which is 1 for `x^0` and `1^y` as for `f64::powf`.
+fn pow_nan(base: Interval, exp: Interval) -> Interval {
+    if exp == Interval::point(0.0) || base.contains(1.0) {Interval::point(1.0)}
+    else {Interval::EMPTY}
+}
+
+/// `x^y` for `x >= 0`, which is monotonic in each argument and so extreme at the corners.
+fn pow_nonneg(base: Interval, exp: Interval) -> Interval {
+    if base.is_empty() {
+        return Interval::EMPTY;
+    }
+    let (lo, hi) = min_max(&[base.lo.powf(exp.lo), base.lo.powf(exp.hi), base.hi.powf(exp.lo), base.hi.powf(exp.hi)]);
+    clamp(widen(lo, hi, LIBM_ULPS), 0.0, Value::INFINITY)
+}
+
+/// `f(x)` for `f` increasing on `domain`, with results rounded by `ulps`.
+fn increasing(x: Interval, f: fn(Value) -> Value, domain: Interval, ulps: u32) -> Interval {
+    let x = x.intersect(domain);
+    if x.is_empty() {Interval::EMPTY}
+    else {widen(f(x.lo), f(x.hi), ulps)}
+}
+
+/// `f(x)` for `f` decreasing on `domain`.
+fn decreasing(x: Interval, f: fn(Value) -> Value, domain: Interval) -> Interval {
+    let x = x.intersect(domain);
+    if x.is_empty() {Interval::EMPTY}
+    else {widen(f(x.hi), f(x.lo), LIBM_ULPS)}
+}
+
+/// Whether `x` may contain `at + k * period` for an integer `k`, erring on the side of yes.
+fn hits(x: Interval, at: Value, period: Value) -> bool {
+    let lo = (x.lo - at) / period;
+    let hi = (x.hi - at) / period;
+    let slack = 1e-12 * (1.0 + lo.abs().max(hi.abs()));
+    (hi + slack).floor() >= (lo - slack).ceil()
+}
+
+/// `f(x)` for sine or cosine, which are 1 at `max_at + k * TAU` and -1 at `min_at + k * TAU`.
+fn periodic(x: Interval, f: fn(Value) -> Value, max_at: Value, min_at: Value) -> Interval {
+    if !x.is_bounded() || x.width() >= TAU {
+        return Interval {lo: -1.0, hi: 1.0};
+    }
+    let (a, b) = (f(x.lo), f(x.hi));
+    let mut y = widen(a.min(b), a.max(b), LIBM_ULPS);
+    if hits(x, max_at, TAU) {
+        y.hi = 1.0;
+    }
+    if hits(x, min_at, TAU) {
+        y.lo = -1.0;
+    }
+    clamp(y, -1.0, 1.0)
+}
+
+fn call1(func: &Function, x: Interval) -> Interval {
+    let all = Interval::ENTIRE;
+    let nonneg = Interval {lo: 0.0, hi: Value::INFINITY};
+    let unit = Interval {lo: -1.0, hi: 1.0};
+    match func {
+        Function::Sin => periodic(x, Value::sin, FRAC_PI_2, -FRAC_PI_2),
+        Function::Cos => periodic(x, Value::cos, 0.0, PI),
+        Function::Tan => {
+            if !x.is_bounded() || x.width() >= PI || hits(x, FRAC_PI_2, PI) {all}
+            else {increasing(x, Value::tan, all, LIBM_ULPS)}
+        },
+        Function::Asin => increasing(x, Value::asin, unit, LIBM_ULPS),
+        Function::Acos => decreasing(x, Value::acos, unit),
+        Function::Atan => increasing(x, Value::atan, all, LIBM_ULPS),
+        Function::Sinh => increasing(x, Value::sinh, all, LIBM_ULPS),
+        Function::Cosh => clamp(increasing(x.abs(), Value::cosh, all, LIBM_ULPS), 1.0, Value::INFINITY),
+        Function::Tanh => clamp(increasing(x, Value::tanh, all, LIBM_ULPS), -1.0, 1.0),
+        Function::Sqrt => clamp(increasing(x, Value::sqrt, nonneg, LIBM_ULPS), 0.0, Value::INFINITY),
+        Function::Cbrt => increasing(x, Value::cbrt, all, LIBM_ULPS),
+        Function::Ln => increasing(x, Value::ln, nonneg, LIBM_ULPS),
+        Function::Log10 => increasing(x, Value::log10, nonneg, LIBM_ULPS),
+        Function::Log2 => increasing(x, Value::log2, nonneg, LIBM_ULPS),
+        Function::Exp => clamp(increasing(x, Value::exp, all, LIBM_ULPS), 0.0, Value::INFINITY),
+        Function::Exp2 => clamp(increasing(x, Value::exp2, all, LIBM_ULPS), 0.0, Value::INFINITY),
+        // exact
+        Function::Floor => increasing(x, Value::floor, all, 0),
+        Function::Ceil => increasing(x, Value::ceil, all, 0),
+        Function::Round => increasing(x, Value::round, all, 0),
+        Function::Trunc => increasing(x, Value::trunc, all, 0),
+        Function::Fract => {
+            let t = x.lo.trunc();
+            if t == x.hi.trunc() && t.is_finite() {Interval {lo: x.lo - t, hi: x.hi - t}}
+            else if x.lo >= 0.0 {Interval {lo: 0.0, hi: 1.0}}
+            else if x.hi <= 0.0 {Interval {lo: -1.0, hi: 0.0}}
+            else {unit}
+        },
+        Function::Abs => x.abs(),
+        Function::Signum => {
+            if x.lo > 0.0 {Interval::point(1.0)}
+            else if x.hi < 0.0 {Interval::point(-1.0)}
+            else {unit}
+        },
+        _ => {unreachable!()}
+    }
+}
+
+fn call2(func: &Function, lhs: Interval, rhs: Interval) -> Interval {
+    match func {
+        Function::Log => div(call1(&Function::Ln, lhs), call1(&Function::Ln, rhs)),
+        Function::Powf => pow(lhs, rhs),
+        Function::Powi => {
+            if rhs.is_point() && rhs.lo.abs() <= i32::MAX as Value {powi(lhs, rhs.lo as i32)}
+            else {pow(lhs, Interval {lo: rhs.lo.trunc(), hi: rhs.hi.trunc()})}
+        },
+        Function::DivEuclid => div_euclid(lhs, rhs),
+        Function::RemEuclid if rhs == Interval::point(0.0) => Interval::EMPTY,
+        Function::RemEuclid => {
+            let m = rhs.abs().hi;
+            let q = div_euclid(lhs, rhs);
+            if q.is_point() && q.lo.is_finite() {
+                clamp(add(lhs, -mul(q, rhs)), 0.0, m)
+            }
+            else {
+                Interval {lo: 0.0, hi: m}
+            }
+        },
+        _ => {unreachable!()}
+    }
+}
+
+/// Quotient of the Euclidean division, which is `floor(x / y)` for `y > 0` and `ceil(x / y)` for `y < 0`.
+fn div_euclid(lhs: Interval, rhs: Interval) -> Interval {
+    let q = div(lhs, rhs);
+    if rhs.lo > 0.0 {increasing(q, Value::floor, Interval::ENTIRE, 0)}
+    else if rhs.hi < 0.0 {increasing(q, Value::ceil, Interval::ENTIRE, 0)}
+    else {Interval::ENTIRE}
+}
+
+impl std::ops::Neg for Interval {
+    type Output = Self;
+    fn neg(self) -> Self {
+        Self {lo: -self.hi, hi: -self.lo}
+    }
+}
+
+/// `context` with its values as points and its custom functions applied to points.
+///
+/// Custom functions have unknown monotonicity, so their results are unbounded for wider arguments.
+pub(crate) fn interval_context(context: &Context) -> Context<Interval> {
+    context.map(|v| Interval::point(*v), |f| {
+        let f = f.clone();
+        let g: SharedFunc<Interval> = Arc::new(move |args: &[Interval]| {
+            // empty arguments are passed as NaN
+            if args.iter().all(|x| x.is_point() || x.is_empty()) {
+                Interval::point(f(&args.iter().map(|x| x.lo).collect::<Vec<Value>>()))
+            }
+            else {
+                Interval::ENTIRE
+            }
+        });
+        g
+    })
+}
@@ -5,12 +5,24 @@
 //! ## Features
 //! 
 //! - Parse and evaluate simple mathematical expressions.
-//!     - Basic arithmetic operations: `+`, `-`, `*`, `/`, `%`
+//!     - Basic arithmetic operations: `+`, `-`, `*`, `/`, `%`, `^` (or `**`)
+//!     - Comparison and logical operations: `==`, `!=`, `<`, `<=`, `>`, `>=`, `&&`, `||`, `!` (true is `1.0`, false is `0.0`)
+//!     - Conditional expressions: `if(cond, a, b)` and `cond ? a : b`
 //!     - Parentheses for expression grouping
+//!     - Number literals in decimal or scientific notation, such as `1.5E-9` and `6.022e+23`
 //!     - Common mathematical functions: `sin`, `cos`, `atan`, `cosh`, `pow`, `sqrt`, `hypot`, `exp`, `ln`, `div_euclid`, `floor` etc...
 //!     - mathematical constants such as `PI`, `TAU`, and `E`.
 //! - Support for variables, operators, and functions.
+//! - Symbolic differentiation and forward-mode automatic differentiation of expressions.
+//! - Export of expressions as infix text, LaTeX and MathML.
+//! - Formula sheets that recompute only the formulas affected by a change.
+//! - Opt-in spreadsheet cell references and ranges with `SUM`, `AVERAGE` and `COUNT`.
+//! - Pluggable number types through the `Numeric` trait, with `f64` as the default.
+//! - Interval evaluation for guaranteed bounds on the range of an expression.
 //! - Minimal dependencies.
+//! - Optional parallel batch evaluation with the `rayon` feature.
+//! - Optional complex number evaluation with the `complex` feature.
+//! - Optional exact rational arithmetic with the `rational` feature.
 //! - Provides a simple and easy-to-use API.
 //! 
 //! ## Usage
@@ -39,6 +51,9 @@
 //! 
 //! // using macro `ex!`
 //! assert_eq!(ex!("sqrt(4)").eval().unwrap(), 2.0);
+//! 
+//! // `^` is right-associative and binds tighter than unary minus
+//! assert_eq!(eval_from_str("-2^3^2").unwrap(), vec![-512.0]);
 //! ```
 //! 
 //! You can assign numerical values to variables and evaluate them using `Context`.
@@ -61,7 +76,7 @@
 //! assert_eq!(ex!("sqrt(2+x+y)").set_var("x", 2.0).set_var("y", 5.0).eval().unwrap(), 3.0);
 //! ```
 //! 
-//! You can use custom functions.
+//! You can use custom functions, including closures that capture their environment.
 //! 
 //! ```rust
 //! # use lieval::*;
@@ -76,6 +91,14 @@
 //!     context.eval("1 + func(2,3)").unwrap(),
 //!     6.0
 //! );
+//! 
+//! let table = vec![1.0, 4.0, 9.0];
+//! context.set_func("table", 1, move |x| table[x[0] as usize]);
+//! assert_eq!(context.eval("table(2)").unwrap(), 9.0);
+//! 
+//! // variadic functions accept an arity range such as `1..`, `2..=3` or `0..`
+//! context.set_func("avg", 1.., |x| x.iter().sum::<f64>() / x.len() as f64);
+//! assert_eq!(context.eval("avg(1, 2, 3, 6) + max(1, 5, 3)").unwrap(), 8.0);
 //! ```
 //! 
 //! You can evaluate multiple expressions separated by commas or semicolons.
@@ -138,6 +161,295 @@
 //! assert_eq!((-ex!("1+x") * expr1).set_var("x", 2.0).eval().unwrap(), -18.0);
 //! ```
 //! 
+//! You can inspect the parsed expression tree and write your own passes with `Visitor` and `Fold`.
+//! 
+//! ```rust
+//! # use lieval::*;
+//! #
+//! struct CountVars(usize);
+//! impl Visitor for CountVars {
+//!     fn visit_node(&mut self, node: &Node) {
+//!         if let Node::Var(_) = node {
+//!             self.0 += 1;
+//!         }
+//!         walk_node(self, node);
+//!     }
+//! }
+//! 
+//! let expr = ex!("x * sin(x + y)");
+//! let mut counter = CountVars(0);
+//! expr.accept(&mut counter);
+//! assert_eq!(counter.0, 3);
+//! assert_eq!(
+//!     expr.nodes()[0],
+//!     Node::binary(
+//!         BinaryOp::Mul,
+//!         Node::Var("x".to_owned()),
+//!         Node::call(Function::Sin, vec![Node::binary(BinaryOp::Add, Node::Var("x".to_owned()), Node::Var("y".to_owned()))])
+//!     )
+//! );
+//! ```
+//! 
+//! Errors point at the offending part of the input.
+//! 
+//! ```rust
+//! # use lieval::*;
+//! #
+//! let source = "sqrt(x) + 2 $ 3";
+//! let err = Expr::new(source).unwrap_err();
+//! assert_eq!(err.span(), Some(Span::new(12, 13)));
+//! assert_eq!(err.diagnostic(source), "string $ at 12..13 is Invalid\nsqrt(x) + 2 $ 3\n            ^");
+//! ```
+//! 
+//! Comparison and logical operators evaluate to `1.0` (true) or `0.0` (false).
+//! 
+//! ```rust
+//! # use lieval::*;
+//! #
+//! let mut context = Context::new();
+//! context.set_value("age", 20.0).set_value("income", 30000.0).set_value("limit", 50000.0);
+//! assert_eq!(context.eval("age >= 18 && income < limit").unwrap(), 1.0);
+//! assert_eq!(context.eval("!(age >= 18) || income >= limit").unwrap(), 0.0);
+//! ```
+//! 
+//! Conditional expressions only evaluate the branch that is taken.
+//! 
+//! ```rust
+//! # use lieval::*;
+//! #
+//! let mut expr = ex!("if(x > 0, ln(x), 0)");
+//! assert_eq!(expr.set_var("x", 1.0).eval().unwrap(), 0.0);
+//! assert_eq!(expr.set_var("x", -1.0).eval().unwrap(), 0.0);
+//! 
+//! assert_eq!(eval_from_str("1 < 2 ? 10 : y").unwrap(), vec![10.0]);
+//! ```
+//! 
+//! For hot loops, compile the expression with its variables resolved to slots.
+//! 
+//! ```rust
+//! # use lieval::*;
+//! #
+//! let mut compiled = ex!("a * x + sin(y)").set_var("a", 2.0).compile(&["x", "y"]);
+//! assert_eq!(compiled.eval(&[1.0, 0.0]).unwrap(), 2.0);
+//! assert_eq!(compiled.eval(&[3.0, 0.0]).unwrap(), 6.0);
+//! ```
+//! 
+//! To evaluate many rows at once, bind each variable to a column slice or a broadcast scalar.
+//! 
+//! ```rust
+//! # use lieval::*;
+//! #
+//! let x = [1.0, 2.0, 3.0];
+//! let mut out = [0.0; 3];
+//! ex!("a * x + b").set_var("b", 1.0)
+//!     .eval_batch(&[("x", Column::Slice(&x)), ("a", Column::Scalar(2.0))], &mut out)
+//!     .unwrap();
+//! assert_eq!(out, [3.0, 5.0, 7.0]);
+//! ```
+//! 
+//! With the `rayon` feature enabled, the rows are evaluated in parallel.
+//! 
+//! ```toml
+//! [dependencies]
+//! lieval = { version = "<version>", features = ["rayon"] }
+//! ```
+//! 
+//! ```rust
+//! # use lieval::*;
+//! # #[cfg(feature = "rayon")]
+//! # {
+//! let x: Vec<f64> = (0..100_000).map(|i| i as f64).collect();
+//! let mut out = vec![0.0; x.len()];
+//! ex!("x / 2").par_eval_batch(&[("x", Column::Slice(&x))], &mut out).unwrap();
+//! assert_eq!(out[10], 5.0);
+//! # }
+//! ```
+//! 
+//! Expressions can be differentiated symbolically. The derivatives of custom functions are given by naming functions that compute their partial derivatives.
+//! 
+//! ```rust
+//! # use lieval::*;
+//! #
+//! let mut expr = ex!("x^2 + sin(x)");
+//! let mut derivative = expr.derivative("x").unwrap();
+//! assert_eq!(derivative.set_var("x", 0.0).eval().unwrap(), 1.0);
+//! 
+//! expr = ex!("f(x, 2)");
+//! expr.set_func("f", 2, |x| x[0] * x[1])
+//!     .set_func("df_da", 2, |x| x[1])
+//!     .set_func("df_db", 2, |x| x[0])
+//!     .set_derivative("f", &["df_da", "df_db"]);
+//! assert_eq!(expr.derivative("x").unwrap().set_var("x", 3.0).eval().unwrap(), 2.0);
+//! ```
+//! 
+//! The value and the gradient can also be computed in one pass with dual numbers.
+//! 
+//! ```rust
+//! # use lieval::*;
+//! #
+//! let mut expr = ex!("x^2 * y + sin(y)");
+//! expr.set_var("x", 3.0).set_var("y", 0.0);
+//! assert_eq!(expr.eval_with_gradient(&["x", "y"]).unwrap(), (0.0, vec![0.0, 10.0]));
+//! ```
+//! 
+//! `simplify` folds constants, collects like terms and removes identities.
+//! 
+//! ```rust
+//! # use lieval::*;
+//! #
+//! let mut expr = ex!("x + 1 + 2 - x * 0 + 2 * y - y * 1");
+//! assert_eq!(expr.simplify().nodes(), ex!("x + y + 3").nodes());
+//! ```
+//! 
+//! `Expr` implements `Display`, so expressions can be stored as text and parsed again.
+//! 
+//! ```rust
+//! # use lieval::*;
+//! #
+//! let expr = (ex!("x + 1") * 2.0) ^ ex!("y");
+//! assert_eq!(expr.to_string(), "((x + 1) * 2)^y");
+//! ```
+//! 
+//! Expressions can be exported as LaTeX or MathML.
+//! 
+//! ```rust
+//! # use lieval::*;
+//! #
+//! let expr = ex!("sqrt(x) / (2 * PI) + y^2");
+//! assert_eq!(expr.to_latex(), vec![r"\frac{\sqrt{x}}{2 \cdot \pi} + y^{2}"]);
+//! assert!(expr.to_mathml()[0].starts_with("<math"));
+//! ```
+//! 
+//! A variable can be replaced with another expression.
+//! 
+//! ```rust
+//! # use lieval::*;
+//! #
+//! let mut expr = ex!("PI * r^2");
+//! expr.substitute("r", &ex!("sqrt(x * x + y * y)")).unwrap();
+//! assert_eq!(expr.set_var("x", 3.0).set_var("y", 4.0).eval().unwrap(), 25.0 * std::f64::consts::PI);
+//! ```
+//! 
+//! A statement can be named with `name = ...`, and later statements can refer to it by that name. Operators such as `+` apply to every output and return an expression without names.
+//! 
+//! ```rust
+//! # use lieval::*;
+//! #
+//! let mut expr = ex!("r = sqrt(x * x + y * y); area = PI * r^2; area / r");
+//! expr.set_var("x", 3.0).set_var("y", 4.0);
+//! assert_eq!(expr.eval_named("r").unwrap(), 5.0);
+//! assert_eq!(expr.evals_named().unwrap()[1].0, "area");
+//! assert_eq!(expr.eval_index(2).unwrap(), 5.0 * std::f64::consts::PI);
+//! ```
+//! 
+//! `FormulaSheet` keeps named formulas that depend on each other and recomputes only what a change affects.
+//! 
+//! ```rust
+//! # use lieval::*;
+//! #
+//! let mut sheet = FormulaSheet::new();
+//! sheet.set_formula("margin", "revenue - cost").unwrap()
+//!     .set_formula("ratio", "margin / revenue").unwrap();
+//! sheet.set_value("revenue", 200.0).set_value("cost", 150.0);
+//! sheet.recompute().unwrap();
+//! assert_eq!(sheet.get("ratio"), Some(0.25));
+//! 
+//! sheet.set_value("cost", 100.0);
+//! assert_eq!(sheet.recompute().unwrap(), 2);
+//! assert_eq!(sheet.get("ratio"), Some(0.5));
+//! assert!(sheet.set_formula("revenue", "ratio * 1000").is_err());
+//! ```
+//! 
+//! `Expr::with_cell_refs` reads cell references such as `A1` and `$B$2` and ranges such as `A1:C3`, whose values come from a `CellResolver` in the context.
+//! 
+//! ```rust
+//! # use lieval::*;
+//! #
+//! struct Grid(Vec<Vec<f64>>);
+//! 
+//! impl CellResolver for Grid {
+//!     fn cell(&self, cell: CellRef) -> Option<f64> {
+//!         self.0.get(cell.row)?.get(cell.col).copied()
+//!     }
+//! }
+//! 
+//! let mut expr = Expr::with_cell_refs("SUM(A1:B2) / COUNT(A1:B2) + $A$1").unwrap();
+//! expr.set_resolver(Grid(vec![vec![1.0, 2.0], vec![3.0, 4.0]]));
+//! assert_eq!(expr.eval().unwrap(), 3.5);
+//! ```
+//! 
+//! The default `CellResolver::range` looks up every cell of a range one by one, so resolvers for large sheets should override it.
+//! 
+//! Expressions evaluate to `f64` by default. `Expr<T>` and `Context<T>` compute with any type implementing `Numeric`, which also decides the built-in functions it supports. Symbolic rewrites such as `simplify`, `derivative` and `partial_eval` fold constants as `f64`, so they are only provided for `f64` expressions.
+//! 
+//! ```rust
+//! # use lieval::*;
+//! #
+//! let mut expr = Expr::<f32>::parse("x / 3").unwrap();
+//! expr.set_var("x", 1.0);
+//! assert_eq!(expr.eval().unwrap(), 1.0f32 / 3.0);
+//! 
+//! let mut ctx = Context::<f32>::default();
+//! ctx.set_value("y", 2.5);
+//! assert_eq!(ctx.eval("y * 2").unwrap(), 5.0f32);
+//! ```
+//! 
+//! With the `complex` feature enabled, `Complex64` is a `Numeric` type. Its expressions accept imaginary literals such as `4i` or `2j`, the imaginary unit `i` (or `j`), and the functions `re`, `im`, `conj` and `arg`.
+//! 
+//! ```toml
+//! [dependencies]
+//! lieval = { version = "<version>", features = ["complex"] }
+//! ```
+//! 
+//! ```rust
+//! # use lieval::*;
+//! # #[cfg(feature = "complex")]
+//! # {
+//! assert_eq!(Expr::<Complex64>::parse("sqrt(-1)").unwrap().eval().unwrap(), Complex64::i());
+//! assert_eq!(Expr::<Complex64>::parse("abs(3+4i)").unwrap().eval().unwrap(), Complex64::new(5.0, 0.0));
+//! 
+//! let mut expr = Expr::<Complex64>::parse("arg(z) / PI").unwrap();
+//! expr.set_var("z", Complex64::new(0.0, 2.0));
+//! assert_eq!(expr.eval().unwrap().re, 0.5);
+//! # }
+//! ```
+//! 
+//! `Expr::eval_interval` encloses every value an expression can take when its variables range over intervals. Bounds are rounded outward and become infinite where the expression is unbounded.
+//! 
+//! ```rust
+//! # use lieval::*;
+//! #
+//! let expr = ex!("x * y + sin(x)");
+//! let range = expr.eval_interval(&[("x", Interval::new(0.0, 2.0)), ("y", Interval::new(-1.0, 1.0))]).unwrap();
+//! assert!(range.lo <= -2.0 && range.hi >= 3.0);
+//! assert!(range.width() < 5.5);
+//! 
+//! let expr = ex!("1 / y");
+//! assert_eq!(expr.eval_interval(&[("y", Interval::new(-1.0, 1.0))]).unwrap(), Interval::ENTIRE);
+//! ```
+//! 
+//! With the `rational` feature enabled, `BigRational` is a `Numeric` type that computes with exact fractions. Number literals are read from their digits, so `0.1` is exactly `1/10` and `0.1000000000000000000001 - 0.1` is not zero. Built-in functions and powers without a rational result fail with `EvalError::Inexact`, and trigonometric, exponential and logarithmic functions with `EvalError::UnsupportedFunction`, unless `set_float_fallback` computes them with `f64` instead.
+//! 
+//! ```toml
+//! [dependencies]
+//! lieval = { version = "<version>", features = ["rational"] }
+//! ```
+//! 
+//! ```rust
+//! # use lieval::*;
+//! # #[cfg(feature = "rational")]
+//! # {
+//! let half = BigRational::new(1.into(), 2.into());
+//! assert_eq!(Expr::<BigRational>::parse("1/3 + 1/6").unwrap().eval().unwrap(), half);
+//! assert_eq!(Expr::<BigRational>::parse("sqrt(0.25)").unwrap().eval().unwrap(), half);
+//! 
+//! let mut expr = Expr::<BigRational>::parse("sqrt(2)").unwrap();
+//! assert_eq!(expr.eval(), Err(EvalError::Inexact("sqrt".to_string())));
+//! expr.set_float_fallback(true);
+//! assert_eq!(expr.eval().unwrap(), BigRational::from_f64(2f64.sqrt()));
+//! # }
+//! ```
+//! 
 //! ## API Documentation
 //! 
 //! Detailed API documentation can be found [here](https://docs.rs/lieval).
@@ -147,15 +459,42 @@
 //! This project is licensed under the MIT license.
 
 
+mod ast;
+mod cell;
+mod compile;
+#[cfg(feature="complex")]
+mod complex;
+mod diff;
+mod dual;
 mod eval;
+mod interval;
+mod markup;
+mod numeric;
 mod parse;
+#[cfg(feature="rational")]
+mod rational;
+mod sheet;
+mod simplify;
 mod token;
 mod context;
 mod error;
 mod util;
 
 pub use crate::{
+    ast::{Node, Visitor, Fold, walk_node},
+    cell::{CellRef, CellResolver},
+    compile::{CompiledExpr, Column},
     eval::{Expr, eval_from_str, eval_from_str_with_context},
-    context::Context,
-    error::EvalError,
-};
\ No newline at end of file
+    interval::Interval,
+    numeric::Numeric,
+    sheet::FormulaSheet,
+    token::{Value, UnaryOp, BinaryOp, Function, Arity},
+    context::{Context, FuncClosure, SharedFunc},
+    error::{EvalError, Span},
+};
+
+#[cfg(feature="complex")]
+pub use num_complex::Complex64;
+
+#[cfg(feature="rational")]
+pub use num_rational::BigRational;
@@ -0,0 +1,344 @@
+use crate::ast::Node;
+use crate::token::{Value, UnaryOp, BinaryOp, Function, Constant, Token};
+
+/// Target notation of [`render`].
+trait Markup {
+    fn number(&self, v: Value) -> String;
+    fn constant(&self, c: Constant) -> String;
+    fn ident(&self, name: &str) -> String;
+    fn unary_op(&self, op: UnaryOp) -> String;
+    fn binary_op(&self, op: BinaryOp) -> String;
+    fn row(&self, items: Vec<String>) -> String;
+    fn fenced(&self, open: &str, inner: String, close: &str) -> String;
+    fn frac(&self, num: String, den: String) -> String;
+    fn sup(&self, base: String, exp: String) -> String;
+    fn root(&self, arg: String, index: Option<&str>) -> String;
+    /// A named function applied to `args`, with an optional subscript such as the base of `log`.
+    fn func(&self, name: &str, sub: Option<String>, args: Vec<String>) -> String;
+    fn cases(&self, cond: String, then: String, otherwise: String) -> String;
+
+    fn parens(&self, inner: String) -> String {
+        self.fenced("(", inner, ")")
+    }
+}
+
+pub(crate) fn to_latex(node: &Node) -> String {
+    render(node, &Latex)
+}
+
+pub(crate) fn to_mathml(node: &Node) -> String {
+    format!("<math xmlns=\"http://www.w3.org/1998/Math/MathML\">{}</math>", render(node, &MathMl))
+}
+
+/// Binding powers as in [`Token::precedence`], where fractions, calls and cases are atoms.
+fn precedence(node: &Node) -> (u8, u8) {
+    match node {
+        Node::Binary(BinaryOp::Div, ..) | Node::Cond(..) => (100, 100),
+        Node::Call(Function::Powf | Function::Powi | Function::Exp2, _) => Token::Binary(BinaryOp::Pow).precedence(),
+        _ => node.precedence(),
+    }
+}
+
+/// Whether `node` can be the base of a superscript without parentheses.
+fn is_atom(node: &Node) -> bool {
+    precedence(node) == (100, 100) && !matches!(node, Node::Binary(BinaryOp::Div, ..) | Node::Cond(..))
+}
+
+fn render<M: Markup>(node: &Node, m: &M) -> String {
+    let operand = |node: &Node, parens: bool| {
+        if parens {m.parens(render(node, m))}
+        else {render(node, m)}
+    };
+    let power = |base: &Node, exp: String| m.sup(operand(base, !is_atom(base)), exp);
+    match node {
+        Node::Value(v) if v.is_sign_negative() && !v.is_nan() => {
+            m.row(vec![m.unary_op(UnaryOp::Neg), render(&Node::Value(-v), m)])
+        },
+        Node::Value(v) => {
+            match Constant::from_value(*v) {
+                Some(c) => m.constant(c),
+                None => m.number(*v),
+            }
+        },
+        Node::Literal(v, _) => m.number(*v),
+        Node::Var(s) => m.ident(s),
+        Node::Range(..) => m.ident(&node.to_string()),
+        Node::Unary(op, arg) => {
+            let (_, r) = Token::Unary(*op).precedence();
+            m.row(vec![m.unary_op(*op), operand(arg, precedence(arg).0 <= r)])
+        },
+        Node::Binary(BinaryOp::Div, lhs, rhs) => m.frac(render(lhs, m), render(rhs, m)),
+        Node::Binary(BinaryOp::Pow, lhs, rhs) => power(lhs, render(rhs, m)),
+        Node::Binary(op, lhs, rhs) => {
+            let (l, r) = Token::Binary(*op).precedence();
+            m.row(vec![
+                operand(lhs, precedence(lhs).1 < l),
+                m.binary_op(*op),
+                operand(rhs, precedence(rhs).0 <= r),
+            ])
+        },
+        Node::Call(func, args) => {
+            match (func, args.as_slice()) {
+                (Function::Powf | Function::Powi, [base, exp]) => power(base, render(exp, m)),
+                (Function::Exp2, [exp]) => m.sup(m.number(2.0), render(exp, m)),
+                (Function::Sqrt, [arg]) => m.root(render(arg, m), None),
+                (Function::ImaginaryUnit, []) => m.ident("i"),
+                (Function::Cbrt, [arg]) => m.root(render(arg, m), Some("3")),
+                (Function::Abs, [arg]) => m.fenced("|", render(arg, m), "|"),
+                (Function::Floor, [arg]) => m.fenced("⌊", render(arg, m), "⌋"),
+                (Function::Ceil, [arg]) => m.fenced("⌈", render(arg, m), "⌉"),
+                (Function::Log, [arg, base]) => m.func("log", Some(render(base, m)), vec![render(arg, m)]),
+                (Function::Log10, [arg]) => m.func("log", Some(m.number(10.0)), vec![render(arg, m)]),
+                (Function::Log2, [arg]) => m.func("log", Some(m.number(2.0)), vec![render(arg, m)]),
+                _ => {
+                    let name = match func {
+                        Function::Asin => "arcsin",
+                        Function::Acos => "arccos",
+                        Function::Atan => "arctan",
+                        func => func.name(),
+                    };
+                    m.func(name, None, args.iter().map(|arg| render(arg, m)).collect())
+                },
+            }
+        },
+        Node::Cond(cond, then, otherwise) => {
+            m.cases(render(cond, m), render(then, m), render(otherwise, m))
+        },
+    }
+}
+
+/// Splits `v` into a mantissa and a power of ten if the decimal notation is much longer.
+fn scientific(v: Value) -> Option<(String, String)> {
+    let decimal = v.to_string();
+    let scientific = format!("{:e}", v);
+    if scientific.len() + 2 < decimal.len() {
+        let (mantissa, exp) = scientific.split_once('e')?;
+        Some((mantissa.to_owned(), exp.to_owned()))
+    }
+    else {
+        None
+    }
+}
+
+struct Latex;
+
+impl Latex {
+    fn text(name: &str) -> String {
+        name.replace('_', "\\_")
+    }
+}
+
+impl Markup for Latex {
+    fn number(&self, v: Value) -> String {
+        if v.is_nan() {
+            return "\\mathrm{NaN}".to_owned();
+        }
+        if v.is_infinite() {
+            return "\\infty".to_owned();
+        }
+        match scientific(v) {
+            Some((mantissa, exp)) if mantissa == "1" => format!("10^{{{}}}", exp),
+            Some((mantissa, exp)) => format!("{} \\times 10^{{{}}}", mantissa, exp),
+            None => v.to_string(),
+        }
+    }
+
+    fn constant(&self, c: Constant) -> String {
+        match c {
+            Constant::PI => "\\pi",
+            Constant::Tau => "\\tau",
+            Constant::E => "e",
+        }.to_owned()
+    }
+
+    fn ident(&self, name: &str) -> String {
+        if name.chars().count() == 1 {name.to_owned()}
+        else {format!("\\mathrm{{{}}}", Self::text(name))}
+    }
+
+    fn unary_op(&self, op: UnaryOp) -> String {
+        match op {
+            UnaryOp::Neg => "-",
+            UnaryOp::Not => "\\lnot ",
+        }.to_owned()
+    }
+
+    fn binary_op(&self, op: BinaryOp) -> String {
+        match op {
+            BinaryOp::Add => " + ",
+            BinaryOp::Sub => " - ",
+            BinaryOp::Mul => " \\cdot ",
+            BinaryOp::Div => " / ",
+            BinaryOp::Rem => " \\bmod ",
+            BinaryOp::Pow => "^",
+            BinaryOp::Eq => " = ",
+            BinaryOp::Ne => " \\neq ",
+            BinaryOp::Lt => " < ",
+            BinaryOp::Le => " \\leq ",
+            BinaryOp::Gt => " > ",
+            BinaryOp::Ge => " \\geq ",
+            BinaryOp::And => " \\land ",
+            BinaryOp::Or => " \\lor ",
+        }.to_owned()
+    }
+
+    fn row(&self, items: Vec<String>) -> String {
+        items.concat()
+    }
+
+    fn fenced(&self, open: &str, inner: String, close: &str) -> String {
+        let delimiter = |s: &str| match s {
+            "⌊" => "\\lfloor",
+            "⌋" => "\\rfloor",
+            "⌈" => "\\lceil",
+            "⌉" => "\\rceil",
+            s => s,
+        }.to_owned();
+        format!("\\left{} {} \\right{}", delimiter(open), inner, delimiter(close))
+    }
+
+    fn frac(&self, num: String, den: String) -> String {
+        format!("\\frac{{{}}}{{{}}}", num, den)
+    }
+
+    fn sup(&self, base: String, exp: String) -> String {
+        format!("{}^{{{}}}", base, exp)
+    }
+
+    fn root(&self, arg: String, index: Option<&str>) -> String {
+        match index {
+            Some(index) => format!("\\sqrt[{}]{{{}}}", index, arg),
+            None => format!("\\sqrt{{{}}}", arg),
+        }
+    }
+
+    fn func(&self, name: &str, sub: Option<String>, args: Vec<String>) -> String {
+        let name = match name {
+            "sin" | "cos" | "tan" | "arcsin" | "arccos" | "arctan" | "sinh" | "cosh" | "tanh"
+            | "exp" | "ln" | "log" | "min" | "max" => format!("\\{}", name),
+            name => format!("\\operatorname{{{}}}", Self::text(name)),
+        };
+        let sub = sub.map_or(String::new(), |sub| format!("_{{{}}}", sub));
+        format!("{}{}{}", name, sub, self.parens(args.join(", ")))
+    }
+
+    fn cases(&self, cond: String, then: String, otherwise: String) -> String {
+        format!("\\begin{{cases}} {} & \\text{{if }} {} \\\\ {} & \\text{{otherwise}} \\end{{cases}}", then, cond, otherwise)
+    }
+}
+
+struct MathMl;
+
+impl MathMl {
+    fn escape(s: &str) -> String {
+        s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+    }
+
+    fn mo(s: &str) -> String {
+        format!("<mo>{}</mo>", Self::escape(s))
+    }
+}
+
+impl Markup for MathMl {
+    fn number(&self, v: Value) -> String {
+        if v.is_nan() {
+            return "<mi>NaN</mi>".to_owned();
+        }
+        if v.is_infinite() {
+            return "<mi>∞</mi>".to_owned();
+        }
+        match scientific(v) {
+            Some((mantissa, exp)) => {
+                let exp = match exp.strip_prefix('-') {
+                    Some(exp) => format!("<mrow><mo>−</mo><mn>{}</mn></mrow>", exp),
+                    None => format!("<mn>{}</mn>", exp),
+                };
+                let power = format!("<msup><mn>10</mn>{}</msup>", exp);
+                if mantissa == "1" {power}
+                else {format!("<mrow><mn>{}</mn><mo>×</mo>{}</mrow>", mantissa, power)}
+            },
+            None => format!("<mn>{}</mn>", v),
+        }
+    }
+
+    fn constant(&self, c: Constant) -> String {
+        match c {
+            Constant::PI => "<mi>π</mi>",
+            Constant::Tau => "<mi>τ</mi>",
+            Constant::E => "<mi>e</mi>",
+        }.to_owned()
+    }
+
+    fn ident(&self, name: &str) -> String {
+        format!("<mi>{}</mi>", Self::escape(name))
+    }
+
+    fn unary_op(&self, op: UnaryOp) -> String {
+        match op {
+            UnaryOp::Neg => Self::mo("−"),
+            UnaryOp::Not => Self::mo("¬"),
+        }
+    }
+
+    fn binary_op(&self, op: BinaryOp) -> String {
+        Self::mo(match op {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "−",
+            BinaryOp::Mul => "⋅",
+            BinaryOp::Div => "/",
+            BinaryOp::Rem => "mod",
+            BinaryOp::Pow => "^",
+            BinaryOp::Eq => "=",
+            BinaryOp::Ne => "≠",
+            BinaryOp::Lt => "<",
+            BinaryOp::Le => "≤",
+            BinaryOp::Gt => ">",
+            BinaryOp::Ge => "≥",
+            BinaryOp::And => "∧",
+            BinaryOp::Or => "∨",
+        })
+    }
+
+    fn row(&self, items: Vec<String>) -> String {
+        format!("<mrow>{}</mrow>", items.concat())
+    }
+
+    fn fenced(&self, open: &str, inner: String, close: &str) -> String {
+        self.row(vec![Self::mo(open), inner, Self::mo(close)])
+    }
+
+    fn frac(&self, num: String, den: String) -> String {
+        format!("<mfrac>{}{}</mfrac>", num, den)
+    }
+
+    fn sup(&self, base: String, exp: String) -> String {
+        format!("<msup>{}{}</msup>", base, exp)
+    }
+
+    fn root(&self, arg: String, index: Option<&str>) -> String {
+        match index {
+            Some(index) => format!("<mroot>{}<mn>{}</mn></mroot>", arg, index),
+            None => format!("<msqrt>{}</msqrt>", arg),
+        }
+    }
+
+    fn func(&self, name: &str, sub: Option<String>, args: Vec<String>) -> String {
+        let name = self.ident(name);
+        let name = match sub {
+            Some(sub) => format!("<msub>{}{}</msub>", name, sub),
+            None => name,
+        };
+        let args = args.join(&Self::mo(","));
+        self.row(vec![name, Self::mo("\u{2061}"), self.parens(args)])
+    }
+
+    fn cases(&self, cond: String, then: String, otherwise: String) -> String {
+        format!(
+            "<mrow><mo>{{</mo><mtable>\
+            <mtr><mtd>{}</mtd><mtd><mtext>if </mtext>{}</mtd></mtr>\
+            <mtr><mtd>{}</mtd><mtd><mtext>otherwise</mtext></mtd></mtr>\
+            </mtable></mrow>",
+            then, cond, otherwise
+        )
+    }
+}
@@ -0,0 +1,131 @@
+use crate::token::{Value, UnaryOp, BinaryOp, Function, to_bool};
+use crate::error::EvalError;
+
+/// Number type an [`Expr`](crate::Expr) and its [`Context`](crate::Context) compute with.
+///
+/// Number literals are converted with [`Numeric::from_literal`], and built-in functions
+/// outside [`Numeric::supports`] fail with [`EvalError::UnsupportedFunction`].
+/// Operations without an exact result in this type may fail with [`EvalError::Inexact`].
+pub trait Numeric: Clone + std::fmt::Debug + Send + Sync + 'static {
+    fn from_f64(v: Value) -> Self;
+
+    /// Same as [`Numeric::from_f64`], or an error for values this type cannot hold.
+    fn try_from_f64(v: Value) -> Result<Self, EvalError> {
+        Ok(Self::from_f64(v))
+    }
+
+    /// Number literal `text`, whose nearest `f64` is `v`.
+    ///
+    /// Defaults to [`Numeric::try_from_f64`]; types more precise than `f64` may read the digits of `text`.
+    fn from_literal(v: Value, _text: &str) -> Result<Self, EvalError> {
+        Self::try_from_f64(v)
+    }
+
+    /// Truth value in conditionals and logical operators.
+    fn to_bool(&self) -> bool;
+
+    /// Same as [`Numeric::to_bool`], or `None` if the value may be either true or false.
+    ///
+    /// Conditionals evaluate both branches and [`Numeric::join`] the results in that case.
+    fn truth(&self) -> Option<bool> {
+        Some(self.to_bool())
+    }
+
+    /// Value containing both `lhs` and `rhs`, for types whose truth value may be unknown.
+    fn join(lhs: Self, _rhs: Self) -> Self {
+        lhs
+    }
+
+    /// Approximation used by [`Context::set_float_fallback`](crate::Context::set_float_fallback).
+    fn to_f64(&self) -> Option<Value> {
+        None
+    }
+
+    fn from_bool(b: bool) -> Self {
+        Self::from_f64(if b {1.0} else {0.0})
+    }
+
+    /// Value of `i` and `j` unless they are set in the context.
+    ///
+    /// Types with an imaginary unit also parse literals such as `4i` and the
+    /// functions `re`, `im`, `conj` and `arg`.
+    fn imaginary_unit() -> Option<Self> {
+        None
+    }
+
+    fn unary(op: UnaryOp, arg: Self) -> Result<Self, EvalError>;
+
+    fn binary(op: BinaryOp, lhs: Self, rhs: Self) -> Result<Self, EvalError>;
+
+    /// Whether the built-in `func` can be evaluated with this type.
+    fn supports(func: &Function) -> bool;
+
+    /// Applies a supported built-in function to arguments it accepts.
+    fn call(func: &Function, args: &[Self]) -> Result<Self, EvalError>;
+}
+
+/// Fails with [`EvalError::UnsupportedFunction`] unless `T` supports the built-in `func`.
+pub(crate) fn check_supported<T: Numeric>(func: &Function) -> Result<(), EvalError> {
+    if T::supports(func) {Ok(())}
+    else {Err(EvalError::UnsupportedFunction(func.name().to_owned()))}
+}
+
+impl Numeric for f64 {
+    fn from_f64(v: Value) -> Self {
+        v
+    }
+
+    fn to_bool(&self) -> bool {
+        to_bool(*self)
+    }
+
+    fn unary(op: UnaryOp, arg: Self) -> Result<Self, EvalError> {
+        Ok(op.eval(arg))
+    }
+
+    fn binary(op: BinaryOp, lhs: Self, rhs: Self) -> Result<Self, EvalError> {
+        Ok(op.eval(lhs, rhs))
+    }
+
+    /// Every built-in function except those of complex numbers.
+    fn supports(func: &Function) -> bool {
+        !func.is_complex()
+    }
+
+    fn call(func: &Function, args: &[Self]) -> Result<Self, EvalError> {
+        let v = match args {
+            [arg] => func.eval1(*arg),
+            [lhs, rhs] => func.eval2((*lhs, *rhs)),
+            _ => func.evaln(args),
+        };
+        Ok(v)
+    }
+}
+
+/// Computes in `f64` and rounds every result to `f32`.
+impl Numeric for f32 {
+    fn from_f64(v: Value) -> Self {
+        v as f32
+    }
+
+    fn to_bool(&self) -> bool {
+        *self != 0.0
+    }
+
+    fn unary(op: UnaryOp, arg: Self) -> Result<Self, EvalError> {
+        f64::unary(op, arg.into()).map(|v| v as f32)
+    }
+
+    fn binary(op: BinaryOp, lhs: Self, rhs: Self) -> Result<Self, EvalError> {
+        f64::binary(op, lhs.into(), rhs.into()).map(|v| v as f32)
+    }
+
+    fn supports(func: &Function) -> bool {
+        f64::supports(func)
+    }
+
+    fn call(func: &Function, args: &[Self]) -> Result<Self, EvalError> {
+        let args: Vec<f64> = args.iter().map(|&v| v.into()).collect();
+        f64::call(func, &args).map(|v| v as f32)
+    }
+}
@@ -1,163 +1,448 @@
-use crate::token::{PreToken, Token, Value, UnaryOp, BinaryOp, Function, Constant};
-use crate::error::EvalError;
-use crate::util::{is_literalchar, is_identstr};
+use crate::token::{PreToken, Token, MULTI_CHAR_OPERATORS, Value, UnaryOp, BinaryOp, Function, Constant, Arity};
+use crate::ast::Node;
+use crate::cell::CellRef;
+use crate::error::{EvalError, Span};
+use crate::util::{is_literalchar, is_identstr, is_exponent_prefix};
 use std::str::FromStr;
 
-pub fn parse_str_to_rpn(expr: &str) -> Result<Vec<Vec<Token>>, EvalError> {
-    let tokens_vec = pretoken_to_tokens(parse_str_to_pretokens(expr)?)?;
-    let mut ret_tokens = vec![];
-    for tokens in tokens_vec {
-        ret_tokens.push(to_rpn(tokens)?);
+/// Optional syntax accepted by the parser.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Syntax {
+    /// Cell references such as `$B$2` and `A1:C10` and the aggregates `SUM`, `AVERAGE` and `COUNT`.
+    pub cells: bool,
+    /// Imaginary literals such as `4i` or `2.5j` and the functions `re`, `im`, `conj` and `arg`.
+    pub imaginary: bool,
+}
+
+/// Parses `;` or `,` separated statements, each optionally named by `name = ...`.
+pub(crate) fn parse_str_to_ast(expr: &str, syntax: Syntax) -> Result<Vec<(Option<String>, Node)>, EvalError> {
+    let tokens_vec = pretoken_to_tokens(parse_str_to_pretokens(expr, syntax)?, syntax)?;
+    let mut statements = vec![];
+    for (mut tokens, mut span) in tokens_vec {
+        let mut name = None;
+        if let [(Token::Var(s), var_span), (Token::Assign, assign_span), ..] = tokens.as_slice() {
+            name = Some(s.clone());
+            span = Span::new(assign_span.end.max(var_span.end), span.end);
+            tokens.drain(..2);
+        }
+        if let Some((_, assign_span)) = tokens.iter().find(|(token, _)| *token == Token::Assign) {
+            return Err(EvalError::WrongExpression(*assign_span));
+        }
+        statements.push((name, to_ast(tokens, span)?));
+    }
+    Ok(statements)
+}
+
+fn to_pretoken(s: &str, span: Span, syntax: Syntax) -> Result<(PreToken, Span), EvalError> {
+    // absolute references are the only literals with `$`
+    if syntax.cells && s.contains('$') && s.parse::<CellRef>().is_ok() {
+        return Ok((PreToken::Literal(s.to_owned()), span));
     }
-    Ok(ret_tokens)
+    PreToken::from_str(s)
+        .map(|pretoken| (pretoken, span))
+        .map_err(|_| EvalError::InvalidString(s.to_owned(), span))
 }
 
-fn parse_str_to_pretokens(expr: &str) -> Result<Vec<PreToken>, EvalError> {
+pub(crate) fn parse_str_to_pretokens(expr: &str, syntax: Syntax) -> Result<Vec<(PreToken, Span)>, EvalError> {
     let n = expr.len();
     let mut l = 0;
     let mut pretokens = vec![];
-    for (r, c) in expr.chars().enumerate() {
-        if !is_literalchar(c) {
-            if l < r {pretokens.push(PreToken::from_str(&expr[l..r])?);}
+    let mut chars = expr.char_indices().peekable();
+    while let Some((r, c)) = chars.next() {
+        if (c == '+' || c == '-')
+            && is_exponent_prefix(&expr[l..r])
+            && chars.peek().is_some_and(|(_, next)| next.is_ascii_digit()) {
+            // signed exponent of a number literal such as `1e-3`
+            continue;
+        }
+        if !(is_literalchar(c) || syntax.cells && c == '$') {
+            if l < r {pretokens.push(to_pretoken(&expr[l..r], Span::new(l, r), syntax)?);}
+            if let Some(op) = MULTI_CHAR_OPERATORS.iter().find(|op| expr[r..].starts_with(*op)) {
+                pretokens.push(to_pretoken(op, Span::new(r, r + op.len()), syntax)?);
+                for _ in 1..op.chars().count() {
+                    chars.next();
+                }
+                l = r + op.len();
+                continue;
+            }
             if !c.is_whitespace() {
-                pretokens.push(PreToken::from_str(&c.to_string())?);
+                pretokens.push(to_pretoken(&c.to_string(), Span::new(r, r + c.len_utf8()), syntax)?);
             }
-            l = r+1;
+            l = r + c.len_utf8();
         }
     }
     if l < n {
-        pretokens.push(PreToken::from_str(&expr[l..n])?);
+        pretokens.push(to_pretoken(&expr[l..n], Span::new(l, n), syntax)?);
+    }
+    if syntax.cells {
+        pretokens = join_ranges(pretokens);
     }
     Ok(pretokens)
 }
 
-fn pretoken_to_tokens(pretokens: Vec<PreToken>) -> Result<Vec<Vec<Token>>, EvalError> {
+/// Joins `A1`, `:` and `C10` written without spaces into the literal `A1:C10`,
+/// so that `cond ? A1 : C10` is still a conditional.
+fn join_ranges(pretokens: Vec<(PreToken, Span)>) -> Vec<(PreToken, Span)> {
+    let mut joined: Vec<(PreToken, Span)> = vec![];
+    for (pretoken, span) in pretokens {
+        if let ([.., (PreToken::Literal(from), from_span), (PreToken::Colon, colon_span)], PreToken::Literal(to)) = (joined.as_slice(), &pretoken) {
+            if from_span.end == colon_span.start && colon_span.end == span.start
+                && from.parse::<CellRef>().is_ok() && to.parse::<CellRef>().is_ok() {
+                let range = (PreToken::Literal(format!("{}:{}", from, to)), from_span.to(span));
+                joined.truncate(joined.len() - 2);
+                joined.push(range);
+                continue;
+            }
+        }
+        joined.push((pretoken, span));
+    }
+    joined
+}
+
+/// A cell as a variable named after it, or a cell range.
+fn to_cell_token(s: &str) -> Option<Token> {
+    match s.split_once(':') {
+        Some((from, to)) => Some(Token::Range(from.parse().ok()?, to.parse().ok()?)),
+        None => s.parse::<CellRef>().ok().map(|cell| Token::Var(cell.to_string())),
+    }
+}
+
+/// Coefficient of an imaginary literal such as `4i` or `1.5e3j`.
+fn imaginary_literal(s: &str) -> Option<Value> {
+    // not `infi` or `nanj`, which are names
+    let coef = s.strip_suffix(['i', 'j'])?;
+    if !coef.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+        return None;
+    }
+    coef.parse().ok()
+}
+
+/// Digits of a decimal literal without leading and trailing zeros, and the power of ten of the last one.
+fn decimal_digits(s: &str) -> Option<(String, i64)> {
+    let (mantissa, exp) = match s.split_once(['e', 'E']) {
+        Some((mantissa, exp)) => (mantissa, exp.parse::<i64>().ok()?),
+        None => (s, 0),
+    };
+    let (int, frac) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    let digits = format!("{}{}", int, frac);
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let significant = digits.trim_start_matches('0').trim_end_matches('0');
+    if significant.is_empty() {
+        return Some((String::new(), 0));
+    }
+    let trailing = digits.len() - digits.trim_end_matches('0').len();
+    Some((significant.to_owned(), exp - frac.len() as i64 + trailing as i64))
+}
+
+type Statement = (Vec<(Token, Span)>, Span);
+
+fn pretoken_to_tokens(pretokens: Vec<(PreToken, Span)>, syntax: Syntax) -> Result<Vec<Statement>, EvalError> {
     let mut tokens_vec = vec![];
-    let mut tokens = vec![];
+    let mut tokens: Vec<(Token, Span)> = vec![];
     let mut ptiter = pretokens.into_iter().peekable();
-    let mut paren_count = 0;
-    while let Some(pretoken) = ptiter.next() {
-        match pretoken {
+    let mut paren_spans: Vec<Span> = vec![];
+    let mut start = 0;
+    while let Some((pretoken, span)) = ptiter.next() {
+        let token = match pretoken {
             PreToken::Literal(s) => {
+                let is_call = matches!(ptiter.peek(), Some((PreToken::LeftParen, _)));
                 if let Ok(v) = s.parse::<Value>() {
-                    tokens.push(Token::Value(v));
+                    // keep the text of literals that `f64` rounds, for number types that can hold it
+                    let digits = decimal_digits(&s);
+                    if digits.is_some() && digits != decimal_digits(&v.to_string()) {Token::Literal(v, s)}
+                    else {Token::Value(v)}
                 }
                 else if let Ok(c) = s.replace("_", "").parse::<Constant>() {
-                    tokens.push(Token::Value(c.eval()));
+                    Token::Value(c.eval())
+                }
+                else if let Some(token) = to_cell_token(&s).filter(|_| syntax.cells && !is_call) {
+                    token
+                }
+                else if let Some(v) = imaginary_literal(&s).filter(|_| syntax.imaginary) {
+                    // `4i` is read as `(4 * i())` with the built-in unit, which a variable `i` does not shadow
+                    let unit = [Token::Function(Function::ImaginaryUnit), Token::LeftParen, Token::RightParen];
+                    if v == 1.0 {
+                        tokens.extend(unit.map(|token| (token, span)));
+                    }
+                    else {
+                        tokens.extend([Token::LeftParen, Token::Value(v), Token::Binary(BinaryOp::Mul)].map(|token| (token, span)));
+                        tokens.extend(unit.map(|token| (token, span)));
+                        tokens.push((Token::RightParen, span));
+                    }
+                    continue;
                 }
                 else if is_identstr(&s) {
-                    if let Some(PreToken::LeftParen) = ptiter.peek() {
-                        tokens.push(Token::Function(s.parse::<Function>()?));
+                    if is_call {
+                        if s == "if" {
+                            Token::If
+                        }
+                        else if let Some(func) = Function::aggregate(&s).filter(|_| syntax.cells) {
+                            Token::Function(func)
+                        }
+                        else if let Some(func) = Function::complex(&s).filter(|_| syntax.imaginary) {
+                            Token::Function(func)
+                        }
+                        else {
+                            Token::Function(s.parse::<Function>()?)
+                        }
                     }
                     else {
-                        tokens.push(Token::Var(s.to_string()));
+                        Token::Var(s.to_string())
                     }
                 }
                 else {
-                    return Err(EvalError::InvalidString(s));
+                    return Err(EvalError::InvalidString(s, span));
                 }
             },
-            PreToken::Plus => {
-                tokens.push(Token::Binary(BinaryOp::Add));
-            },
+            PreToken::Plus => Token::Binary(BinaryOp::Add),
             PreToken::Minus => {
                 match tokens.last() {
-                    Some(Token::RightParen) | Some(Token::Value(_)) | Some(Token::Var(_)) => {
-                        tokens.push(Token::Binary(BinaryOp::Sub));
+                    Some((Token::RightParen, _)) | Some((Token::Value(_), _)) | Some((Token::Literal(..), _)) | Some((Token::Var(_), _)) | Some((Token::Range(..), _)) => {
+                        Token::Binary(BinaryOp::Sub)
                     },
-                    _ => {tokens.push(Token::Unary(UnaryOp::Neg));}
+                    _ => Token::Unary(UnaryOp::Neg)
                 }
             },
-            PreToken::Asterisk => {
-                tokens.push(Token::Binary(BinaryOp::Mul));
-            },
-            PreToken::Slash => {
-                tokens.push(Token::Binary(BinaryOp::Div));
-            },
-            PreToken::Percent => {
-                tokens.push(Token::Binary(BinaryOp::Rem));
-            },
+            PreToken::Asterisk => Token::Binary(BinaryOp::Mul),
+            PreToken::Slash => Token::Binary(BinaryOp::Div),
+            PreToken::Percent => Token::Binary(BinaryOp::Rem),
+            PreToken::Caret | PreToken::DoubleAsterisk => Token::Binary(BinaryOp::Pow),
+            PreToken::EqEq => Token::Binary(BinaryOp::Eq),
+            PreToken::NotEq => Token::Binary(BinaryOp::Ne),
+            PreToken::Less => Token::Binary(BinaryOp::Lt),
+            PreToken::LessEq => Token::Binary(BinaryOp::Le),
+            PreToken::Greater => Token::Binary(BinaryOp::Gt),
+            PreToken::GreaterEq => Token::Binary(BinaryOp::Ge),
+            PreToken::AndAnd => Token::Binary(BinaryOp::And),
+            PreToken::OrOr => Token::Binary(BinaryOp::Or),
+            PreToken::Bang => Token::Unary(UnaryOp::Not),
+            PreToken::Question => Token::Question,
+            PreToken::Colon => Token::Colon,
+            PreToken::Assign => Token::Assign,
             PreToken::LeftParen => {
-                tokens.push(Token::LeftParen);
-                paren_count += 1;
+                paren_spans.push(span);
+                Token::LeftParen
             },
             PreToken::RightParen => {
-                tokens.push(Token::RightParen);
-                paren_count -= 1;
+                if paren_spans.pop().is_none() {
+                    return Err(EvalError::UnexpectedParenthesis(span));
+                }
+                Token::RightParen
             },
             PreToken::SemiColon => {
-                tokens_vec.push(std::mem::take(&mut tokens));
+                tokens_vec.push((std::mem::take(&mut tokens), Span::new(start, span.start)));
+                start = span.end;
+                continue;
             }
             PreToken::Comma => {
-                if paren_count > 0 {
-                    tokens.push(Token::Comma);
+                if !paren_spans.is_empty() {
+                    Token::Comma
                 }
                 else {
-                    tokens_vec.push(std::mem::take(&mut tokens));
+                    tokens_vec.push((std::mem::take(&mut tokens), Span::new(start, span.start)));
+                    start = span.end;
+                    continue;
                 }
             },
-        }
-        if paren_count < 0 {
-            return Err(EvalError::UnexpectedParenthesis);
-        }
+        };
+        tokens.push((token, span));
     }
-    if paren_count == 0 {
-        if !tokens.is_empty() {
-            tokens_vec.push(std::mem::take(&mut tokens));
-        }
-        Ok(tokens_vec)
+    if let Some(span) = paren_spans.pop() {
+        return Err(EvalError::UnexpectedParenthesis(span));
+    }
+    if !tokens.is_empty() {
+        let end = tokens.last().map_or(start, |(_, span)| span.end);
+        tokens_vec.push((std::mem::take(&mut tokens), Span::new(start, end)));
     }
-    else {
-        Err(EvalError::UnexpectedParenthesis)
+    Ok(tokens_vec)
+}
+
+/// Pops an operand of the operator at `span`; cell ranges are not operands.
+fn pop_operand(node_stack: &mut Vec<(Node, Span)>, span: Span) -> Result<(Node, Span), EvalError> {
+    match node_stack.pop() {
+        Some((Node::Range(..), range_span)) => Err(EvalError::WrongExpression(range_span)),
+        Some(operand) => Ok(operand),
+        None => Err(EvalError::WrongExpression(span)),
+    }
+}
+
+fn reduce(node_stack: &mut Vec<(Node, Span)>, token: Token, span: Span) -> Result<(), EvalError> {
+    match token {
+        Token::Unary(op) => {
+            let (arg, arg_span) = pop_operand(node_stack, span)?;
+            node_stack.push((Node::unary(op, arg), span.to(arg_span)));
+        },
+        Token::Binary(op) => {
+            let (rhs, rhs_span) = pop_operand(node_stack, span)?;
+            let (lhs, lhs_span) = pop_operand(node_stack, span)?;
+            node_stack.push((Node::binary(op, lhs, rhs), lhs_span.to(rhs_span)));
+        },
+        Token::Colon => {
+            let (otherwise, else_span) = pop_operand(node_stack, span)?;
+            let (then, _) = pop_operand(node_stack, span)?;
+            let (cond, cond_span) = pop_operand(node_stack, span)?;
+            node_stack.push((Node::cond(cond, then, otherwise), cond_span.to(else_span)));
+        },
+        Token::LeftParen | Token::RightParen => {
+            return Err(EvalError::UnexpectedParenthesis(span));
+        },
+        _ => {
+            return Err(EvalError::WrongExpression(span));
+        }
     }
-    
+    Ok(())
 }
 
-fn to_rpn(tokens: Vec<Token>) -> Result<Vec<Token>, EvalError> {
-    // Shunting yard
-    let mut rpn_stack: Vec<Token> = vec![];
-    let mut op_stack: Vec<Token> = vec![];
+fn to_ast(tokens: Vec<(Token, Span)>, span: Span) -> Result<Node, EvalError> {
+    // Shunting yard, reducing operators into nodes instead of emitting RPN
+    let mut node_stack: Vec<(Node, Span)> = vec![];
+    let mut op_stack: Vec<(Token, Span)> = vec![];
+    // (node_stack length at the left parenthesis, number of commas)
+    let mut paren_stack: Vec<(usize, usize)> = vec![];
+
+    // whether the next token must start an operand
+    let mut expect_operand = true;
+    let mut last_span = span;
 
-    for token in tokens.into_iter() {
+    for (token, token_span) in tokens {
+        let empty_call = expect_operand && token == Token::RightParen
+            && matches!(op_stack.as_slice(), [.., (Token::Function(_) | Token::If, _), (Token::LeftParen, _)])
+            && paren_stack.last().is_some_and(|&(base, _)| base == node_stack.len());
+        let starts_operand = matches!(token, Token::Value(_) | Token::Literal(..) | Token::Var(_) | Token::Range(..) | Token::Unary(_) | Token::Function(_) | Token::If | Token::LeftParen);
+        if starts_operand != expect_operand && !empty_call {
+            return Err(EvalError::WrongExpression(token_span));
+        }
+        expect_operand = matches!(
+            token,
+            Token::Unary(_) | Token::Binary(_) | Token::Function(_) | Token::If | Token::LeftParen | Token::Comma | Token::Question | Token::Colon
+        );
+        last_span = token_span;
         match token {
-            Token::Value(_) | Token::Var(_) => {
-                rpn_stack.push(token);
+            Token::Value(v) => {
+                node_stack.push((Node::Value(v), token_span));
+            },
+            Token::Literal(v, s) => {
+                node_stack.push((Node::Literal(v, s), token_span));
+            },
+            Token::Var(s) => {
+                node_stack.push((Node::Var(s), token_span));
+            },
+            Token::Range(from, to) => {
+                node_stack.push((Node::Range(from, to), token_span));
+            },
+            Token::Colon => {
+                // close the innermost `?` and wait for the else branch
+                loop {
+                    match op_stack.pop() {
+                        Some((Token::Question, question_span)) => {
+                            op_stack.push((Token::Colon, question_span));
+                            break
+                        },
+                        Some((Token::LeftParen, _)) | None => {
+                            return Err(EvalError::WrongExpression(token_span));
+                        },
+                        Some((top_token, top_span)) => {
+                            reduce(&mut node_stack, top_token, top_span)?;
+                        }
+                    }
+                }
             },
             _ => {
                 let (l_asc, _) = token.precedence();
-                while let Some(top_token) = op_stack.last() {
+                while let Some((top_token, _)) = op_stack.last() {
                     let (_, r_asc) = top_token.precedence();
                     if l_asc > r_asc {
                         break
                     }
-                    rpn_stack.push(op_stack.pop().unwrap());
+                    let (top_token, top_span) = op_stack.pop().unwrap();
+                    reduce(&mut node_stack, top_token, top_span)?;
                 }
-                if token == Token::RightParen {
-                    match op_stack.last() {
-                        Some(Token::LeftParen) => {
-                            op_stack.pop().unwrap();
-                            if let Some(Token::Function(_)) = op_stack.last() {
-                                rpn_stack.push(op_stack.pop().unwrap());
+                match token {
+                    Token::LeftParen => {
+                        paren_stack.push((node_stack.len(), 0));
+                        op_stack.push((token, token_span));
+                    },
+                    Token::RightParen => {
+                        let (base, commas) = paren_stack.pop().ok_or(EvalError::UnexpectedParenthesis(token_span))?;
+                        let paren_span = match op_stack.pop() {
+                            Some((Token::LeftParen, paren_span)) => paren_span.to(token_span),
+                            _ => {
+                                return Err(EvalError::UnexpectedParenthesis(token_span));
+                            }
+                        };
+                        let n_nodes = node_stack.len() - base;
+                        if let Some((Token::Function(_) | Token::If, _)) = op_stack.last() {
+                            let (func_token, func_span) = op_stack.pop().unwrap();
+                            let call_span = func_span.to(token_span);
+                            let n_args = if n_nodes == 0 && commas == 0 {0} else {commas + 1};
+                            if n_nodes != n_args {
+                                return Err(EvalError::WrongExpression(paren_span));
+                            }
+                            if !matches!(&func_token, Token::Function(func) if func.is_aggregate()) {
+                                if let Some((_, range_span)) = node_stack[base..].iter().find(|(node, _)| matches!(node, Node::Range(..))) {
+                                    return Err(EvalError::WrongExpression(*range_span));
+                                }
+                            }
+                            let args = node_stack.split_off(base).into_iter().map(|(arg, _)| arg).collect::<Vec<Node>>();
+                            match func_token {
+                                Token::Function(func @ Function::Custom(_)) => {
+                                    node_stack.push((Node::call(func, args), call_span));
+                                },
+                                Token::Function(func) => {
+                                    if !func.arity().accepts(n_args) {
+                                        return Err(EvalError::WrongArguments {
+                                            name: func.name().to_owned(),
+                                            expected: func.arity(),
+                                            found: n_args,
+                                            span: Some(call_span)
+                                        });
+                                    }
+                                    node_stack.push((Node::call(func, args), call_span));
+                                },
+                                _ => {
+                                    let [cond, then, otherwise]: [Node; 3] = args.try_into().map_err(|_| EvalError::WrongArguments {
+                                        name: "if".to_owned(),
+                                        expected: Arity::exact(3),
+                                        found: n_args,
+                                        span: Some(call_span)
+                                    })?;
+                                    node_stack.push((Node::cond(cond, then, otherwise), call_span));
+                                }
+                            }
+                        }
+                        else if n_nodes != 1 || commas != 0 {
+                            return Err(EvalError::WrongExpression(paren_span));
+                        }
+                        else if let Some((_, node_span)) = node_stack.last_mut() {
+                            *node_span = paren_span;
+                        }
+                    },
+                    Token::Comma => {
+                        match paren_stack.last_mut() {
+                            Some((_, commas)) => {*commas += 1;},
+                            None => {
+                                return Err(EvalError::WrongExpression(token_span));
                             }
-                            continue;
-                        },
-                        _ => {
-                            return Err(EvalError::UnexpectedParenthesis);
                         }
+                    },
+                    _ => {
+                        op_stack.push((token, token_span));
                     }
                 }
-                if token != Token::Comma {
-                    op_stack.push(token);
-                }
-            } 
+            }
         }
     }
-    while let Some(top_token) = op_stack.pop() {
-        if top_token == Token::LeftParen || top_token == Token::RightParen {
-            return Err(EvalError::UnexpectedParenthesis);
-        }
-        rpn_stack.push(top_token);
+    if expect_operand {
+        return Err(EvalError::WrongExpression(last_span));
     }
-    Ok(rpn_stack)
-
-}
\ No newline at end of file
+    while let Some((top_token, top_span)) = op_stack.pop() {
+        reduce(&mut node_stack, top_token, top_span)?;
+    }
+    match node_stack.len() {
+        0 => Err(EvalError::WrongExpression(span)),
+        1 => pop_operand(&mut node_stack, span).map(|(node, _)| node),
+        _ => Err(EvalError::WrongExpression(node_stack[1].1)),
+    }
+}
@@ -0,0 +1,198 @@
+use crate::numeric::Numeric;
+use crate::token::{Value, UnaryOp, BinaryOp, Function};
+use crate::error::EvalError;
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::{One, Signed, ToPrimitive, Zero};
+
+/// Exact fractions of arbitrary size.
+///
+/// Literals are read from their digits, so `0.1` is exactly `1/10` and
+/// `0.1000000000000000000001 - 0.1` is not zero. Other `f64` values are converted through
+/// their shortest decimal representation. Operations whose result is not rational, such as
+/// `sqrt(2)` or division by zero, fail with [`EvalError::Inexact`], as do infinite and
+/// NaN values, literals with exponents beyond 1000 and powers of more than a million bits.
+/// Trigonometric, exponential and logarithmic functions are not supported.
+/// [`Numeric::from_f64`] itself maps infinite and NaN values to zero.
+impl Numeric for BigRational {
+    fn from_f64(v: Value) -> Self {
+        if v.is_finite() {decimal(v)}
+        else {BigRational::zero()}
+    }
+
+    fn try_from_f64(v: Value) -> Result<Self, EvalError> {
+        if v.is_finite() {Ok(decimal(v))}
+        else {Err(inexact(&v.to_string()))}
+    }
+
+    fn from_literal(_v: Value, text: &str) -> Result<Self, EvalError> {
+        parse_decimal(text).ok_or_else(|| inexact(text))
+    }
+
+    fn to_bool(&self) -> bool {
+        !self.is_zero()
+    }
+
+    fn to_f64(&self) -> Option<Value> {
+        ToPrimitive::to_f64(self)
+    }
+
+    fn unary(op: UnaryOp, arg: Self) -> Result<Self, EvalError> {
+        let v = match op {
+            UnaryOp::Neg => -arg,
+            UnaryOp::Not => Self::from_bool(arg.is_zero()),
+        };
+        Ok(v)
+    }
+
+    fn binary(op: BinaryOp, lhs: Self, rhs: Self) -> Result<Self, EvalError> {
+        let v = match op {
+            BinaryOp::Add => lhs + rhs,
+            BinaryOp::Sub => lhs - rhs,
+            BinaryOp::Mul => lhs * rhs,
+            BinaryOp::Div => {
+                if rhs.is_zero() {return Err(inexact("/"))}
+                lhs / rhs
+            },
+            BinaryOp::Rem => {
+                if rhs.is_zero() {return Err(inexact("%"))}
+                lhs % rhs
+            },
+            BinaryOp::Pow => pow(&lhs, &rhs).ok_or_else(|| inexact("^"))?,
+            BinaryOp::Eq => Self::from_bool(lhs == rhs),
+            BinaryOp::Ne => Self::from_bool(lhs != rhs),
+            BinaryOp::Lt => Self::from_bool(lhs < rhs),
+            BinaryOp::Le => Self::from_bool(lhs <= rhs),
+            BinaryOp::Gt => Self::from_bool(lhs > rhs),
+            BinaryOp::Ge => Self::from_bool(lhs >= rhs),
+            BinaryOp::And => Self::from_bool(lhs.to_bool() && rhs.to_bool()),
+            BinaryOp::Or => Self::from_bool(lhs.to_bool() || rhs.to_bool()),
+        };
+        Ok(v)
+    }
+
+    /// The functions with rational results for some rational arguments,
+    /// which excludes trigonometric, exponential and logarithmic functions.
+    fn supports(func: &Function) -> bool {
+        matches!(
+            func,
+            Function::Min | Function::Max | Function::Hypot | Function::Sum | Function::Average | Function::Count
+            | Function::Floor | Function::Ceil | Function::Round | Function::Fract | Function::Trunc
+            | Function::Abs | Function::Signum | Function::Sqrt | Function::Cbrt
+            | Function::Powi | Function::Powf | Function::DivEuclid | Function::RemEuclid
+        )
+    }
+
+    fn call(func: &Function, args: &[Self]) -> Result<Self, EvalError> {
+        let v = match (func, args) {
+            (Function::Min, _) => args.iter().min().cloned(),
+            (Function::Max, _) => args.iter().max().cloned(),
+            (Function::Hypot, _) => root(&args.iter().map(|x| x * x).sum(), 2),
+            (Function::Sum, _) => Some(args.iter().sum()),
+            (Function::Average, [_, ..]) => Some(args.iter().sum::<BigRational>() / BigInt::from(args.len())),
+            (Function::Count, _) => Some(BigRational::from_integer(args.len().into())),
+            (_, [x]) => call1(func, x),
+            (_, [lhs, rhs]) => call2(func, lhs, rhs),
+            _ => None,
+        };
+        v.ok_or_else(|| inexact(func.name()))
+    }
+}
+
+fn inexact(name: &str) -> EvalError {
+    EvalError::Inexact(name.to_owned())
+}
+
+/// Fraction with the digits of the shortest decimal that converts back to `v`.
+fn decimal(v: Value) -> BigRational {
+    parse_decimal(&v.to_string()).unwrap()
+}
+
+/// Exact value of a decimal such as `12.5` or `1.25e-3`.
+///
+/// Exponents beyond `MAX_EXP` are rejected rather than expanded into huge integers.
+fn parse_decimal(s: &str) -> Option<BigRational> {
+    const MAX_EXP: u32 = 1000;
+    let (mantissa, exp) = match s.split_once(['e', 'E']) {
+        Some((mantissa, exp)) => (mantissa, exp.parse::<i32>().ok().filter(|e| e.unsigned_abs() <= MAX_EXP)?),
+        None => (s, 0),
+    };
+    let (int, frac) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    let numer = format!("{}{}", int, frac).parse::<BigInt>().ok()?;
+    let exp = exp - frac.len() as i32;
+    let scale = BigInt::from(10).pow(exp.unsigned_abs());
+    if exp >= 0 {Some(BigRational::from_integer(numer * scale))}
+    else {Some(BigRational::new(numer, scale))}
+}
+
+fn call1(func: &Function, x: &BigRational) -> Option<BigRational> {
+    let v = match func {
+        Function::Floor => x.floor(),
+        Function::Ceil => x.ceil(),
+        Function::Round => x.round(),
+        Function::Fract => x.fract(),
+        Function::Trunc => x.trunc(),
+        Function::Abs => x.abs(),
+        // same as `f64::signum`, which is 1 at zero
+        Function::Signum => {
+            if x.is_negative() {-BigRational::one()}
+            else {BigRational::one()}
+        },
+        Function::Sqrt => return root(x, 2),
+        Function::Cbrt => return root(x, 3),
+        _ => return None,
+    };
+    Some(v)
+}
+
+fn call2(func: &Function, lhs: &BigRational, rhs: &BigRational) -> Option<BigRational> {
+    match func {
+        Function::Powi => pow(lhs, &rhs.trunc()),
+        Function::Powf => pow(lhs, rhs),
+        Function::DivEuclid => div_euclid(lhs, rhs),
+        Function::RemEuclid => div_euclid(lhs, rhs).map(|q| lhs - rhs * q),
+        _ => None,
+    }
+}
+
+/// `base^exp` if it is rational, with the principal root for fractional exponents.
+///
+/// Powers with more than `MAX_BITS` bits are not computed.
+fn pow(base: &BigRational, exp: &BigRational) -> Option<BigRational> {
+    const MAX_BITS: u64 = 1 << 20;
+    let power = exp.numer().to_i32()?;
+    let base = root(base, exp.denom().to_u32()?)?;
+    if base.is_zero() && power < 0 {
+        return None;
+    }
+    let bits = base.numer().bits().max(base.denom().bits());
+    if bits > 1 && bits.saturating_mul(power.unsigned_abs() as u64) > MAX_BITS {
+        return None;
+    }
+    Some(base.pow(power))
+}
+
+/// Exact `n`-th root of `x`.
+fn root(x: &BigRational, n: u32) -> Option<BigRational> {
+    if n == 1 {
+        return Some(x.clone());
+    }
+    if x.is_negative() && n.is_multiple_of(2) {
+        return None;
+    }
+    let exact = |v: &BigInt| {
+        let r = v.nth_root(n);
+        if &r.pow(n) == v {Some(r)}
+        else {None}
+    };
+    Some(BigRational::new(exact(x.numer())?, exact(x.denom())?))
+}
+
+fn div_euclid(lhs: &BigRational, rhs: &BigRational) -> Option<BigRational> {
+    if rhs.is_zero() {
+        return None;
+    }
+    let q = lhs / rhs;
+    if rhs.is_positive() {Some(q.floor())}
+    else {Some(q.ceil())}
+}
@@ -0,0 +1,220 @@
+use crate::eval::Expr;
+use crate::ast::{Node, Visitor, walk_node};
+use crate::context::Context;
+use crate::error::EvalError;
+use crate::token::{Value, Arity, Function};
+use std::collections::BTreeMap;
+
+/// Named formulas that may refer to each other and to input values.
+///
+/// Formulas are evaluated in dependency order by [`FormulaSheet::recompute`], and
+/// only the ones affected by a change since the last call are evaluated again.
+#[derive(Debug, Clone, Default)]
+pub struct FormulaSheet {
+    formulas: BTreeMap<String, Formula>,
+    /// Formula names, each after the formulas it depends on.
+    order: Vec<String>,
+    /// Inputs and the results of the formulas.
+    context: Context,
+}
+
+#[derive(Debug, Clone)]
+struct Formula {
+    expr: Expr,
+    deps: Vec<String>,
+    /// Custom functions of the sheet that the formula calls.
+    funcs: Vec<String>,
+    dirty: bool,
+}
+
+impl FormulaSheet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parses `formula` and stores it under `name`. See [`FormulaSheet::set_expr`].
+    pub fn set_formula(&mut self, name: &str, formula: &str) -> Result<&mut Self, EvalError> {
+        self.set_expr(name, Expr::new(formula)?)
+    }
+
+    /// Stores the first expression of `expr` under `name`, replacing the formula or input of that name.
+    ///
+    /// Only the first expression is evaluated, so the names in the others are not dependencies.
+    /// Values and functions in the context of `expr` only apply to this formula, and take
+    /// precedence over the inputs and functions of the sheet.
+    /// Fails with [`EvalError::CyclicDependency`] if `name` would depend on itself,
+    /// in which case the sheet is left unchanged.
+    pub fn set_expr(&mut self, name: &str, expr: Expr) -> Result<&mut Self, EvalError> {
+        let mut names = Names::default();
+        if let Some(node) = expr.nodes().first() {
+            node.accept(&mut names);
+        }
+        let deps = names.vars.into_iter().filter(|var| expr.context().get_value(var).is_none()).collect();
+        let funcs = names.funcs.into_iter().filter(|func| expr.context().get_func(func).is_none()).collect();
+        let formula = Formula {deps, funcs, expr, dirty: true};
+        let old = self.formulas.insert(name.to_owned(), formula);
+        match sort(&self.formulas) {
+            Ok(order) => self.order = order,
+            Err(e) => {
+                match old {
+                    Some(old) => self.formulas.insert(name.to_owned(), old),
+                    None => self.formulas.remove(name),
+                };
+                return Err(e);
+            },
+        }
+        self.invalidate(|formula| formula.deps.iter().any(|dep| dep == name));
+        Ok(self)
+    }
+
+    /// Sets the input `name`, replacing the formula of that name if there is one.
+    pub fn set_value(&mut self, name: &str, val: Value) -> &mut Self {
+        if self.formulas.remove(name).is_some() {
+            self.order.retain(|s| s != name);
+        }
+        else if self.context.get_value(name) == Some(&val) {
+            return self;
+        }
+        self.context.set_value(name, val);
+        self.invalidate(|formula| formula.deps.iter().any(|dep| dep == name));
+        self
+    }
+
+    /// Sets the custom function `name`, which the formulas calling it are evaluated again with.
+    pub fn set_func<F>(&mut self, name: &str, arity: impl Into<Arity>, f: F) -> &mut Self
+    where F: Fn(&[Value]) -> Value + Send + Sync + 'static {
+        self.context.set_func(name, arity, f);
+        self.invalidate(|formula| formula.funcs.iter().any(|func| func == name));
+        self
+    }
+
+    /// Value of the input or formula `name`.
+    ///
+    /// Returns `None` for formulas that changed or depend on a change since the last
+    /// [`FormulaSheet::recompute`].
+    pub fn get(&self, name: &str) -> Option<Value> {
+        match self.formulas.get(name) {
+            Some(formula) if formula.dirty => None,
+            _ => self.context.get_value(name).copied(),
+        }
+    }
+
+    pub fn formula(&self, name: &str) -> Option<&Expr> {
+        self.formulas.get(name).map(|formula| &formula.expr)
+    }
+
+    /// Formula names in evaluation order.
+    pub fn order(&self) -> &[String] {
+        &self.order
+    }
+
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+
+    /// Evaluates the formulas affected by the changes since the last call and
+    /// returns how many were evaluated.
+    ///
+    /// On error the failed formula and the ones after it stay pending.
+    pub fn recompute(&mut self) -> Result<usize, EvalError> {
+        let mut count = 0;
+        for name in self.order.iter() {
+            let formula = self.formulas.get_mut(name).expect("every name in order is a formula");
+            if !formula.dirty {
+                continue;
+            }
+            let value = if formula.expr.context().is_empty() {
+                formula.expr.eval_index_with(0, &self.context)?
+            }
+            else {
+                formula.expr.eval_index_with(0, &Context::ctx_merge(formula.expr.context(), &self.context))?
+            };
+            self.context.set_value(name, value);
+            formula.dirty = false;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Marks the formulas that are `affected` by a change, and every formula depending on them, as dirty.
+    fn invalidate(&mut self, affected: impl Fn(&Formula) -> bool) {
+        let mut changed: Vec<String> = vec![];
+        for s in self.order.iter() {
+            let formula = self.formulas.get_mut(s).expect("every name in order is a formula");
+            if affected(formula) || formula.deps.iter().any(|dep| changed.contains(dep)) {
+                formula.dirty = true;
+            }
+            if formula.dirty {
+                changed.push(s.clone());
+            }
+        }
+    }
+}
+
+/// Variables and custom functions referred to by a formula.
+#[derive(Default)]
+struct Names {
+    vars: Vec<String>,
+    funcs: Vec<String>,
+}
+
+impl Visitor for Names {
+    fn visit_node(&mut self, node: &Node) {
+        match node {
+            Node::Var(s) => self.vars.push(s.clone()),
+            Node::Call(Function::Custom(s), _) => self.funcs.push(s.clone()),
+            _ => {},
+        }
+        walk_node(self, node);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mark {
+    Visiting,
+    Done,
+}
+
+/// Orders the formulas topologically, or returns the first cycle found.
+fn sort(formulas: &BTreeMap<String, Formula>) -> Result<Vec<String>, EvalError> {
+    let mut marks = BTreeMap::new();
+    let mut path = vec![];
+    let mut order = vec![];
+    for name in formulas.keys() {
+        visit(name, formulas, &mut marks, &mut path, &mut order)?;
+    }
+    Ok(order)
+}
+
+fn visit<'a>(
+    name: &'a str,
+    formulas: &'a BTreeMap<String, Formula>,
+    marks: &mut BTreeMap<&'a str, Mark>,
+    path: &mut Vec<&'a str>,
+    order: &mut Vec<String>
+) -> Result<(), EvalError> {
+    match marks.get(name) {
+        Some(Mark::Done) => return Ok(()),
+        Some(Mark::Visiting) => {
+            let start = path.iter().position(|s| *s == name).expect("visiting names are on the path");
+            let mut cycle: Vec<String> = path[start..].iter().map(|s| s.to_string()).collect();
+            cycle.push(name.to_owned());
+            return Err(EvalError::CyclicDependency(cycle));
+        },
+        None => {},
+    }
+    // anything that is not a formula is an input
+    let formula = match formulas.get(name) {
+        Some(formula) => formula,
+        None => return Ok(()),
+    };
+    marks.insert(name, Mark::Visiting);
+    path.push(name);
+    for dep in formula.deps.iter() {
+        visit(dep, formulas, marks, path, order)?;
+    }
+    path.pop();
+    marks.insert(name, Mark::Done);
+    order.push(name.to_owned());
+    Ok(())
+}
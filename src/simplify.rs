@@ -0,0 +1,244 @@
+use crate::ast::{Node, Fold};
+use crate::token::{Value, UnaryOp, BinaryOp, Function, to_bool};
+use crate::cell::CellRef;
+use crate::numeric::Numeric;
+use std::cmp::Ordering;
+
+/// Simplifies `node` bottom-up.
+///
+/// Sums are flattened into like terms with numeric coefficients and products
+/// into like bases with numeric exponents, so `x + 1 + 2`, `x - x`, `x * 0`,
+/// `2 * x + x` and `x * y / x` all collapse, assuming that no subexpression is NaN
+/// or infinite.
+pub(crate) fn simplify(node: &Node) -> Node {
+    node.fold(&mut Simplifier)
+}
+
+struct Simplifier;
+
+impl Fold for Simplifier {
+    type Output = Node;
+
+    fn fold_value(&mut self, v: Value) -> Node {
+        Node::Value(v)
+    }
+
+    fn fold_var(&mut self, name: &str) -> Node {
+        Node::Var(name.to_owned())
+    }
+
+    fn fold_range(&mut self, from: CellRef, to: CellRef) -> Node {
+        Node::Range(from, to)
+    }
+
+    fn fold_unary(&mut self, op: UnaryOp, arg: Node) -> Node {
+        match (op, arg) {
+            (op, Node::Value(v)) => Node::Value(op.eval(v)),
+            (UnaryOp::Neg, arg) => sum(vec![(-1.0, arg)]),
+            (op, arg) => Node::unary(op, arg),
+        }
+    }
+
+    fn fold_binary(&mut self, op: BinaryOp, lhs: Node, rhs: Node) -> Node {
+        if let (Some(l), Some(r)) = (lhs.as_value(), rhs.as_value()) {
+            return Node::Value(op.eval(l, r));
+        }
+        match op {
+            BinaryOp::Add => sum(vec![(1.0, lhs), (1.0, rhs)]),
+            BinaryOp::Sub => sum(vec![(1.0, lhs), (-1.0, rhs)]),
+            BinaryOp::Mul => product(vec![(lhs, 1.0), (rhs, 1.0)]),
+            BinaryOp::Div => product(vec![(lhs, 1.0), (rhs, -1.0)]),
+            BinaryOp::Pow => {
+                match (lhs.as_value(), rhs.as_value()) {
+                    (_, Some(0.0)) | (Some(1.0), _) => Node::Value(1.0),
+                    (_, Some(e)) if e.fract() == 0.0 => product(vec![(lhs, e)]),
+                    _ => Node::binary(op, lhs, rhs),
+                }
+            },
+            _ => Node::binary(op, lhs, rhs),
+        }
+    }
+
+    fn fold_call(&mut self, func: &Function, args: Vec<Node>) -> Node {
+        // calls with the wrong number of arguments or unsupported functions are left for evaluation to report
+        if matches!(func, Function::Custom(_)) || !func.arity().accepts(args.len()) || !Value::supports(func) {
+            return Node::call(func.clone(), args);
+        }
+        let values: Option<Vec<Value>> = args.iter().map(|arg| arg.as_value()).collect();
+        match values.as_deref() {
+            Some([v]) => Node::Value(func.eval1(*v)),
+            Some([v1, v2]) => Node::Value(func.eval2((*v1, *v2))),
+            Some(values) => Node::Value(func.evaln(values)),
+            None => Node::call(func.clone(), args),
+        }
+    }
+
+    fn fold_cond(&mut self, cond: Node, then: Node, otherwise: Node) -> Node {
+        match cond.as_value() {
+            Some(v) => {
+                if to_bool(v) {then}
+                else {otherwise}
+            },
+            None if then == otherwise => then,
+            None => Node::cond(cond, then, otherwise),
+        }
+    }
+}
+
+/// Builds the sum of `coeff * node` with the like terms collected.
+fn sum(terms: Vec<(Value, Node)>) -> Node {
+    let mut constant = 0.0;
+    let mut like = vec![];
+    for (coeff, node) in terms {
+        add_term(node, coeff, &mut constant, &mut like);
+    }
+
+    let mut result: Option<Node> = None;
+    for (node, coeff) in like {
+        if coeff == 0.0 {
+            continue;
+        }
+        let term = scale(coeff.abs(), node);
+        result = Some(match result {
+            None if coeff < 0.0 => Node::unary(UnaryOp::Neg, term),
+            None => term,
+            Some(acc) if coeff < 0.0 => Node::binary(BinaryOp::Sub, acc, term),
+            Some(acc) => Node::binary(BinaryOp::Add, acc, term),
+        });
+    }
+    match result {
+        None => Node::Value(constant),
+        Some(acc) if constant == 0.0 => acc,
+        Some(acc) if constant < 0.0 => Node::binary(BinaryOp::Sub, acc, Node::Value(-constant)),
+        Some(acc) => Node::binary(BinaryOp::Add, acc, Node::Value(constant)),
+    }
+}
+
+fn add_term(node: Node, coeff: Value, constant: &mut Value, like: &mut Vec<(Node, Value)>) {
+    match node {
+        Node::Value(v) => *constant += coeff * v,
+        Node::Unary(UnaryOp::Neg, arg) => add_term(*arg, -coeff, constant, like),
+        Node::Binary(BinaryOp::Add, lhs, rhs) => {
+            add_term(*lhs, coeff, constant, like);
+            add_term(*rhs, coeff, constant, like);
+        },
+        Node::Binary(BinaryOp::Sub, lhs, rhs) => {
+            add_term(*lhs, coeff, constant, like);
+            add_term(*rhs, -coeff, constant, like);
+        },
+        Node::Binary(BinaryOp::Mul, lhs, rhs) if lhs.as_value().is_some() => {
+            add_term(*rhs, coeff * lhs.as_value().unwrap(), constant, like);
+        },
+        node => {
+            match like.iter_mut().find(|(n, _)| *n == node) {
+                Some((_, c)) => *c += coeff,
+                None => like.push((node, coeff)),
+            }
+        },
+    }
+}
+
+fn scale(coeff: Value, node: Node) -> Node {
+    if coeff == 1.0 {node}
+    else {Node::binary(BinaryOp::Mul, Node::Value(coeff), node)}
+}
+
+/// Builds the product of `node ^ exp` with the like bases collected.
+fn product(factors: Vec<(Node, Value)>) -> Node {
+    let mut coeff = 1.0;
+    let mut like = vec![];
+    for (node, exp) in factors {
+        mul_factor(node, exp, &mut coeff, &mut like);
+    }
+    if coeff == 0.0 {
+        return Node::Value(0.0);
+    }
+    // factors commute, so `x * y` and `y * x` become the same term
+    like.sort_by(|(a, _), (b, _)| cmp_nodes(a, b));
+
+    let mut numerator: Option<Node> = None;
+    let mut denominator: Option<Node> = None;
+    for (base, exp) in like {
+        if exp == 0.0 {
+            continue;
+        }
+        let (acc, exp) = if exp > 0.0 {(&mut numerator, exp)} else {(&mut denominator, -exp)};
+        let factor = if exp == 1.0 {base} else {Node::binary(BinaryOp::Pow, base, Node::Value(exp))};
+        *acc = Some(match acc.take() {
+            None => factor,
+            Some(acc) => Node::binary(BinaryOp::Mul, acc, factor),
+        });
+    }
+    match (numerator, denominator) {
+        (None, None) => Node::Value(coeff),
+        (None, Some(den)) => Node::binary(BinaryOp::Div, Node::Value(coeff), den),
+        (Some(num), den) => {
+            let body = match den {
+                Some(den) => Node::binary(BinaryOp::Div, num, den),
+                None => num,
+            };
+            if coeff == 1.0 {body}
+            else if coeff == -1.0 {Node::unary(UnaryOp::Neg, body)}
+            else {Node::binary(BinaryOp::Mul, Node::Value(coeff), body)}
+        },
+    }
+}
+
+fn mul_factor(node: Node, exp: Value, coeff: &mut Value, like: &mut Vec<(Node, Value)>) {
+    // splitting a base is only valid for integer exponents
+    let integer = exp.fract() == 0.0;
+    match node {
+        Node::Value(v) => *coeff *= v.powf(exp),
+        Node::Unary(UnaryOp::Neg, arg) if integer => {
+            *coeff *= (-1.0 as Value).powf(exp);
+            mul_factor(*arg, exp, coeff, like);
+        },
+        Node::Binary(BinaryOp::Mul, lhs, rhs) if integer => {
+            mul_factor(*lhs, exp, coeff, like);
+            mul_factor(*rhs, exp, coeff, like);
+        },
+        Node::Binary(BinaryOp::Div, lhs, rhs) if integer => {
+            mul_factor(*lhs, exp, coeff, like);
+            mul_factor(*rhs, -exp, coeff, like);
+        },
+        Node::Binary(BinaryOp::Pow, base, e) if integer && e.as_value().is_some() => {
+            mul_factor(*base, exp * e.as_value().unwrap(), coeff, like);
+        },
+        node => {
+            match like.iter_mut().find(|(n, _)| *n == node) {
+                Some((_, e)) => *e += exp,
+                None => like.push((node, exp)),
+            }
+        },
+    }
+}
+
+/// An arbitrary but fixed total order of nodes.
+fn cmp_nodes(a: &Node, b: &Node) -> Ordering {
+    fn rank(node: &Node) -> u8 {
+        match node {
+            Node::Value(_) | Node::Literal(..) => 0,
+            Node::Var(_) => 1,
+            Node::Unary(..) => 2,
+            Node::Binary(..) => 3,
+            Node::Call(..) => 4,
+            Node::Cond(..) => 5,
+            Node::Range(..) => 6,
+        }
+    }
+    let children = |a: &Node, b: &Node| {
+        a.children().into_iter().zip(b.children())
+            .map(|(a, b)| cmp_nodes(a, b))
+            .find(|o| o.is_ne())
+            .unwrap_or_else(|| a.children().len().cmp(&b.children().len()))
+    };
+    match (a, b) {
+        (Node::Value(a), Node::Value(b)) => a.total_cmp(b),
+        (Node::Var(a), Node::Var(b)) => a.cmp(b),
+        (Node::Range(a_from, a_to), Node::Range(b_from, b_to)) => (a_from, a_to).cmp(&(b_from, b_to)),
+        (Node::Unary(op_a, _), Node::Unary(op_b, _)) => (*op_a as u8).cmp(&(*op_b as u8)).then_with(|| children(a, b)),
+        (Node::Binary(op_a, ..), Node::Binary(op_b, ..)) => (*op_a as u8).cmp(&(*op_b as u8)).then_with(|| children(a, b)),
+        (Node::Call(f_a, _), Node::Call(f_b, _)) => f_a.name().cmp(f_b.name()).then_with(|| children(a, b)),
+        _ => rank(a).cmp(&rank(b)).then_with(|| children(a, b)),
+    }
+}
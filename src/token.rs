@@ -1,18 +1,54 @@
-use crate::error::EvalError;
+use crate::error::{EvalError, Span};
+use crate::cell::CellRef;
 use crate::util::is_literalchar;
 use std::str::FromStr;
+use std::ops::{RangeInclusive, RangeFrom};
 
 pub type Value = f64;
 
+pub(crate) fn to_bool(v: Value) -> bool {
+    v != 0.0
+}
+
+pub(crate) fn from_bool(b: bool) -> Value {
+    if b {1.0} else {0.0}
+}
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum UnaryOp {
     Neg,
+    Not,
 }
 
 impl UnaryOp {
     pub(crate) fn eval(&self, arg: Value) -> Value {
         match self {
             Self::Neg => -arg,
+            Self::Not => from_bool(!to_bool(arg)),
+        }
+    }
+
+    /// Applies the operator in place; each arm is a separate loop so it can be vectorised.
+    pub(crate) fn eval_slice(&self, args: &mut [Value]) {
+        macro_rules! apply {
+            ($op:expr) => {
+                for x in args.iter_mut() {
+                    *x = $op.eval(*x);
+                }
+            };
+        }
+        match self {
+            Self::Neg => apply!(Self::Neg),
+            Self::Not => apply!(Self::Not),
+        }
+    }
+}
+
+impl std::fmt::Display for UnaryOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Neg => write!(f, "-"),
+            Self::Not => write!(f, "!"),
         }
     }
 }
@@ -24,6 +60,15 @@ pub enum BinaryOp {
     Mul,
     Div,
     Rem,
+    Pow,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
 }
 
 impl BinaryOp {
@@ -34,10 +79,68 @@ impl BinaryOp {
             Self::Mul => lhs * rhs,
             Self::Div => lhs / rhs,
             Self::Rem => lhs % rhs,
+            Self::Pow => lhs.powf(rhs),
+            Self::Eq => from_bool(lhs == rhs),
+            Self::Ne => from_bool(lhs != rhs),
+            Self::Lt => from_bool(lhs < rhs),
+            Self::Le => from_bool(lhs <= rhs),
+            Self::Gt => from_bool(lhs > rhs),
+            Self::Ge => from_bool(lhs >= rhs),
+            Self::And => from_bool(to_bool(lhs) && to_bool(rhs)),
+            Self::Or => from_bool(to_bool(lhs) || to_bool(rhs)),
+        }
+    }
+
+    /// Applies the operator element-wise, writing the results into `lhs`.
+    pub(crate) fn eval_slice(&self, lhs: &mut [Value], rhs: &[Value]) {
+        macro_rules! apply {
+            ($op:expr) => {
+                for (l, r) in lhs.iter_mut().zip(rhs) {
+                    *l = $op.eval(*l, *r);
+                }
+            };
+        }
+        match self {
+            Self::Add => apply!(Self::Add),
+            Self::Sub => apply!(Self::Sub),
+            Self::Mul => apply!(Self::Mul),
+            Self::Div => apply!(Self::Div),
+            Self::Rem => apply!(Self::Rem),
+            Self::Pow => apply!(Self::Pow),
+            Self::Eq => apply!(Self::Eq),
+            Self::Ne => apply!(Self::Ne),
+            Self::Lt => apply!(Self::Lt),
+            Self::Le => apply!(Self::Le),
+            Self::Gt => apply!(Self::Gt),
+            Self::Ge => apply!(Self::Ge),
+            Self::And => apply!(Self::And),
+            Self::Or => apply!(Self::Or),
         }
     }
 }
 
+impl std::fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let s = match self {
+            Self::Add => "+",
+            Self::Sub => "-",
+            Self::Mul => "*",
+            Self::Div => "/",
+            Self::Rem => "%",
+            Self::Pow => "^",
+            Self::Eq => "==",
+            Self::Ne => "!=",
+            Self::Lt => "<",
+            Self::Le => "<=",
+            Self::Gt => ">",
+            Self::Ge => ">=",
+            Self::And => "&&",
+            Self::Or => "||",
+        };
+        write!(f, "{}", s)
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Constant {
     PI,
@@ -53,6 +156,75 @@ impl Constant {
             Self::E => std::f64::consts::E
         }
     }
+
+    pub(crate) fn from_value(v: Value) -> Option<Self> {
+        [Self::PI, Self::Tau, Self::E].into_iter().find(|c| c.eval() == v)
+    }
+}
+
+/// Number of arguments a function accepts.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Arity {
+    min: usize,
+    max: Option<usize>,
+}
+
+impl Arity {
+    pub fn exact(n: usize) -> Self {
+        Self {min: n, max: Some(n)}
+    }
+
+    pub fn range(min: usize, max: usize) -> Self {
+        Self {min, max: Some(max)}
+    }
+
+    pub fn at_least(min: usize) -> Self {
+        Self {min, max: None}
+    }
+
+    pub fn variadic() -> Self {
+        Self::at_least(0)
+    }
+
+    pub fn min(&self) -> usize {
+        self.min
+    }
+
+    pub fn max(&self) -> Option<usize> {
+        self.max
+    }
+
+    pub fn accepts(&self, n: usize) -> bool {
+        self.min <= n && self.max.is_none_or(|max| n <= max)
+    }
+}
+
+impl From<usize> for Arity {
+    fn from(n: usize) -> Self {
+        Self::exact(n)
+    }
+}
+
+impl From<RangeInclusive<usize>> for Arity {
+    fn from(r: RangeInclusive<usize>) -> Self {
+        Self::range(*r.start(), *r.end())
+    }
+}
+
+impl From<RangeFrom<usize>> for Arity {
+    fn from(r: RangeFrom<usize>) -> Self {
+        Self::at_least(r.start)
+    }
+}
+
+impl std::fmt::Display for Arity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.max {
+            Some(max) if max == self.min => write!(f, "{}", max),
+            Some(max) => write!(f, "{}..={}", self.min, max),
+            None => write!(f, "{}..", self.min),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -88,24 +260,113 @@ pub enum Function {
     Signum,
     DivEuclid,
     RemEuclid,
+    Sum,
+    Average,
+    Count,
+    Re,
+    Im,
+    Conj,
+    Arg,
+    /// The `i` of imaginary literals such as `4i`.
+    ImaginaryUnit,
     Custom(String)
 }
 
 impl Function {
-    pub(crate) fn num_args(&self) -> usize {
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Min => "min",
+            Self::Max => "max",
+            Self::Powi => "powi",
+            Self::Powf => "powf",
+            Self::Sin => "sin",
+            Self::Cos => "cos",
+            Self::Tan => "tan",
+            Self::Asin => "asin",
+            Self::Acos => "acos",
+            Self::Atan => "atan",
+            Self::Sinh => "sinh",
+            Self::Cosh => "cosh",
+            Self::Tanh => "tanh",
+            Self::Sqrt => "sqrt",
+            Self::Cbrt => "cbrt",
+            Self::Hypot => "hypot",
+            Self::Exp => "exp",
+            Self::Exp2 => "exp2",
+            Self::Log => "log",
+            Self::Log10 => "log10",
+            Self::Log2 => "log2",
+            Self::Ln => "ln",
+            Self::Floor => "floor",
+            Self::Ceil => "ceil",
+            Self::Round => "round",
+            Self::Fract => "fract",
+            Self::Trunc => "trunc",
+            Self::Abs => "abs",
+            Self::Signum => "signum",
+            Self::DivEuclid => "div_euclid",
+            Self::RemEuclid => "rem_euclid",
+            Self::Sum => "SUM",
+            Self::Average => "AVERAGE",
+            Self::Count => "COUNT",
+            Self::Re => "re",
+            Self::Im => "im",
+            Self::Conj => "conj",
+            Self::Arg => "arg",
+            Self::ImaginaryUnit => "i",
+            Self::Custom(s) => s,
+        }
+    }
+
+    /// Arity of a built-in function.
+    pub(crate) fn arity(&self) -> Arity {
         match self {
-            Self::Powi | Self::Powf | Self::Log => 2,
-            Self::DivEuclid | Self::RemEuclid => 2,
-            Self::Hypot => 2,
-            Self::Min | Self::Max => 2,
+            Self::Powi | Self::Powf | Self::Log => Arity::exact(2),
+            Self::DivEuclid | Self::RemEuclid => Arity::exact(2),
+            Self::Hypot => Arity::at_least(1),
+            Self::Min | Self::Max => Arity::at_least(1),
+            Self::Sum | Self::Average | Self::Count => Arity::at_least(1),
             Self::Sin | Self::Cos | Self::Tan | Self::Asin | Self::Acos | Self::Atan
-            | Self::Sinh | Self::Cosh | Self::Tanh => 1,
-            Self::Sqrt | Self::Cbrt | Self::Ln | Self::Exp | Self::Exp2 | Self::Log10 | Self::Log2 => 1,
-            Self::Floor | Self::Ceil | Self::Round | Self::Fract | Self::Trunc => 1,
-            Self::Abs | Self::Signum => 1,
+            | Self::Sinh | Self::Cosh | Self::Tanh => Arity::exact(1),
+            Self::Sqrt | Self::Cbrt | Self::Ln | Self::Exp | Self::Exp2 | Self::Log10 | Self::Log2 => Arity::exact(1),
+            Self::Floor | Self::Ceil | Self::Round | Self::Fract | Self::Trunc => Arity::exact(1),
+            Self::Abs | Self::Signum => Arity::exact(1),
+            Self::Re | Self::Im | Self::Conj | Self::Arg => Arity::exact(1),
+            Self::ImaginaryUnit => Arity::exact(0),
             _ => {unreachable!()},
         }
     }
+
+    /// Spreadsheet functions, whose arguments may be cell ranges.
+    pub(crate) fn aggregate(name: &str) -> Option<Self> {
+        match name {
+            "SUM" => Some(Self::Sum),
+            "AVERAGE" => Some(Self::Average),
+            "COUNT" => Some(Self::Count),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn is_aggregate(&self) -> bool {
+        matches!(self, Self::Sum | Self::Average | Self::Count)
+    }
+
+    /// Functions of complex numbers, which real number types do not support.
+    pub(crate) fn is_complex(&self) -> bool {
+        matches!(self, Self::Re | Self::Im | Self::Conj | Self::Arg | Self::ImaginaryUnit)
+    }
+
+    /// Parts of complex numbers, which are only parsed for complex number types.
+    pub(crate) fn complex(name: &str) -> Option<Self> {
+        match name {
+            "re" => Some(Self::Re),
+            "im" => Some(Self::Im),
+            "conj" => Some(Self::Conj),
+            "arg" => Some(Self::Arg),
+            _ => None,
+        }
+    }
+
     pub(crate) fn eval1(&self, arg: Value) -> Value {
         match self {
             Self::Sin => arg.sin(),
@@ -131,6 +392,9 @@ impl Function {
             Self::Trunc => arg.trunc(),
             Self::Abs => arg.abs(),
             Self::Signum => arg.signum(),
+            Self::Min | Self::Max | Self::Sum | Self::Average => arg,
+            Self::Hypot => arg.abs(),
+            Self::Count => 1.0,
             _ => {unreachable!()}
         }
     }
@@ -144,12 +408,21 @@ impl Function {
             Self::DivEuclid => args.0.div_euclid(args.1),
             Self::RemEuclid => args.0.rem_euclid(args.1),
             Self::Hypot => args.0.hypot(args.1),
+            Self::Sum => args.0 + args.1,
+            Self::Average => (args.0 + args.1) / 2.0,
+            Self::Count => 2.0,
             _ => {unreachable!()}
         }
     }
 
-    pub(crate) fn evaln(&self, _args: &[Value]) -> Value {
+    pub(crate) fn evaln(&self, args: &[Value]) -> Value {
         match self {
+            Self::Min => args.iter().copied().fold(Value::INFINITY, Value::min),
+            Self::Max => args.iter().copied().fold(Value::NEG_INFINITY, Value::max),
+            Self::Hypot => args.iter().copied().fold(0.0, Value::hypot),
+            Self::Sum => args.iter().sum(),
+            Self::Average => args.iter().sum::<Value>() / args.len() as Value,
+            Self::Count => args.len() as Value,
             _ => {unreachable!()}
         }
     }
@@ -160,11 +433,18 @@ pub enum Token {
     Unary(UnaryOp),
     Binary(BinaryOp),
     Function(Function),
+    If,
     Value(Value),
+    /// Number literal with more digits than its `f64` value holds.
+    Literal(Value, String),
     Var(String),
+    Range(CellRef, CellRef),
     LeftParen,
     RightParen,
     Comma,
+    Question,
+    Colon,
+    Assign,
 }
 
 impl Token {
@@ -172,8 +452,16 @@ impl Token {
         match self {
             Self::Binary(BinaryOp::Add) | Self::Binary(BinaryOp::Sub) => (50, 51),
             Self::Binary(BinaryOp::Mul) | Self::Binary(BinaryOp::Div) | Self::Binary(BinaryOp::Rem) => (55, 56),
-            Self::Unary(UnaryOp::Neg) => (99, 65),
-            Self::Function(_) => (97, 10),
+            Self::Binary(BinaryOp::Or) => (20, 21),
+            Self::Binary(BinaryOp::And) => (25, 26),
+            Self::Binary(BinaryOp::Eq) | Self::Binary(BinaryOp::Ne) => (30, 31),
+            Self::Binary(BinaryOp::Lt) | Self::Binary(BinaryOp::Le)
+            | Self::Binary(BinaryOp::Gt) | Self::Binary(BinaryOp::Ge) => (35, 36),
+            Self::Binary(BinaryOp::Pow) => (71, 70),
+            Self::Unary(UnaryOp::Neg) | Self::Unary(UnaryOp::Not) => (99, 65),
+            Self::Function(_) | Self::If => (97, 10),
+            Self::Question => (15, 4),
+            Self::Colon => (14, 14),
             Self::LeftParen => (99, 2),
             Self::RightParen => (3, 100),
             Self::Comma => (5, 5),
@@ -190,6 +478,20 @@ pub enum PreToken {
     Asterisk,
     Slash,
     Percent,
+    Caret,
+    DoubleAsterisk,
+    EqEq,
+    NotEq,
+    Less,
+    LessEq,
+    Greater,
+    GreaterEq,
+    AndAnd,
+    OrOr,
+    Bang,
+    Question,
+    Colon,
+    Assign,
     LeftParen,
     RightParen,
     SemiColon,
@@ -197,6 +499,8 @@ pub enum PreToken {
     Literal(String)
 }
 
+pub(crate) const MULTI_CHAR_OPERATORS: [&str; 7] = ["**", "==", "!=", "<=", ">=", "&&", "||"];
+
 impl FromStr for PreToken {
     type Err = EvalError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -206,14 +510,28 @@ impl FromStr for PreToken {
             "*" => Ok(PreToken::Asterisk),
             "/" => Ok(PreToken::Slash),
             "%" => Ok(PreToken::Percent),
+            "^" => Ok(PreToken::Caret),
+            "**" => Ok(PreToken::DoubleAsterisk),
+            "==" => Ok(PreToken::EqEq),
+            "!=" => Ok(PreToken::NotEq),
+            "<" => Ok(PreToken::Less),
+            "<=" => Ok(PreToken::LessEq),
+            ">" => Ok(PreToken::Greater),
+            ">=" => Ok(PreToken::GreaterEq),
+            "&&" => Ok(PreToken::AndAnd),
+            "||" => Ok(PreToken::OrOr),
+            "!" => Ok(PreToken::Bang),
+            "?" => Ok(PreToken::Question),
+            ":" => Ok(PreToken::Colon),
+            "=" => Ok(PreToken::Assign),
             "(" => Ok(PreToken::LeftParen),
             ")" => Ok(PreToken::RightParen),
             ";" => Ok(PreToken::SemiColon),
             "," => Ok(PreToken::Comma),
-            c if c.chars().all(is_literalchar) => {
+            c if c.chars().all(is_literalchar) || c.parse::<Value>().is_ok() => {
                 Ok(PreToken::Literal(c.to_owned()))
             },
-            _ => {Err(EvalError::InvalidString(s.to_owned()))}
+            _ => {Err(EvalError::InvalidString(s.to_owned(), Span::new(0, s.len())))}
         }
     }
 }
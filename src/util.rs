@@ -5,4 +5,10 @@ pub(crate) fn is_literalchar(c: char) -> bool {
 pub(crate) fn is_identstr(s: &str) -> bool {
     s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
     && s.chars().find(|c| c.is_ascii_alphanumeric()).filter(|c| c.is_ascii_alphabetic()).is_some()
-}
\ No newline at end of file
+}
+
+pub(crate) fn is_exponent_prefix(s: &str) -> bool {
+    s.strip_suffix(['e', 'E'])
+    .filter(|m| m.chars().all(|c| c.is_ascii_digit() || c == '.'))
+    .is_some_and(|m| m.chars().any(|c| c.is_ascii_digit()))
+}
@@ -1,3 +1,5 @@
+#![allow(clippy::approx_constant, clippy::needless_borrow)]
+
 use lieval::*;
 
 #[test]
@@ -260,4 +262,1271 @@ fn expr_opeation_test() {
     let expr1 = Expr::new("1+x").unwrap();
     let expr2 = Expr::new("2*x, 3*x, 4*x").unwrap();
     assert_eq!((expr2 + ex!("x") * expr1).set_var("x", 2.0).evals(), Ok(vec![10.0, 12.0, 14.0]));
-}
\ No newline at end of file
+}
+
+#[test]
+fn ast_test() {
+    let expr = Expr::new("1 + x * sin(y)").unwrap();
+    assert_eq!(
+        expr.nodes(),
+        &[Node::binary(
+            BinaryOp::Add,
+            Node::Value(1.0),
+            Node::binary(
+                BinaryOp::Mul,
+                Node::Var("x".to_owned()),
+                Node::call(Function::Sin, vec![Node::Var("y".to_owned())])
+            )
+        )]
+    );
+    assert_eq!(
+        Expr::new("-func(1, x)").unwrap().nodes(),
+        &[Node::unary(
+            UnaryOp::Neg,
+            Node::call(Function::Custom("func".to_owned()), vec![Node::Value(1.0), Node::Var("x".to_owned())])
+        )]
+    );
+    assert_eq!(Expr::new("func()").unwrap().nodes(), &[Node::call(Function::Custom("func".to_owned()), vec![])]);
+    assert!(Expr::new("1 +").is_err());
+    assert!(Expr::new("(1, 2)").is_err());
+
+    struct CountCalls(usize);
+    impl Visitor for CountCalls {
+        fn visit_node(&mut self, node: &Node) {
+            if let Node::Call(_, _) = node {
+                self.0 += 1;
+            }
+            walk_node(self, node);
+        }
+    }
+    let mut counter = CountCalls(0);
+    ex!("sin(cos(x)) + f(1, g(2)); h(3)").accept(&mut counter);
+    assert_eq!(counter.0, 5);
+
+    struct Depth;
+    impl Fold for Depth {
+        type Output = usize;
+        fn fold_value(&mut self, _: Value) -> usize {1}
+        fn fold_var(&mut self, _: &str) -> usize {1}
+        fn fold_unary(&mut self, _: UnaryOp, arg: usize) -> usize {arg + 1}
+        fn fold_binary(&mut self, _: BinaryOp, lhs: usize, rhs: usize) -> usize {lhs.max(rhs) + 1}
+        fn fold_call(&mut self, _: &Function, args: Vec<usize>) -> usize {args.into_iter().max().unwrap_or(0) + 1}
+        fn fold_cond(&mut self, cond: usize, then: usize, otherwise: usize) -> usize {cond.max(then).max(otherwise) + 1}
+    }
+    assert_eq!(ex!("1 + 2 * 3, -sin(x)").fold(&mut Depth), vec![3, 3]);
+
+    let expr = Expr::from_nodes(vec![Node::binary(BinaryOp::Mul, Node::Value(2.0), Node::Var("x".to_owned()))]);
+    assert_eq!((expr + 1.0).set_var("x", 3.0).eval(), Ok(7.0));
+}
+
+#[test]
+fn span_test() {
+    assert_eq!(Expr::new("1 + 2 $ 3").unwrap_err(), EvalError::InvalidString("$".to_owned(), Span::new(6, 7)));
+    assert_eq!(Expr::new("1 + 2.3.4").unwrap_err(), EvalError::InvalidString("2.3.4".to_owned(), Span::new(4, 9)));
+    assert_eq!(Expr::new("(1 + 2)) * 3").unwrap_err(), EvalError::UnexpectedParenthesis(Span::new(7, 8)));
+    assert_eq!(Expr::new("sin((1 + 2) * 3").unwrap_err(), EvalError::UnexpectedParenthesis(Span::new(3, 4)));
+    assert_eq!(Expr::new("1 + 2 *").unwrap_err(), EvalError::WrongExpression(Span::new(6, 7)));
+    assert_eq!(Expr::new("1 + 2 (3 - 4)").unwrap_err(), EvalError::WrongExpression(Span::new(6, 7)));
+    assert_eq!(Expr::new("1; ; 2").unwrap_err(), EvalError::WrongExpression(Span::new(2, 3)));
+    assert_eq!(Expr::new("1 + α").unwrap_err().span(), Some(Span::new(4, 6)));
+    assert_eq!(Expr::new("1 + α").unwrap_err().span().unwrap().render("1 + α"), "1 + α\n    ^");
+
+    let source = "sqrt(x) + 2 $ 3";
+    let err = Expr::new(source).unwrap_err();
+    assert_eq!(err.span().unwrap().render(source), "sqrt(x) + 2 $ 3\n            ^");
+    assert_eq!(
+        err.diagnostic(source),
+        "string $ at 12..13 is Invalid\nsqrt(x) + 2 $ 3\n            ^"
+    );
+
+    let source = "1 + 2;\nmax(1, 2) ++ )";
+    let err = Expr::new(source).unwrap_err();
+    assert_eq!(err.span().unwrap().render(source), "max(1, 2) ++ )\n             ^");
+
+    let source = "1 + foo * 2";
+    let err = Expr::new(source).unwrap().eval().unwrap_err();
+    assert_eq!(err.span(), None);
+    assert_eq!(err.diagnostic(source), "variable foo is undefined\n1 + foo * 2\n    ^^^");
+}
+
+#[test]
+fn scientific_notation_test() {
+    assert_eq!(eval_from_str("1e-3"), Ok(vec![1e-3]));
+    assert_eq!(eval_from_str("1e3"), Ok(vec![1e3]));
+    assert_eq!(eval_from_str("6.022e+23"), Ok(vec![6.022e23]));
+    assert_eq!(eval_from_str("1.5E-9 * 2"), Ok(vec![1.5e-9 * 2.0]));
+    assert_eq!(eval_from_str(".5e-1"), Ok(vec![0.05]));
+    assert_eq!(eval_from_str("2e-3-1e-3"), Ok(vec![2e-3 - 1e-3]));
+    assert_eq!(eval_from_str("-1e+2+1"), Ok(vec![-99.0]));
+    assert_eq!(ex!("1e-3").nodes(), &[Node::Value(1e-3)]);
+
+    let mut context = Context::new();
+    context.set_value("e", 2.0).set_value("x1e", 3.0);
+    assert_eq!(eval_from_str_with_context("e-1", &context), Ok(vec![1.0]));
+    assert_eq!(eval_from_str_with_context("x1e-1", &context), Ok(vec![2.0]));
+    assert_eq!(Expr::new("1e-x").unwrap_err(), EvalError::InvalidString("1e".to_owned(), Span::new(0, 2)));
+}
+
+#[test]
+fn pow_operator_test() {
+    assert_eq!(eval_from_str("2 ^ 3"), Ok(vec![8.0]));
+    assert_eq!(eval_from_str("2 ** 3"), Ok(vec![8.0]));
+    assert_eq!(eval_from_str("2^3^2"), Ok(vec![512.0]));
+    assert_eq!(eval_from_str("2**3**2"), Ok(vec![512.0]));
+    assert_eq!(eval_from_str("(2^3)^2"), Ok(vec![64.0]));
+    assert_eq!(eval_from_str("-2^2"), Ok(vec![-4.0]));
+    assert_eq!(eval_from_str("(-2)^2"), Ok(vec![4.0]));
+    assert_eq!(eval_from_str("2^-1"), Ok(vec![0.5]));
+    assert_eq!(eval_from_str("2 * 3^2 / 3"), Ok(vec![6.0]));
+    assert_eq!(eval_from_str("2^2 * 3"), Ok(vec![12.0]));
+    assert_eq!(eval_from_str("sin(1)^2 + cos(1)**2"), Ok(vec![1.0f64.sin().powf(2.0) + 1.0f64.cos().powf(2.0)]));
+    assert_eq!(eval_from_str("1e-3^2"), Ok(vec![1e-3f64.powf(2.0)]));
+    assert_eq!(ex!("-x^2").set_var("x", 3.0).eval(), Ok(-9.0));
+    assert!(Expr::new("2 *** 3").is_err());
+
+    let mut expr = ex!("a * x^2 + b");
+    expr.set_var("a", 2.0).set_var("b", 1.0).partial_eval().unwrap();
+    assert_eq!(expr.set_var("x", 3.0).eval(), Ok(19.0));
+
+    assert_eq!((ex!("x") ^ ex!("y")).set_var("x", 2.0).set_var("y", 3.0).eval(), Ok(8.0));
+    assert_eq!((ex!("x") ^ 2.0).set_var("x", 3.0).eval(), Ok(9.0));
+    assert_eq!((2.0 ^ ex!("x, x + 1")).set_var("x", 3.0).evals(), Ok(vec![8.0, 16.0]));
+    let mut expr = ex!("x + 1");
+    expr ^= 2.0;
+    assert_eq!(expr.set_var("x", 1.0).eval(), Ok(4.0));
+}
+
+#[test]
+fn logic_operator_test() {
+    assert_eq!(eval_from_str("1 == 1, 1 == 2, 1 != 2, 1 != 1"), Ok(vec![1.0, 0.0, 1.0, 0.0]));
+    assert_eq!(eval_from_str("1 < 2, 2 < 1, 2 <= 2, 3 <= 2"), Ok(vec![1.0, 0.0, 1.0, 0.0]));
+    assert_eq!(eval_from_str("1 > 2, 2 > 1, 2 >= 2, 2 >= 3"), Ok(vec![0.0, 1.0, 1.0, 0.0]));
+    assert_eq!(eval_from_str("1 && 2, 1 && 0, 0 || 3, 0 || 0"), Ok(vec![1.0, 0.0, 1.0, 0.0]));
+    assert_eq!(eval_from_str("!0, !1, !!5, !-1"), Ok(vec![1.0, 0.0, 1.0, 0.0]));
+    assert_eq!(eval_from_str("1 + 1 == 2 * 1"), Ok(vec![1.0]));
+    assert_eq!(eval_from_str("1 < 2 == 2 < 3"), Ok(vec![1.0]));
+    assert_eq!(eval_from_str("0 && 1 || 1"), Ok(vec![1.0]));
+    assert_eq!(eval_from_str("1 || 1 && 0"), Ok(vec![1.0]));
+    assert_eq!(eval_from_str("!(1 < 2) || 2 < 1"), Ok(vec![0.0]));
+    assert_eq!(eval_from_str("(1 < 2) * 5 + (2 <= 1) * 3"), Ok(vec![5.0]));
+    assert!(Expr::new("1 & 2").is_err());
+    assert!(Expr::new("1 = 2").is_err());
+
+    let mut context = Context::new();
+    context.set_value("age", 20.0).set_value("income", 30000.0).set_value("limit", 50000.0);
+    assert_eq!(context.eval("age >= 18 && income < limit"), Ok(1.0));
+    context.set_value("age", 17.0);
+    assert_eq!(context.eval("age >= 18 && income < limit"), Ok(0.0));
+    assert_eq!(context.eval("!(age >= 18) || income >= limit"), Ok(1.0));
+
+    let mut expr = ex!("x > a && x < b");
+    expr.set_var("a", 1.0).set_var("b", 3.0).partial_eval().unwrap();
+    assert_eq!(expr.set_var("x", 2.0).eval(), Ok(1.0));
+    assert_eq!(expr.set_var("x", 4.0).eval(), Ok(0.0));
+}
+
+#[test]
+fn conditional_test() {
+    assert_eq!(eval_from_str("if(1, 2, 3), if(0, 2, 3)"), Ok(vec![2.0, 3.0]));
+    assert_eq!(eval_from_str("1 ? 2 : 3, 0 ? 2 : 3"), Ok(vec![2.0, 3.0]));
+    assert_eq!(eval_from_str("1 < 2 ? 10 + 1 : 20"), Ok(vec![11.0]));
+    assert_eq!(eval_from_str("0 ? 1 : 0 ? 2 : 3"), Ok(vec![3.0]));
+    assert_eq!(eval_from_str("1 ? 0 ? 1 : 2 : 3"), Ok(vec![2.0]));
+    assert_eq!(eval_from_str("(1 ? 2 : 3) * 4"), Ok(vec![8.0]));
+    assert_eq!(eval_from_str("max(0 ? 1 : 5, 2)"), Ok(vec![5.0]));
+    assert_eq!(eval_from_str("1 + if(2 > 1, -1, 1) * 3"), Ok(vec![-2.0]));
+    assert_eq!(eval_from_str("0 || 1 ? 2 : 3"), Ok(vec![2.0]));
+
+    // the untaken branch is never evaluated
+    assert_eq!(eval_from_str("if(1, 2, y)"), Ok(vec![2.0]));
+    assert_eq!(eval_from_str("0 ? y : 3"), Ok(vec![3.0]));
+    assert_eq!(eval_from_str("0 && y, 1 || y"), Ok(vec![0.0, 1.0]));
+    assert_eq!(eval_from_str("if(0, 2, y)"), Err(EvalError::UndefinedVariable("y".to_owned())));
+
+    let mut expr = ex!("if(x > 0, ln(x), 0)");
+    assert_eq!(expr.set_var("x", std::f64::consts::E).eval(), Ok(1.0));
+    assert_eq!(expr.set_var("x", -1.0).eval(), Ok(0.0));
+
+    let mut expr = ex!("a > 0 ? x : -x");
+    expr.set_var("a", 1.0).partial_eval().unwrap();
+    assert_eq!(expr.nodes(), &[Node::Var("x".to_owned())]);
+
+    assert!(Expr::new("1 ? 2").is_err());
+    assert!(Expr::new("1 : 2").is_err());
+    assert!(Expr::new("(1 ? 2) : 3").is_err());
+    assert_eq!(
+        Expr::new("if(1, 2)").unwrap_err(),
+        EvalError::WrongArguments {name: "if".to_owned(), expected: Arity::exact(3), found: 2, span: Some(Span::new(0, 8))}
+    );
+}
+
+#[test]
+fn closure_func_test() {
+    let table: Vec<f64> = (1..=4).map(|i| (i * i) as f64).collect();
+    let mut context = Context::new();
+    context.set_func("table", 1, move |x| table[x[0] as usize]);
+    assert_eq!(context.eval("table(2) + table(3)"), Ok(25.0));
+
+    let scale = 2.5;
+    let mut expr = ex!("calib(x) + 1");
+    expr.set_func("calib", 1, move |x| x[0] * scale).set_var("x", 2.0);
+    assert_eq!(expr.eval(), Ok(6.0));
+
+    // fn pointers are still accepted
+    fn add(x: &[f64]) -> f64 {x[0] + x[1]}
+    context.set_func("add", 2, add);
+    assert_eq!(context.eval("add(1, 2)"), Ok(3.0));
+
+    let counter = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let shared: SharedFunc = {
+        let counter = counter.clone();
+        std::sync::Arc::new(move |x: &[f64]| {
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            x[0]
+        })
+    };
+    context.set_shared_func("count", 1, shared);
+    let cloned = context.clone();
+    assert_eq!(cloned.clone().eval("count(1) + count(2)"), Ok(3.0));
+    assert_eq!(context.eval("1 ? count(3) : count(4)"), Ok(3.0));
+    assert_eq!(counter.load(std::sync::atomic::Ordering::SeqCst), 3);
+}
+
+#[test]
+fn variadic_func_test() {
+    assert_eq!(eval_from_str("max(1, 5, 3, 2)"), Ok(vec![5.0]));
+    assert_eq!(eval_from_str("min(4, 2, -3, 2, 7)"), Ok(vec![-3.0]));
+    assert_eq!(eval_from_str("max(7), min(-2)"), Ok(vec![7.0, -2.0]));
+    assert_eq!(eval_from_str("hypot(2, 3, 6)"), Ok(vec![7.0]));
+    assert_eq!(eval_from_str("hypot(-2)"), Ok(vec![2.0]));
+    assert_eq!(eval_from_str("max(1, min(2, 3, 0.5), 0)"), Ok(vec![1.0]));
+    assert_eq!(ex!("max(x, y, z)").set_var("x", 1.0).set_var("y", 3.0).set_var("z", 2.0).eval(), Ok(3.0));
+    assert_eq!(
+        eval_from_str("max()"),
+        Err(EvalError::WrongArguments {name: "max".to_owned(), expected: Arity::at_least(1), found: 0, span: Some(Span::new(0, 5))})
+    );
+
+    let mut context = Context::new();
+    context.set_func("sum", 0.., |x| x.iter().sum());
+    context.set_func("avg", 1.., |x| x.iter().sum::<f64>() / x.len() as f64);
+    context.set_func("lerp", 2..=3, |x| x[0] + (x[1] - x[0]) * x.get(2).copied().unwrap_or(0.5));
+    context.set_func("two", Arity::exact(2), |x| x[0] * x[1]);
+    assert_eq!(context.eval("sum()"), Ok(0.0));
+    assert_eq!(context.eval("sum(1, 2, 3, 4)"), Ok(10.0));
+    assert_eq!(context.eval("avg(1, 2, 3, 6)"), Ok(3.0));
+    assert_eq!(context.eval("lerp(0, 10)"), Ok(5.0));
+    assert_eq!(context.eval("lerp(0, 10, 0.2)"), Ok(2.0));
+    assert_eq!(context.eval("two(2, 3)"), Ok(6.0));
+    assert_eq!(
+        context.eval("avg()"),
+        Err(EvalError::WrongArguments {name: "avg".to_owned(), expected: Arity::at_least(1), found: 0, span: None})
+    );
+    assert_eq!(
+        context.eval("lerp(0, 1, 2, 3)"),
+        Err(EvalError::WrongArguments {name: "lerp".to_owned(), expected: Arity::range(2, 3), found: 4, span: None})
+    );
+    assert_eq!(context.get_func("lerp").map(FuncClosure::arity), Some(Arity::range(2, 3)));
+
+    let mut expr = ex!("sum(a, b, x)");
+    expr.apply_context(&context).unwrap().set_var("a", 1.0).set_var("b", 2.0).partial_eval().unwrap();
+    assert_eq!(expr.set_var("x", 3.0).eval(), Ok(6.0));
+
+    assert!(Arity::at_least(2).accepts(5));
+    assert!(!Arity::range(1, 2).accepts(3));
+    assert_eq!(Arity::range(1, 2).to_string(), "1..=2");
+    assert_eq!(Arity::at_least(1).to_string(), "1..");
+    assert_eq!(Arity::from(3).to_string(), "3");
+}
+
+#[test]
+fn arity_check_test() {
+    let wrong_args = |name: &str, expected: Arity, found: usize, span: Option<Span>| {
+        EvalError::WrongArguments {name: name.to_owned(), expected, found, span}
+    };
+    assert_eq!(Expr::new("sin(1, 2)").unwrap_err(), wrong_args("sin", Arity::exact(1), 2, Some(Span::new(0, 9))));
+    assert_eq!(Expr::new("1 + pow(2)").unwrap_err(), wrong_args("powf", Arity::exact(2), 1, Some(Span::new(4, 10))));
+    assert_eq!(Expr::new("sqrt()").unwrap_err(), wrong_args("sqrt", Arity::exact(1), 0, Some(Span::new(0, 6))));
+    assert_eq!(
+        Expr::new("1 + sin(2, 3)").unwrap_err().to_string(),
+        "function sin expects 1 arguments, but 2 were given"
+    );
+    assert_eq!(
+        Expr::new("1 + sin(2, 3)").unwrap_err().diagnostic("1 + sin(2, 3)"),
+        "function sin expects 1 arguments, but 2 were given\n1 + sin(2, 3)\n    ^^^^^^^^^"
+    );
+
+    // custom functions are checked once a context is applied
+    let mut context = Context::new();
+    context.set_func("f", 2, |x| x[0] + x[1]);
+    let mut expr = Expr::new("1 + f(1, 2, 3)").unwrap();
+    assert_eq!(expr.apply_context(&context).unwrap_err(), wrong_args("f", Arity::exact(2), 3, None));
+    assert_eq!(eval_from_str_with_context("f(1)", &context), Err(wrong_args("f", Arity::exact(2), 1, None)));
+    assert_eq!(context.eval("0 ? f(1) : 2"), Err(wrong_args("f", Arity::exact(2), 1, None)));
+    assert_eq!(
+        wrong_args("f", Arity::exact(2), 1, None).diagnostic("1 + f(1)"),
+        "function f expects 2 arguments, but 1 were given\n1 + f(1)\n    ^"
+    );
+
+    let mut expr = Expr::new("g(1, 2, 3)").unwrap();
+    assert_eq!(expr.set_func("g", 2, |x| x[0]).eval(), Err(wrong_args("g", Arity::exact(2), 3, None)));
+}
+
+#[test]
+fn compiled_expr_test() {
+    let mut compiled = ex!("a1 * a2 + sin(x + 2)").set_var("a1", 2.0).set_var("a2", 3.0).compile(&["x"]);
+    assert_eq!(compiled.slots(), &["x".to_owned()]);
+    for x in [0.0, 1.0, -2.5] {
+        assert_eq!(compiled.eval(&[x]), Ok(6.0 + (x + 2.0).sin()));
+    }
+
+    let mut compiled = ex!("x^2 + y * z; hypot(x, y, z), max(x, y) - min(z)").compile(&["x", "y", "z"]);
+    assert_eq!(compiled.len(), 3);
+    assert_eq!(compiled.evals(&[1.0, 2.0, 3.0]), Ok(vec![7.0, 14f64.sqrt(), -1.0]));
+    assert_eq!(compiled.eval_index(2, &[5.0, 2.0, 3.0]), Ok(2.0));
+    assert_eq!(compiled.eval_index(3, &[5.0, 2.0, 3.0]), Err(EvalError::WrongExprIndex(3)));
+    assert_eq!(compiled.eval(&[1.0, 2.0]), Err(EvalError::WrongSlots(3)));
+
+    // slots take precedence over the values in the context
+    let mut compiled = ex!("x + y").set_var("x", 100.0).set_var("y", 1.0).compile(&["x"]);
+    assert_eq!(compiled.eval(&[2.0]), Ok(3.0));
+
+    let scale = 3.0;
+    let mut expr = ex!("f(x, 2) + g(x) + f(1, 1)");
+    expr.set_func("f", 2, move |x| (x[0] + x[1]) * scale).set_func("g", 1.., |x| x.iter().sum());
+    let mut compiled = expr.compile(&["x"]);
+    assert_eq!(compiled.eval(&[1.0]), Ok(9.0 + 1.0 + 6.0));
+
+    // conditionals and logic short-circuit in the bytecode
+    let mut compiled = ex!("if(x > 0, ln(x), y); x > 0 && y, x > 0 || y, x ? 1 : 0 ? 2 : 3").compile(&["x"]);
+    assert_eq!(compiled.eval(&[1.0]), Ok(0.0));
+    assert_eq!(compiled.eval(&[-1.0]), Err(EvalError::UndefinedVariable("y".to_owned())));
+    assert_eq!(compiled.evals(&[2.0]), Err(EvalError::UndefinedVariable("y".to_owned())));
+    assert_eq!(compiled.eval_index(1, &[-2.0]), Ok(0.0));
+    assert_eq!(compiled.eval_index(2, &[2.0]), Ok(1.0));
+    assert_eq!(compiled.eval_index(3, &[0.0]), Ok(3.0));
+    assert_eq!(compiled.eval_index(3, &[5.0]), Ok(1.0));
+
+    let mut compiled = ex!("x && 5, 0 || x").compile(&["x"]);
+    assert_eq!(compiled.evals(&[2.0]), Ok(vec![1.0, 1.0]));
+    assert_eq!(compiled.evals(&[0.0]), Ok(vec![0.0, 0.0]));
+
+    let mut compiled = ex!("1 + h(x)").compile(&["x"]);
+    assert_eq!(compiled.eval(&[1.0]), Err(EvalError::UndefinedFunction("h".to_owned())));
+
+    let expr = ex!("cos(x) + sin(y) * -x");
+    let mut compiled = expr.compile(&["y", "x"]);
+    let mut xn = 0.5;
+    for _ in 0..100 {
+        let mut expr = expr.clone();
+        let v = expr.set_var("x", xn).set_var("y", 1.0).eval();
+        assert_eq!(compiled.eval(&[1.0, xn]), v);
+        xn = v.unwrap();
+    }
+}
+
+#[test]
+fn batch_eval_test() {
+    let xs: Vec<f64> = (0..1000).map(|i| i as f64 * 0.01 - 3.0).collect();
+    let ys: Vec<f64> = (0..1000).map(|i| (i % 7) as f64).collect();
+    let expr = "if(x > 0, ln(x), -x) + y * a - max(x, y, 1) + (x < 1 && y >= 3)";
+    let mut compiled = ex!(expr).set_var("a", 0.5).compile(&["x", "y"]);
+    let mut out = vec![0.0; xs.len()];
+    compiled.eval_batch(&[Column::Slice(&xs), Column::from(&ys)], &mut out).unwrap();
+    for i in 0..xs.len() {
+        assert_eq!(Ok(out[i]), compiled.eval(&[xs[i], ys[i]]));
+    }
+
+    // scalars broadcast to every row
+    let mut out = vec![0.0; xs.len()];
+    compiled.eval_batch(&[Column::Slice(&xs), Column::Scalar(2.0)], &mut out).unwrap();
+    for i in 0..xs.len() {
+        assert_eq!(Ok(out[i]), compiled.eval(&[xs[i], 2.0]));
+    }
+
+    let mut out = [0.0; 3];
+    let mut expr = ex!("f(x, y) * 2");
+    expr.set_func("f", 2, |v| v[0] - v[1]);
+    expr.eval_batch(&[("x", Column::from(&xs[..3])), ("y", Column::from(1.0))], &mut out).unwrap();
+    assert_eq!(out, [-8.0, -7.98, -7.96]);
+
+    assert_eq!(compiled.eval_batch(&[Column::Slice(&xs)], &mut out), Err(EvalError::WrongSlots(2)));
+    assert_eq!(compiled.eval_batch(&[Column::Slice(&xs), Column::Scalar(1.0)], &mut out), Err(EvalError::WrongColumnLength(3)));
+    assert_eq!(
+        ex!("x + z").eval_batch(&[("x", Column::Slice(&xs[..3]))], &mut out),
+        Err(EvalError::UndefinedVariable("z".to_owned()))
+    );
+
+    // failures in branches that are not taken do not fail the batch
+    let xs = [1.0, -2.0, 3.0];
+    let mut out = [0.0; 3];
+    ex!("x > 0 ? sqrt(x) : z").eval_batch(&[("x", Column::from(&xs[..1]))], &mut out[..1]).unwrap();
+    assert_eq!(out[0], 1.0);
+    ex!("x < 0 || z").eval_batch(&[("x", Column::from(&xs[1..2]))], &mut out[1..2]).unwrap();
+    assert_eq!(out[1], 1.0);
+    assert_eq!(
+        ex!("x > 0 ? x : z").eval_batch(&[("x", Column::from(&xs[..]))], &mut out),
+        Err(EvalError::UndefinedVariable("z".to_owned()))
+    );
+    let mut compiled = ex!("if(x > 0, 2 * x, f(x))").compile(&["x"]);
+    compiled.eval_batch(&[Column::from(&[1.0, 2.0, 3.0][..])], &mut out).unwrap();
+    assert_eq!(out, [2.0, 4.0, 6.0]);
+    assert_eq!(compiled.eval_batch(&[Column::from(&xs[..])], &mut out), Err(EvalError::UndefinedFunction("f".to_owned())));
+
+    // custom functions are only called for the rows taking their branch
+    let table: Vec<f64> = (1..=4).map(|i| (i * i) as f64).collect();
+    let mut expr = ex!("if(x < 4, table(x), 0) + (x < 4 && table(x) > 1)");
+    expr.set_func("table", 1, move |x| table[x[0] as usize]);
+    let mut out = [0.0; 6];
+    expr.eval_batch(&[("x", Column::from(&[0.0, 1.0, 3.0, 4.0, 10.0, 2.0][..]))], &mut out).unwrap();
+    assert_eq!(out, [1.0, 5.0, 17.0, 0.0, 0.0, 10.0]);
+}
+
+#[test]
+fn send_sync_test() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Expr>();
+    assert_send_sync::<Context>();
+    assert_send_sync::<CompiledExpr>();
+    assert_send_sync::<EvalError>();
+
+    // custom functions can be shared with other threads
+    let mut context = Context::new();
+    let offset = std::sync::Arc::new(1.5);
+    context.set_func("f", 1, move |x| x[0] + *offset);
+    let mut expr = ex!("f(x) * 2");
+    expr.apply_context(&context).unwrap();
+    let handles: Vec<_> = (0..4)
+        .map(|i| {
+            let mut expr = expr.clone();
+            std::thread::spawn(move || expr.set_var("x", i as f64).eval())
+        })
+        .collect();
+    let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+    assert_eq!(results, vec![Ok(3.0), Ok(5.0), Ok(7.0), Ok(9.0)]);
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn par_batch_eval_test() {
+    let xs: Vec<f64> = (0..100_000).map(|i| i as f64 * 1e-3).collect();
+    let mut context = Context::new();
+    context.set_func("f", 2, |x| x[0] * x[1]);
+    let mut expr = ex!("f(x, y) + sqrt(x) - (x > 50 ? 1 : 0)");
+    expr.apply_context(&context).unwrap();
+
+    let mut out = vec![0.0; xs.len()];
+    let mut par_out = vec![0.0; xs.len()];
+    let bindings = [("x", Column::Slice(&xs)), ("y", Column::Scalar(3.0))];
+    expr.eval_batch(&bindings, &mut out).unwrap();
+    expr.par_eval_batch(&bindings, &mut par_out).unwrap();
+    assert_eq!(out, par_out);
+    assert_eq!(par_out[2000], 6.0 + 2f64.sqrt());
+
+    let mut compiled = ex!("x + z").compile(&["x"]);
+    assert_eq!(compiled.par_eval_batch(&[Column::Slice(&xs)], &mut par_out), Err(EvalError::UndefinedVariable("z".to_owned())));
+    assert_eq!(compiled.par_eval_batch(&[Column::Slice(&xs[1..])], &mut par_out), Err(EvalError::WrongColumnLength(xs.len())));
+    assert_eq!(compiled.eval_batch(&[Column::Slice(&xs[1..])], &mut par_out), Err(EvalError::WrongColumnLength(xs.len())));
+}
+
+#[test]
+fn derivative_test() {
+    fn check(src: &str, x: f64) {
+        let expr = ex!(src);
+        let derivative = expr.derivative("x").unwrap();
+        let h = 1e-6;
+        let numeric = (expr.clone().set_var("x", x + h).eval().unwrap() - expr.clone().set_var("x", x - h).eval().unwrap()) / (2.0 * h);
+        let symbolic = derivative.clone().set_var("x", x).eval().unwrap();
+        assert!((numeric - symbolic).abs() < 1e-5 * (1.0 + numeric.abs()), "{}: {} != {}", src, symbolic, numeric);
+    }
+    let exprs = [
+        "3 * x^2 - 2 * x + 1", "x / (1 + x)", "x % 0.25", "-x ** 3", "2 ^ x", "x ^ x",
+        "sin(x) * cos(x) + tan(x)", "asin(x / 2) + acos(x / 3) + atan(x)", "sinh(x) + cosh(x) * tanh(x)",
+        "sqrt(x) + cbrt(x) + exp(x) + exp2(x)", "ln(x) + log10(x) + log2(x) + log(x, 3) + log(2, x)",
+        "powf(x, 3) + powf(x, x) + powi(x, 3) + abs(x - 1) + fract(x)", "hypot(x, 2 * x, 3)", "hypot(x)",
+        "powi(x, 2.5) + powi(x, -1.5)", "min(x, 1) + max(x^2, 2 * x, 0.5)", "rem_euclid(x, 0.7)", "x > 1 ? x^2 : -x", "(x < 1) * 5 + x",
+    ];
+    for src in exprs {
+        for x in [0.6, 1.3, 1.9] {
+            check(src, x);
+        }
+    }
+
+    assert_eq!(ex!("2 * x + y").derivative("x").unwrap().nodes(), &[Node::Value(2.0)]);
+    assert_eq!(ex!("x * y; y").derivative("y").unwrap().nodes(), &[Node::Var("x".to_owned()), Node::Value(1.0)]);
+
+    assert_eq!(ex!("floor(x)").derivative("x").unwrap_err(), EvalError::NotDifferentiable("floor".to_owned()));
+    // the exponent of powi is truncated
+    assert_eq!(ex!("powi(x, 2.5)").derivative("x").unwrap().set_var("x", 1.0).eval(), Ok(2.0));
+    assert_eq!(ex!("powi(x, x)").derivative("x").unwrap_err(), EvalError::NotDifferentiable("powi".to_owned()));
+    assert_eq!(ex!("f(x)").derivative("x").unwrap_err(), EvalError::NotDifferentiable("f".to_owned()));
+    for args in [vec![], vec![Node::Var("x".to_owned())]] {
+        let found = args.len();
+        let err = Expr::from_nodes(vec![Node::call(Function::Powf, args)]).derivative("x").unwrap_err();
+        assert_eq!(err, EvalError::WrongArguments {name: "powf".to_owned(), expected: Arity::exact(2), found, span: None});
+    }
+
+    // the derivative of a custom function is given by naming its partial derivatives
+    let mut context = Context::new();
+    context.set_func("f", 2, |x| x[0] * x[0] * x[1])
+        .set_func("df_da", 2, |x| 2.0 * x[0] * x[1])
+        .set_func("df_db", 2, |x| x[0] * x[0])
+        .set_derivative("f", &["df_da", "df_db"])
+        .set_value("y", 3.0);
+    let mut expr = ex!("f(x, sin(x)) + y * x");
+    expr.apply_context(&context).unwrap();
+    let mut derivative = expr.derivative("x").unwrap();
+    let x = 0.7f64;
+    assert!((derivative.set_var("x", x).eval().unwrap() - (2.0 * x * x.sin() + x * x * x.cos() + 3.0)).abs() < 1e-12);
+
+    // the number of partial derivatives must match the number of arguments
+    let mut expr = ex!("g(x, 1)");
+    expr.set_func("g", 1.., |x| x.iter().sum()).set_func("dg", 1.., |_| 1.0).set_derivative("g", &["dg"]);
+    assert_eq!(expr.derivative("x").unwrap_err(), EvalError::NotDifferentiable("g".to_owned()));
+}
+
+#[test]
+fn gradient_test() {
+    let exprs = [
+        "3 * x^2 * y - 2 * x / y + 1", "x % 0.25 + y % x", "-x ** y", "x ^ x + 2 ^ y",
+        "sin(x * y) * cos(x) + tan(y)", "asin(x / 2) + acos(y / 3) + atan(x * y)", "sinh(x) + cosh(y) * tanh(x)",
+        "sqrt(x * y) + cbrt(x) + exp(y) + exp2(x)", "ln(x) + log10(y) + log2(x) + log(x, y) + log(2, x)",
+        "powf(x, y) + powi(x, 3) + abs(x - y) + fract(x * y)", "hypot(x, 2 * y, 3)", "hypot(x * y)",
+        "min(x, y) + max(x^2, 2 * y, 0.5)", "rem_euclid(x, y)", "x > y ? x^2 : -y", "(x < 1) * 5 + x && y",
+    ];
+    for src in exprs {
+        for (x, y) in [(0.6, 1.7), (1.3, 0.8), (1.9, 1.2)] {
+            let mut expr = ex!(src);
+            expr.set_var("x", x).set_var("y", y);
+            let (value, grad) = expr.eval_with_gradient(&["x", "y"]).unwrap();
+            assert_eq!(value, expr.eval().unwrap());
+            for (i, var) in ["x", "y"].iter().enumerate() {
+                let mut derivative = expr.derivative(var).unwrap();
+                let symbolic = derivative.set_var("x", x).set_var("y", y).eval().unwrap();
+                assert!((grad[i] - symbolic).abs() < 1e-9 * (1.0 + symbolic.abs()), "{} d/d{}: {} != {}", src, var, grad[i], symbolic);
+            }
+        }
+    }
+
+    let mut expr = ex!("x * z + 2");
+    expr.set_var("x", 3.0).set_var("z", 5.0);
+    assert_eq!(expr.eval_with_gradient(&["x", "y"]), Err(EvalError::UndefinedVariable("y".to_owned())));
+    assert_eq!(expr.eval_with_gradient(&["z", "x"]), Ok((17.0, vec![3.0, 5.0])));
+    assert_eq!(expr.eval_with_gradient(&[]), Ok((17.0, vec![])));
+
+    // custom functions supply their partial derivatives through the context
+    let mut context = Context::new();
+    context.set_func("f", 2, |x| x[0] * x[0] * x[1])
+        .set_func("df_da", 2, |x| 2.0 * x[0] * x[1])
+        .set_func("df_db", 2, |x| x[0] * x[0])
+        .set_derivative("f", &["df_da", "df_db"])
+        .set_func("g", 1, |x| x[0] + 1.0);
+    let mut expr = ex!("f(x, y) + g(2)");
+    expr.apply_context(&context).unwrap();
+    expr.set_var("x", 3.0).set_var("y", 2.0);
+    assert_eq!(expr.eval_with_gradient(&["x", "y"]), Ok((21.0, vec![12.0, 9.0])));
+    let mut expr = ex!("f(x, y) + g(x)");
+    expr.apply_context(&context).unwrap();
+    expr.set_var("x", 3.0).set_var("y", 2.0);
+    assert_eq!(expr.eval_with_gradient(&["y"]), Ok((22.0, vec![9.0])));
+    assert_eq!(expr.eval_with_gradient(&["x"]), Err(EvalError::NotDifferentiable("g".to_owned())));
+    assert_eq!(expr.eval_with_gradient(&["z"]), Err(EvalError::UndefinedVariable("z".to_owned())));
+
+    // forward and symbolic differentiation agree on zero exponents, step functions
+    // and custom functions of arguments constant in `x`
+    for src in ["x^0 + powi(x, 0)", "floor(y) + x", "signum(y) * div_euclid(y, 2) * x", "f(0 * x) + x"] {
+        let mut expr = ex!(src);
+        expr.set_func("f", 1, |x| x[0] + 1.0).set_var("x", 0.0).set_var("y", 1.5);
+        let (_, grad) = expr.eval_with_gradient(&["x"]).unwrap();
+        assert_eq!(Ok(grad[0]), expr.derivative("x").unwrap().set_var("x", 0.0).set_var("y", 1.5).eval(), "{}", src);
+    }
+    // an infinite or NaN derivative in `y` leaves the partial derivative in `x` alone
+    for (src, y) in [("sqrt(y) + x", 0.0), ("ln(y) * x", 0.0), ("acos(y) + x", 2.0), ("k(x, sqrt(y))", 0.0)] {
+        let mut expr = ex!(src);
+        expr.set_func("k", 2, |x| x[0] + x[1])
+            .set_func("dk", 2, |_| 1.0)
+            .set_derivative("k", &["dk", "dk"])
+            .set_var("x", 1.0).set_var("y", y);
+        let (_, grad) = expr.eval_with_gradient(&["x", "y"]).unwrap();
+        assert_eq!(Ok(grad[0]), expr.derivative("x").unwrap().set_var("x", 1.0).set_var("y", y).eval(), "{}", src);
+    }
+    let mut expr = ex!("k(x, y)");
+    expr.set_func("k", 2, |x| x[0] + 2.0 * x[1].sqrt())
+        .set_func("dk_dx", 2, |_| 1.0)
+        .set_func("dk_dy", 2, |x| 1.0 / x[1].sqrt())
+        .set_derivative("k", &["dk_dx", "dk_dy"])
+        .set_var("x", 1.0).set_var("y", 0.0);
+    assert_eq!(expr.eval_with_gradient(&["x", "y"]), Ok((1.0, vec![1.0, f64::INFINITY])));
+    for (src, name) in [("floor(x)", "floor"), ("signum(x) + 1", "signum"), ("div_euclid(x, 2)", "div_euclid"), ("powi(2, x)", "powi")] {
+        let mut expr = ex!(src);
+        expr.set_var("x", 1.5);
+        let err = Err(EvalError::NotDifferentiable(name.to_owned()));
+        assert_eq!(expr.eval_with_gradient(&["x"]).map(|_| ()), err, "{}", src);
+        assert_eq!(expr.derivative("x").map(|_| ()), err, "{}", src);
+    }
+    let mut expr = ex!("h(x)");
+    expr.set_func("h", 1, |x| x[0]).set_func("dh", 2, |_| 1.0).set_derivative("h", &["dh"]).set_var("x", 1.0);
+    assert_eq!(
+        expr.eval_with_gradient(&["x"]),
+        Err(EvalError::WrongArguments {name: "dh".to_owned(), expected: Arity::exact(2), found: 1, span: None})
+    );
+
+    // `min` and `max` skip NaN arguments like `eval`
+    for (src, expected) in [("min(1, sqrt(x), 2)", (1.0, 0.0)), ("max(0, sqrt(x))", (0.0, 0.0)), ("max(sqrt(x), 2 * x)", (-2.0, 2.0))] {
+        let mut expr = ex!(src);
+        expr.set_var("x", -1.0);
+        let (value, grad) = expr.eval_with_gradient(&["x"]).unwrap();
+        assert_eq!(value, expr.eval().unwrap());
+        assert_eq!((value, grad[0]), expected, "{}", src);
+        assert_eq!(expr.derivative("x").unwrap().set_var("x", -1.0).eval(), Ok(grad[0]), "{}", src);
+    }
+}
+
+#[test]
+fn simplify_test() {
+    let cases = [
+        ("x + 1 + 2", "x + 3"),
+        ("1 + 2 + x", "x + 3"),
+        ("x * 0", "0"),
+        ("x * 1 + 0", "x"),
+        ("x - x", "0"),
+        ("--x", "x"),
+        ("-(-x - y)", "x + y"),
+        ("2 * x + 3 * x - x", "4 * x"),
+        ("x * 2 * 3", "6 * x"),
+        ("x * y / x", "y"),
+        ("x * x * x / 2", "0.5 * x^3"),
+        ("(x^2)^3 / x^6 + y^1 + y^0", "y + 2"),
+        ("2 / x + 3 / x", "2 / x + 3 / x"),
+        ("x / y / z", "x / (y * z)"),
+        ("-x * -y", "x * y"),
+        ("sin(2 - 2) + cos(x * 0) + f(1)", "f(1) + 1"),
+        ("1 < 2 ? x + 0 : y", "x"),
+        ("z > 0 ? x * 1 : x", "x"),
+        ("(x + 1) * (x + 1)", "(x + 1)^2"),
+        ("x^0.5 * 1", "x^0.5"),
+    ];
+    for (src, expected) in cases {
+        assert_eq!(ex!(src).simplify().nodes(), ex!(expected).nodes(), "{}", src);
+    }
+
+    // simplification does not change the value
+    let exprs = [
+        "3 * x^2 * y - 2 * x / y + 1 - x * x", "(x + y) * (x - y) / (x + y)^1", "-(x - 2 * y) + x ^ 2 / x",
+        "sin(x * y) * cos(x) + sin(y * x) - 2 * sin(x * y)", "min(x, 1 + 1) + max(x^2, 2 * y, 0.5) * 0 + 1",
+        "hypot(x, 2 * y, 3) / hypot(x, 2 * y, 3) * x", "x > y ? x^2 - x * x : -y + y * 2", "(x / y)^2 * y^2",
+    ];
+    for src in exprs {
+        let mut simplified = ex!(src);
+        simplified.simplify();
+        for (x, y) in [(0.6, 1.7), (-1.3, 0.8), (1.9, -1.2)] {
+            let expected = ex!(src).set_var("x", x).set_var("y", y).eval().unwrap();
+            let value = simplified.set_var("x", x).set_var("y", y).eval().unwrap();
+            assert!((value - expected).abs() < 1e-12 * (1.0 + expected.abs()), "{}: {} != {}", src, value, expected);
+        }
+    }
+
+    // expressions built with operators and derivatives stay compact
+    let x = ex!("x");
+    let mut expr = x.clone() * 2.0 + x.clone() * 3.0 - x.clone() * 5.0 + 1.0;
+    assert_eq!(expr.simplify().nodes(), &[Node::Value(1.0)]);
+    assert_eq!(ex!("x^3 + 2 * x").derivative("x").unwrap().nodes(), ex!("3 * x^2 + 2").nodes());
+    assert_eq!(ex!("x * y * x").derivative("x").unwrap().nodes(), ex!("2 * (x * y)").nodes());
+
+    // subexpressions are assumed to be finite, so the domain may grow
+    for (src, x, before, after) in [("x / x", 0.0, None, 1.0), ("0 * ln(x) + 2", -1.0, None, 2.0), ("(0 / x) ? 1 : 2", 0.0, Some(1.0), 2.0)] {
+        let mut expr = ex!(src);
+        expr.set_var("x", x);
+        let value = expr.eval().unwrap();
+        assert_eq!(if value.is_nan() {None} else {Some(value)}, before, "{}", src);
+        assert_eq!(expr.simplify().eval(), Ok(after), "{}", src);
+    }
+
+    // calls with the wrong number of arguments are not folded
+    let call = Node::call(Function::Powf, vec![Node::Value(1.0)]);
+    let mut expr = Expr::from_nodes(vec![call.clone()]);
+    assert_eq!(expr.simplify().nodes(), &[call]);
+    assert!(matches!(expr.eval(), Err(EvalError::WrongArguments {..})));
+}
+
+#[test]
+fn display_test() {
+    let cases = [
+        ("1 + 2 * 3", "1 + 2 * 3"),
+        ("(1 + 2) * 3", "(1 + 2) * 3"),
+        ("a - (b - c) - (d + e)", "a - (b - c) - (d + e)"),
+        ("a / (b * c) * d % e", "a / (b * c) * d % e"),
+        ("2 ^ 3 ^ 2 + (2 ^ 3) ^ 2", "2^3^2 + (2^3)^2"),
+        ("-x^2 + (-x)^2 - -x", "-x^2 + (-x)^2 - -x"),
+        ("-(a + b) * !(c || d)", "-(a + b) * !(c || d)"),
+        ("a < b == (c >= d) && e != f || g", "a < b == c >= d && e != f || g"),
+        ("if(a, b, c) + (d ? e : f ? g : h)", "(a ? b : c) + (d ? e : f ? g : h)"),
+        ("(a ? b : c) ? (d ? e : f) : g", "(a ? b : c) ? (d ? e : f) : g"),
+        ("max(1, x, y) * f(x + 1); 2", "max(1, x, y) * f(x + 1); 2"),
+        ("0.000001 + 1.5e300 + 1e-3 + 10", "1e-6 + 1.5e300 + 0.001 + 10"),
+    ];
+    for (src, expected) in cases {
+        assert_eq!(ex!(src).to_string(), expected);
+    }
+    assert_eq!(format!("{}", Node::Value(f64::INFINITY)), "1e999");
+    assert_eq!(Node::binary(BinaryOp::Pow, Node::Value(-2.0), Node::Value(-0.5)).to_string(), "(-2)^-0.5");
+
+    // the text parses back to an expression with the same value
+    let exprs = [
+        "3 * x^2 * y - 2 * x / y + 1 - x * x", "-(x - 2 * -y) + x ^ -2 / x % 3", "-x^-y^2 * -(x + y)",
+        "sin(x * y) * cos(x) + log(y * x, 3) - hypot(x, 2, y)", "x > y ? x^2 : y < 0 ? -y : y * 2",
+        "!(x > 1) && y || !y && x == 2 != (x < y)", "(x > y ? x : y) * 2 + (x ? 1 : 2) ^ (y ? 3 : 4)",
+        "-(2) ^ 2 + (-2) ^ 2 - (-x) ^ 3", "1e-300 * 1e300 + 123456789.125 / 0.1",
+    ];
+    for src in exprs {
+        let expr = ex!(src);
+        for text in [expr.to_string(), expr.derivative("x").unwrap().to_string(), expr.clone().simplify().to_string()] {
+            let original = Expr::new(&text).unwrap();
+            let parsed = Expr::new(&original.to_string()).unwrap();
+            assert_eq!(parsed.to_string(), text);
+            for (x, y) in [(0.6, 1.7), (-1.3, 0.8), (2.0, 0.0)] {
+                let expected = original.clone().set_var("x", x).set_var("y", y).eval().unwrap();
+                let value = parsed.clone().set_var("x", x).set_var("y", y).eval().unwrap();
+                assert!(value == expected || value.is_nan() && expected.is_nan(), "{}: {} != {}", text, value, expected);
+            }
+        }
+        let value = expr.clone().set_var("x", 0.6).set_var("y", 1.7).eval().unwrap();
+        assert_eq!(ex!(&expr.to_string()).set_var("x", 0.6).set_var("y", 1.7).eval().unwrap(), value);
+    }
+
+    // expressions built with operators and partial evaluation
+    let expr = (ex!("x + 1") * ex!("y - 2")) ^ 2.0;
+    assert_eq!(expr.to_string(), "((x + 1) * (y - 2))^2");
+    let mut expr = ex!("a * x + b");
+    expr.set_var("a", 2.0).set_var("b", -3.5).partial_eval().unwrap();
+    assert_eq!(expr.to_string(), "2 * x + -3.5");
+}
+
+#[test]
+fn markup_test() {
+    let cases = [
+        ("(a + b) / (c - d) * x^2", r"\frac{a + b}{c - d} \cdot x^{2}"),
+        ("sqrt(x) + cbrt(y) - powf(x + 1, 2) * powi(x, 3)", r"\sqrt{x} + \sqrt[3]{y} - \left( x + 1 \right)^{2} \cdot x^{3}"),
+        ("sin(2 * PI * x) - ln(y) + log(x, 2) + log10(x)", r"\sin\left( 2 \cdot \pi \cdot x \right) - \ln\left( y \right) + \log_{2}\left( x \right) + \log_{10}\left( x \right)"),
+        ("-x^2 + (-x)^2 - -(a - b)", r"-x^{2} + \left( -x \right)^{2} - -\left( a - b \right)"),
+        ("abs(x - 1) + floor(x) + exp2(x) + my_func(x, y)", r"\left| x - 1 \right| + \left\lfloor x \right\rfloor + 2^{x} + \operatorname{my\_func}\left( x, y \right)"),
+        ("x > 0 ? x : -x", r"\begin{cases} x & \text{if } x > 0 \\ -x & \text{otherwise} \end{cases}"),
+        ("atan(x) * 1e-9 + 2.5e20 + E + TAU", r"\arctan\left( x \right) \cdot 10^{-9} + 2.5 \times 10^{20} + e + \tau"),
+        ("!(a && b) || c <= d != (x % 3 == 1)", r"\lnot \left( a \land b \right) \lor c \leq d \neq \left( x \bmod 3 = 1 \right)"),
+        ("(a / b)^2 + 2^(x / y)^z; alpha * beta_1", r"\left( \frac{a}{b} \right)^{2} + 2^{\left( \frac{x}{y} \right)^{z}}"),
+    ];
+    for (src, expected) in cases {
+        assert_eq!(ex!(src).to_latex()[0], expected);
+    }
+    assert_eq!(ex!("1; alpha * beta_1").to_latex(), vec!["1", r"\mathrm{alpha} \cdot \mathrm{beta\_1}"]);
+
+    let mathml = ex!("(a + 1) / 2 * x^2 - sqrt(y)").to_mathml();
+    assert_eq!(
+        mathml[0],
+        "<math xmlns=\"http://www.w3.org/1998/Math/MathML\"><mrow><mrow><mfrac><mrow><mi>a</mi><mo>+</mo><mn>1</mn></mrow><mn>2</mn></mfrac>\
+        <mo>⋅</mo><msup><mi>x</mi><mn>2</mn></msup></mrow><mo>−</mo><msqrt><mi>y</mi></msqrt></mrow></math>"
+    );
+    let mathml = ex!("sin(PI * x) < 1e-9 && if(x, -1, 2)").to_mathml();
+    assert_eq!(
+        mathml[0],
+        "<math xmlns=\"http://www.w3.org/1998/Math/MathML\"><mrow><mrow><mrow><mi>sin</mi><mo>\u{2061}</mo><mrow><mo>(</mo>\
+        <mrow><mi>π</mi><mo>⋅</mo><mi>x</mi></mrow><mo>)</mo></mrow></mrow><mo>&lt;</mo><msup><mn>10</mn><mrow><mo>−</mo><mn>9</mn></mrow></msup></mrow>\
+        <mo>∧</mo><mrow><mo>{</mo><mtable><mtr><mtd><mrow><mo>−</mo><mn>1</mn></mrow></mtd><mtd><mtext>if </mtext><mi>x</mi></mtd></mtr>\
+        <mtr><mtd><mn>2</mn></mtd><mtd><mtext>otherwise</mtext></mtd></mtr></mtable></mrow></mrow></math>"
+    );
+}
+
+#[test]
+fn substitute_test() {
+    let mut expr = ex!("r^2 * PI + 2 * r");
+    expr.substitute("r", &ex!("sqrt(x * x + y * y)")).unwrap();
+    assert_eq!(expr.to_string(), "sqrt(x * x + y * y)^2 * 3.141592653589793 + 2 * sqrt(x * x + y * y)");
+    assert_eq!(expr.vars(), vec!["x".to_owned(), "y".to_owned()]);
+    assert_eq!(expr.clone().set_var("x", 3.0).set_var("y", 4.0).eval(), Ok(25.0 * std::f64::consts::PI + 10.0));
+
+    // the result can be partially evaluated and differentiated
+    let mut partial = expr.clone();
+    partial.set_var("y", 0.0).partial_eval().unwrap();
+    assert_eq!(partial.to_string(), "sqrt(x * x + 0)^2 * 3.141592653589793 + 2 * sqrt(x * x + 0)");
+    let mut derivative = expr.derivative("x").unwrap();
+    let value = derivative.set_var("x", 3.0).set_var("y", 4.0).eval().unwrap();
+    assert!((value - (2.0 * std::f64::consts::PI * 3.0 + 2.0 * 3.0 / 5.0)).abs() < 1e-12);
+
+    // contexts are merged, with the values of the substituted expression as fallback
+    let mut inner = ex!("a * t + f(t)");
+    inner.set_var("a", 2.0).set_var("t", 100.0).set_func("f", 1, |x| x[0] + 1.0);
+    let mut outer = ex!("x * t + 1; x - 1");
+    outer.set_var("t", 3.0);
+    outer.substitute("x", &inner).unwrap();
+    assert_eq!(outer.to_string(), "(a * t + f(t)) * t + 1; a * t + f(t) - 1");
+    assert_eq!(outer.evals(), Ok(vec![31.0, 9.0]));
+
+    // multiple expressions are paired up
+    let mut expr = ex!("x + 1");
+    expr.substitute("x", &ex!("y; 2 * y")).unwrap();
+    assert_eq!(expr.to_string(), "y + 1; 2 * y + 1");
+    let mut expr = ex!("x; -x; x^2");
+    expr.substitute("x", &ex!("y + 1")).unwrap().substitute("y", &ex!("z")).unwrap();
+    assert_eq!(expr.to_string(), "z + 1; -(z + 1); (z + 1)^2");
+    assert_eq!(ex!("x; 2 * x").substitute("x", &ex!("y; y; y")).map(|_| ()), Err(EvalError::WrongExprIndex(2)));
+
+    // the named expressions of the substituted expression are inlined
+    let mut expr = ex!("x + 1");
+    expr.substitute("x", &ex!("b = y * 2; b + b")).unwrap();
+    assert_eq!(expr.to_string(), "y * 2 + 1; y * 2 + y * 2 + 1");
+    assert_eq!(expr.vars(), vec!["y".to_owned()]);
+    assert_eq!(expr.set_var("y", 1.0).evals(), Ok(vec![3.0, 5.0]));
+}
+
+#[test]
+fn assignment_test() {
+    let mut expr = ex!("r = sqrt(x^2 + y^2); area = PI * r^2; r * 2; area / r");
+    expr.set_var("x", 3.0).set_var("y", 4.0);
+    assert_eq!(expr.evals(), Ok(vec![5.0, 25.0 * std::f64::consts::PI, 10.0, 5.0 * std::f64::consts::PI]));
+    assert_eq!(expr.evals_named(), Ok(vec![("r".to_owned(), 5.0), ("area".to_owned(), 25.0 * std::f64::consts::PI)]));
+    assert_eq!(expr.eval_named("r"), Ok(5.0));
+    assert_eq!(expr.eval_index(2), Ok(10.0));
+    assert_eq!(expr.eval_named("d"), Err(EvalError::UndefinedOutput("d".to_owned())));
+    assert_eq!(expr.name(1), Some("area"));
+    assert_eq!(expr.name(2), None);
+    assert_eq!(expr.index_of("area"), Some(1));
+    assert_eq!(expr.vars(), vec!["x".to_owned(), "y".to_owned()]);
+    assert_eq!(expr.to_string(), "r = sqrt(x^2 + y^2); area = 3.141592653589793 * r^2; r * 2; area / r");
+
+    // assignments shadow the context, and reassigning uses the previous value
+    let mut expr = ex!("a = a + 1, a = a * 10, a");
+    expr.set_var("a", 1.0);
+    assert_eq!(expr.evals(), Ok(vec![2.0, 20.0, 20.0]));
+    assert_eq!(expr.eval_named("a"), Ok(20.0));
+    assert_eq!(eval_from_str("b = 2; c = b^3; b + c"), Ok(vec![2.0, 8.0, 10.0]));
+
+    // compiled and differentiated expressions see through the names
+    let expr = ex!("u = x * x; u + x");
+    let mut out = [0.0; 3];
+    expr.compile(&["x"]).eval_batch_index(1, &[Column::Slice(&[1.0, 2.0, 3.0])], &mut out).unwrap();
+    assert_eq!(out, [2.0, 6.0, 12.0]);
+    let mut derivative = expr.derivative("x").unwrap();
+    assert_eq!(derivative.set_var("x", 3.0).evals(), Ok(vec![6.0, 7.0]));
+
+    // partial evaluation does not replace names bound by earlier expressions
+    let mut expr = ex!("a = x + 1; a * 2");
+    expr.set_var("a", 100.0).set_var("x", 1.0);
+    assert_eq!(expr.evals(), Ok(vec![2.0, 4.0]));
+    expr.partial_evals().unwrap();
+    assert_eq!(expr.evals(), Ok(vec![2.0, 4.0]));
+    assert_eq!(expr.to_string(), "a = 2; a * 2");
+
+    // operators apply to the outputs of named expressions, not to the values they bind
+    let mut expr = ex!("a = x; a + 1") + ex!("1");
+    assert_eq!(expr.set_var("x", 1.0).evals(), Ok(vec![2.0, 3.0]));
+    assert_eq!(expr.evals_named(), Ok(vec![]));
+    let mut expr = 2.0 * ex!("a = a + 1, a = a * 10, a");
+    assert_eq!(expr.set_var("a", 1.0).evals(), Ok(vec![4.0, 40.0, 40.0]));
+    let mut expr = ex!("x") * ex!("b = y; b + 1");
+    assert_eq!(expr.set_var("x", 2.0).set_var("y", 3.0).evals(), Ok(vec![6.0, 8.0]));
+
+    // `=` is only allowed after the leading name of a statement
+    assert!(matches!(Expr::new("1 = x"), Err(EvalError::WrongExpression(_))));
+    assert!(matches!(Expr::new("a = b = 1"), Err(EvalError::WrongExpression(_))));
+    assert_eq!(eval_from_str("1 == 1"), Ok(vec![1.0]));
+}
+
+#[test]
+fn formula_sheet_test() {
+    let mut sheet = FormulaSheet::new();
+    sheet.set_formula("ratio", "margin / revenue").unwrap()
+        .set_formula("margin", "revenue - cost").unwrap()
+        .set_formula("tax", "max(margin, 0) * rate").unwrap();
+    sheet.set_value("revenue", 200.0).set_value("cost", 150.0).set_value("rate", 0.2);
+    assert_eq!(sheet.order(), ["margin", "ratio", "tax"]);
+    assert_eq!(sheet.get("margin"), None);
+    assert_eq!(sheet.recompute(), Ok(3));
+    assert_eq!(sheet.get("margin"), Some(50.0));
+    assert_eq!(sheet.get("ratio"), Some(0.25));
+    assert_eq!(sheet.get("tax"), Some(10.0));
+
+    // only the formulas depending on a changed input are evaluated again
+    sheet.set_value("rate", 0.3);
+    assert_eq!(sheet.get("tax"), None);
+    assert_eq!(sheet.get("ratio"), Some(0.25));
+    assert_eq!(sheet.recompute(), Ok(1));
+    assert!((sheet.get("tax").unwrap() - 15.0).abs() < 1e-12);
+    sheet.set_value("cost", 100.0);
+    assert_eq!(sheet.recompute(), Ok(3));
+    assert_eq!(sheet.get("ratio"), Some(0.5));
+    sheet.set_value("cost", 100.0);
+    assert_eq!(sheet.recompute(), Ok(0));
+
+    // cycles are rejected and leave the sheet unchanged
+    assert_eq!(
+        sheet.set_formula("revenue", "ratio * 1000").map(|_| ()),
+        Err(EvalError::CyclicDependency(vec!["margin".to_owned(), "revenue".to_owned(), "ratio".to_owned(), "margin".to_owned()]))
+    );
+    assert_eq!(
+        sheet.set_formula("x", "x + 1").unwrap_err().to_string(),
+        "formulas form a cycle: x -> x"
+    );
+    assert_eq!(sheet.get("revenue"), Some(200.0));
+    assert_eq!(sheet.order(), ["margin", "ratio", "tax"]);
+
+    // formulas and inputs can replace each other
+    sheet.set_formula("cost", "revenue * 0.4").unwrap();
+    assert_eq!(sheet.order(), ["cost", "margin", "ratio", "tax"]);
+    assert_eq!(sheet.recompute(), Ok(4));
+    assert_eq!(sheet.get("margin"), Some(120.0));
+    sheet.set_value("margin", 10.0);
+    assert_eq!(sheet.recompute(), Ok(2));
+    assert_eq!(sheet.get("ratio"), Some(0.05));
+
+    // errors leave the failed formula pending
+    let mut sheet = FormulaSheet::new();
+    sheet.set_formula("y", "f(x) * 2").unwrap().set_func("f", 1, |x| x[0] + 1.0);
+    assert_eq!(sheet.recompute(), Err(EvalError::UndefinedVariable("x".to_owned())));
+    sheet.set_value("x", 1.0);
+    assert_eq!(sheet.recompute(), Ok(1));
+    assert_eq!(sheet.get("y"), Some(4.0));
+
+    // the context of a formula takes precedence over the sheet and is not shared
+    let mut expr = ex!("f(x) * k + g(0)");
+    expr.set_var("k", 10.0).set_func("f", 1, |x| x[0] * 3.0).set_func("g", 1, |_| 0.0);
+    sheet.set_expr("z", expr).unwrap().set_value("k", 2.0).set_formula("w", "f(x) * k").unwrap();
+    assert_eq!(sheet.recompute(), Ok(2));
+    assert_eq!((sheet.get("z"), sheet.get("w")), (Some(30.0), Some(4.0)));
+    sheet.set_value("k", 5.0);
+    assert_eq!(sheet.recompute(), Ok(1));
+    assert_eq!((sheet.get("z"), sheet.get("w")), (Some(30.0), Some(10.0)));
+    assert_eq!(sheet.set_formula("v", "g(1)").unwrap().recompute(), Err(EvalError::UndefinedFunction("g".to_owned())));
+
+    // replacing a function evaluates the formulas calling it again
+    sheet.set_func("g", 1, |x| x[0] * 7.0);
+    assert_eq!(sheet.recompute(), Ok(1));
+    assert_eq!(sheet.get("v"), Some(7.0));
+    sheet.set_func("f", 1, |x| x[0] - 1.0);
+    assert_eq!(sheet.recompute(), Ok(2));
+    assert_eq!((sheet.get("y"), sheet.get("w"), sheet.get("z")), (Some(0.0), Some(0.0), Some(30.0)));
+
+    // only the first expression of a formula is evaluated, so only it has dependencies
+    sheet.set_formula("b", "1; a").unwrap().set_formula("a", "b + 1").unwrap();
+    assert_eq!(sheet.recompute(), Ok(2));
+    assert_eq!(sheet.get("a"), Some(2.0));
+}
+
+#[test]
+fn cell_ref_test() {
+    // a 3x3 grid where B3 is empty
+    struct Grid(Vec<Vec<Option<f64>>>);
+    impl CellResolver for Grid {
+        fn cell(&self, cell: CellRef) -> Option<f64> {
+            *self.0.get(cell.row)?.get(cell.col)?
+        }
+    }
+    let grid = || Grid(vec![
+        vec![Some(1.0), Some(2.0), Some(3.0)],
+        vec![Some(4.0), Some(5.0), Some(6.0)],
+        vec![Some(7.0), None, Some(9.0)],
+    ]);
+
+    assert_eq!("AB12".parse::<CellRef>(), Ok(CellRef::new(27, 11)));
+    assert_eq!("$B$2".parse::<CellRef>(), Ok(CellRef::new(1, 1)));
+    assert_eq!(CellRef::new(701, 0).to_string(), "ZZ1");
+    assert!("A0".parse::<CellRef>().is_err());
+    assert!("b2".parse::<CellRef>().is_err());
+
+    let mut expr = Expr::with_cell_refs("SUM(A1:C3); AVERAGE(A1:A3, $B$2) * 2; COUNT(B1:B3); A1 + $C$3").unwrap();
+    expr.set_resolver(grid());
+    assert_eq!(expr.evals(), Ok(vec![37.0, 8.5, 2.0, 10.0]));
+    assert_eq!(expr.to_string(), "SUM(A1:C3); AVERAGE(A1:A3, B2) * 2; COUNT(B1:B3); A1 + C3");
+    assert_eq!(expr.vars(), vec!["A1".to_owned(), "B2".to_owned(), "C3".to_owned()]);
+    // values in the context take precedence over the resolver
+    expr.set_var("C3", 0.0);
+    assert_eq!(expr.eval_index(3), Ok(1.0));
+    assert_eq!(expr.to_latex()[0], r"\operatorname{SUM}\left( \mathrm{A1:C3} \right)");
+
+    // empty cells are undefined, but skipped by ranges
+    let mut expr = Expr::with_cell_refs("B3 + 1").unwrap();
+    expr.set_resolver(grid());
+    assert_eq!(expr.eval(), Err(EvalError::UndefinedVariable("B3".to_owned())));
+    assert_eq!(Expr::with_cell_refs("SUM(A1:A2)").unwrap().eval(), Err(EvalError::UndefinedVariable("A1:A2".to_owned())));
+
+    // ranges work in compiled code, derivatives and gradients
+    let mut expr = Expr::with_cell_refs("SUM(A1:B2, x) * x").unwrap();
+    expr.set_resolver(grid());
+    let mut out = [0.0; 2];
+    expr.eval_batch(&[("x", Column::Slice(&[1.0, 2.0]))], &mut out).unwrap();
+    assert_eq!(out, [13.0, 28.0]);
+    assert_eq!(expr.set_var("x", 2.0).eval_with_gradient(&["x"]), Ok((28.0, vec![16.0])));
+    assert_eq!(expr.derivative("x").unwrap().eval(), Ok(16.0));
+    let mut expr = Expr::with_cell_refs("AVERAGE(A1:C1, x)").unwrap();
+    expr.set_resolver(grid()).set_var("x", 6.0);
+    assert_eq!(expr.eval_with_gradient(&["x"]), Ok((3.0, vec![0.25])));
+    assert_eq!(expr.derivative("x").unwrap().eval(), Ok(0.25));
+    struct Ones;
+    impl CellResolver for Ones {
+        fn cell(&self, _: CellRef) -> Option<f64> {Some(1.0)}
+    }
+    let mut expr = Expr::with_cell_refs("SUM(A1:Z1000) + AVERAGE(x, A1:B2, 2 * x) + COUNT(A1:C3)").unwrap();
+    expr.set_resolver(Ones);
+    let mut out = [0.0; 300];
+    let xs: Vec<f64> = (0..300).map(|i| i as f64).collect();
+    expr.eval_batch(&[("x", Column::Slice(&xs))], &mut out).unwrap();
+    assert_eq!(out[299], 26009.0 + (299.0 * 3.0 + 4.0) / 6.0);
+    assert_eq!(expr.compile(&["x"]).eval(&[5.0]), expr.set_var("x", 5.0).eval());
+
+    // ranges are only arguments of aggregates, and `cond ? A1 : B1` is still a conditional
+    assert!(matches!(Expr::with_cell_refs("A1:B2 + 1"), Err(EvalError::WrongExpression(_))));
+    assert!(matches!(Expr::with_cell_refs("max(A1:B2)"), Err(EvalError::WrongExpression(_))));
+    assert!(matches!(Expr::with_cell_refs("A1:B2"), Err(EvalError::WrongExpression(_))));
+    let mut expr = Expr::with_cell_refs("A1 > 0 ? A1 : B1").unwrap();
+    expr.set_resolver(grid());
+    assert_eq!(expr.eval(), Ok(1.0));
+
+    // ranges moved out of an aggregate fail at evaluation and point at the source
+    let source = "1 + SUM($A$1:B2)";
+    let mut expr = Expr::with_cell_refs(source).unwrap();
+    expr.set_resolver(grid());
+    if let Node::Binary(_, _, rhs) = &mut expr.nodes_mut()[0] {
+        **rhs = rhs.children()[0].clone();
+    }
+    let err = EvalError::MisplacedRange("A1:B2".to_owned());
+    assert_eq!(expr.eval(), Err(err.clone()));
+    assert_eq!(expr.compile(&[]).eval(&[]), Err(err.clone()));
+    assert_eq!(err.diagnostic(source), "range A1:B2 is not an argument of SUM, AVERAGE or COUNT\n1 + SUM($A$1:B2)\n        ^^^^^^^");
+
+    // without the opt-in, cells are plain variables and aggregates are custom functions
+    assert!(Expr::new("SUM(A1:B2)").is_err());
+    assert_eq!(ex!("SUM(A1)").nodes(), [Node::call(Function::Custom("SUM".to_owned()), vec![Node::Var("A1".to_owned())])]);
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Int(i64);
+
+impl Numeric for Int {
+    fn from_f64(v: Value) -> Self {
+        Int(v as i64)
+    }
+
+    fn to_bool(&self) -> bool {
+        self.0 != 0
+    }
+
+    fn unary(op: UnaryOp, arg: Self) -> Result<Self, EvalError> {
+        f64::unary(op, arg.0 as Value).map(Int::from_f64)
+    }
+
+    fn binary(op: BinaryOp, lhs: Self, rhs: Self) -> Result<Self, EvalError> {
+        match op {
+            BinaryOp::Div if rhs.0 == 0 => Err(EvalError::UnsupportedFunction("division by zero".to_owned())),
+            BinaryOp::Div => Ok(Int(lhs.0 / rhs.0)),
+            _ => f64::binary(op, lhs.0 as Value, rhs.0 as Value).map(Int::from_f64),
+        }
+    }
+
+    fn supports(func: &Function) -> bool {
+        matches!(func, Function::Abs | Function::Min | Function::Max)
+    }
+
+    fn call(func: &Function, args: &[Self]) -> Result<Self, EvalError> {
+        let values: Vec<Value> = args.iter().map(|v| v.0 as Value).collect();
+        f64::call(func, &values).map(Int::from_f64)
+    }
+}
+
+#[test]
+fn numeric_test() {
+    // f32 computes in f64 and rounds every step
+    let mut expr = Expr::<f32>::parse("x / 3; sin(x) > 0 ? 1 : 2").unwrap();
+    expr.set_var("x", 1.0);
+    assert_eq!(expr.evals(), Ok(vec![1.0f32 / 3.0, 1.0]));
+    let expr = expr * 3.0;
+    assert_eq!(expr.eval(), Ok(1.0));
+    let mut ctx = Context::<f32>::default();
+    ctx.set_value("y", 2.5).set_func("half", 1, |args| args[0] / 2.0);
+    assert_eq!(ctx.eval("half(y) * 2"), Ok(2.5f32));
+    assert_eq!(eval_from_str_with_context("y; y + 1", &ctx), Ok(vec![2.5f32, 3.5]));
+
+    // each type decides which built-in functions it provides
+    let expr = Expr::<Int>::parse("a = 7 / 2; max(a, -5) * abs(-2)").unwrap();
+    assert_eq!(expr.evals(), Ok(vec![Int(3), Int(6)]));
+    assert_eq!(expr.eval_named("a"), Ok(Int(3)));
+    assert_eq!(Expr::<Int>::parse("sin(1)").unwrap().eval(), Err(EvalError::UnsupportedFunction("sin".to_owned())));
+    assert!(Expr::<Int>::parse("1 / x").unwrap().set_var("x", Int(0)).eval().is_err());
+    let mut ctx = Context::<Int>::default();
+    ctx.set_func("sq", 1, |args| Int(args[0].0 * args[0].0));
+    assert_eq!(ctx.evals("sq(3) - 1; 1 && 0"), Ok(vec![Int(8), Int(0)]));
+
+    // real number types do not support the functions of complex numbers
+    let unsupported = |name: &str| EvalError::UnsupportedFunction(name.to_owned());
+    let mut expr = Expr::from_nodes(vec![Node::call(Function::Re, vec![Node::Var("x".to_owned())])]);
+    expr.set_var("x", 2.0);
+    assert_eq!(expr.eval(), Err(unsupported("re")));
+    assert_eq!(expr.compile(&["x"]).eval(&[2.0]), Err(unsupported("re")));
+    assert_eq!(expr.eval_with_gradient(&["x"]).map(|_| ()), Err(unsupported("re")));
+    assert_eq!(expr.derivative("x").map(|_| ()), Err(unsupported("re")));
+    assert_eq!(expr.eval_interval(&[("x", Interval::new(0.0, 1.0))]).map(|_| ()), Err(unsupported("re")));
+    assert_eq!(expr.partial_eval().unwrap().eval(), Err(unsupported("re")));
+    assert_eq!(expr.simplify().eval(), Err(unsupported("re")));
+    assert_eq!(Expr::from_nodes(vec![Node::call(Function::ImaginaryUnit, vec![])]).eval(), Err(unsupported("i")));
+}
+
+#[cfg(feature = "complex")]
+#[test]
+fn complex_test() {
+    let close = |z: Complex64, w: Complex64| (z - w).norm() < 1e-12;
+    let eval = |s: &str| Expr::<Complex64>::parse(s).unwrap().eval().unwrap();
+    assert_eq!(eval("sqrt(-1)"), Complex64::i());
+    assert_eq!(eval("abs(3+4i)"), Complex64::new(5.0, 0.0));
+    assert!(close(eval("exp(i*PI)"), Complex64::new(-1.0, 0.0)));
+    assert_eq!(eval("(1 + 2j) * (3 - 1.5e1i)"), Complex64::new(33.0, -9.0));
+    assert_eq!(eval("2^3i"), eval("2^(3 * i)"));
+    assert_eq!(eval("i^2"), Complex64::new(-1.0, 0.0));
+    assert!(close(eval("(-8)^(1/3)"), Complex64::new(1.0, 3f64.sqrt())));
+    assert!(close(eval("ln(-1)"), Complex64::new(0.0, std::f64::consts::PI)));
+    assert!(close(eval("sin(i)"), Complex64::new(0.0, 1f64.sinh())));
+    assert!(close(eval("cosh(i * PI)"), Complex64::new(-1.0, 0.0)));
+    assert!(close(eval("powf(i, i)"), Complex64::new((-std::f64::consts::FRAC_PI_2).exp(), 0.0)));
+
+    // re, im, conj and arg
+    let mut expr = Expr::<Complex64>::parse("re(z); im(z); conj(z); arg(z); z * conj(z); max(z, 0)").unwrap();
+    expr.set_var("z", Complex64::new(-1.0, 1.0));
+    let values = expr.evals().unwrap();
+    assert_eq!(values[..3], [Complex64::new(-1.0, 0.0), Complex64::new(1.0, 0.0), Complex64::new(-1.0, -1.0)]);
+    assert!(close(values[3], Complex64::new(3.0 * std::f64::consts::FRAC_PI_4, 0.0)));
+    assert_eq!(values[4..], [Complex64::new(2.0, 0.0), Complex64::new(0.0, 0.0)]);
+
+    // context variables, and `i` as a variable when set
+    let mut ctx = Context::<Complex64>::default();
+    ctx.set_value("z", Complex64::new(0.0, 2.0)).set_func("sq", 1, |args| args[0] * args[0]);
+    assert_eq!(ctx.eval("sq(z) + 1"), Ok(Complex64::new(-3.0, 0.0)));
+    ctx.set_value("i", Complex64::new(2.0, 0.0));
+    assert_eq!(ctx.eval("i + 1"), Ok(Complex64::new(3.0, 0.0)));
+
+    // imaginary literals are not affected by a variable `i`, and display as literals
+    let mut expr = Expr::<Complex64>::parse("4i + 1; 1j * i; 2^1.5i").unwrap();
+    expr.set_var("i", Complex64::new(2.0, 0.0));
+    assert_eq!(expr.evals().unwrap()[..2], [Complex64::new(1.0, 4.0), Complex64::new(0.0, 2.0)]);
+    assert_eq!(expr.to_string(), "4 * 1i + 1; 1i * i; 2^(1.5 * 1i)");
+    let reparsed = Expr::<Complex64>::parse(&expr.to_string()).unwrap();
+    assert_eq!(reparsed.nodes(), expr.nodes());
+    assert_eq!(expr.to_latex()[0], ex!("4 * i + 1").to_latex()[0]);
+
+    // real number types keep `4i`, `re` and `infi` as before
+    assert!(Expr::new("4i").is_err());
+    assert_eq!(ex!("re(x)").nodes(), [Node::call(Function::Custom("re".to_owned()), vec![Node::Var("x".to_owned())])]);
+    assert_eq!(Expr::<Complex64>::parse("infi").unwrap().vars(), vec!["infi".to_owned()]);
+}
+
+#[test]
+fn interval_test() {
+    let x = Interval::new(0.0, 2.0);
+    let y = Interval::new(-1.0, 1.0);
+    let eval = |s: &str| ex!(s).eval_interval(&[("x", x), ("y", y)]).unwrap();
+    // enclosures are rounded outward, but only by a few ulps
+    let encloses = |r: Interval, lo: f64, hi: f64| r.lo <= lo && hi <= r.hi && lo - r.lo < 1e-12 && r.hi - hi < 1e-12;
+    assert!(encloses(eval("x * y + 1"), -1.0, 3.0));
+    assert!(encloses(eval("x - x"), -2.0, 2.0));
+    assert!(encloses(eval("x^2 - y^2"), -1.0, 4.0));
+    assert!(encloses(eval("(x + 1) / (y + 2)"), 1.0 / 3.0, 3.0));
+    assert!(encloses(eval("sqrt(x) * exp(y)"), 0.0, 2f64.sqrt() * 1f64.exp()));
+    assert!(encloses(eval("sin(x + 1)"), 3f64.sin(), 1.0));
+    assert!(encloses(eval("cos(4 * x)"), -1.0, 1.0));
+    assert!(encloses(eval("(x + 3.5) % 3"), 0.5, 2.5));
+    assert!(eval("7 % (x + 3)").contains(0.0) && eval("7 % (x + 3)").contains(3.4));
+    assert!(encloses(eval("abs(y) + min(x, y) + floor(x / 3)"), -1.0, 2.0));
+    assert!(encloses(eval("x > 1 ? x : -x"), -2.0, 2.0));
+    assert_eq!(eval("x >= 0 && y <= 1"), Interval::point(1.0));
+    assert_eq!(eval("x < y"), Interval::new(0.0, 1.0));
+
+    // division by intervals containing zero, and poles, are unbounded
+    assert_eq!(eval("1 / y"), Interval::ENTIRE);
+    assert_eq!(eval("(x + 1) / (y + 1)").hi, f64::INFINITY);
+    assert!(eval("(x + 1) / (y + 1)").lo > 0.0);
+    assert_eq!(eval("tan(x)"), Interval::ENTIRE);
+    assert_eq!(eval("ln(x)").lo, f64::NEG_INFINITY);
+    assert!(eval("1 / (y - 5)").hi < 0.0);
+    assert!(eval("sqrt(y - 2)").is_empty());
+    assert_eq!(eval("x / 0"), Interval::ENTIRE);
+    assert!(eval("x % 0").is_empty() && eval("rem_euclid(x, 0)").is_empty());
+    assert_eq!(eval("x % 0 == 0"), Interval::point(0.0));
+    assert_eq!(eval("rem_euclid(x, 0) == 0"), Interval::point(0.0));
+    assert!(eval("x % y").contains(0.5));
+
+    // overflowing bounds keep the largest finite value on the inner side
+    for m in [f64::MAX, -f64::MAX] {
+        for s in ["x + x", "x * 2"] {
+            let r = ex!(s).eval_interval(&[("x", Interval::point(m))]).unwrap();
+            assert_eq!(r, if m > 0.0 {Interval::new(f64::MAX, f64::INFINITY)} else {Interval::new(f64::NEG_INFINITY, -f64::MAX)}, "{} at {}", s, m);
+        }
+    }
+
+    // NaN results of out-of-domain arguments behave as they do for `f64`
+    let neg = Interval::new(-1.5, -0.5);
+    for s in ["max(0, sqrt(x))", "min(sqrt(x), x)", "sqrt(x) && 1", "!sqrt(x) || x", "sqrt(x) ? 2 : 3", "sqrt(x) < 1", "sqrt(x) != 1",
+              "x / 0", "0^(x + 0.5)", "powi(0, -1) + x", "sqrt(x)^0", "1^sqrt(x)", "hypot(sqrt(x), 1 / 0)"] {
+        let mut expr = ex!(s);
+        let r = expr.eval_interval(&[("x", neg)]).unwrap();
+        for i in 0..=10 {
+            let v = expr.set_var("x", -1.5 + i as f64 / 10.0).eval().unwrap();
+            assert!(v.is_nan() || r.contains(v), "{}: {} not in {}", s, v, r);
+        }
+    }
+
+    // every point result lies in the enclosure
+    let mut expr = ex!("atan2(y, x) + x^y - hypot(x, y) * cosh(y) + powi(y, 3) - log(x + 1, 2)");
+    expr.set_func("atan2", 2, |args| args[0].atan2(args[1]));
+    let r = expr.eval_interval(&[("x", x), ("y", Interval::point(0.5))]).unwrap();
+    for i in 0..=20 {
+        let v = expr.set_var("x", i as f64 / 10.0).set_var("y", 0.5).eval().unwrap();
+        assert!(r.contains(v), "{} not in {}", v, r);
+    }
+    // custom functions are only evaluated at points
+    assert_eq!(r.lo, f64::NEG_INFINITY);
+    assert!(expr.eval_interval(&[("x", Interval::point(1.0)), ("y", Interval::point(0.5))]).unwrap().contains(expr.set_var("x", 1.0).eval().unwrap()));
+
+    // the number type is also available to expressions directly
+    let mut expr = Expr::<Interval>::parse("a * b").unwrap();
+    expr.set_var("a", Interval::new(-2.0, 3.0)).set_var("b", Interval::new(1.0, f64::INFINITY));
+    assert_eq!(expr.eval(), Ok(Interval::ENTIRE));
+    assert_eq!(Interval::new(1.0, 2.0).to_string(), "[1, 2]");
+}
+
+#[cfg(feature = "rational")]
+#[test]
+fn rational_test() {
+    let q = |numer: i128, denom: i128| BigRational::new(numer.into(), denom.into());
+    let eval = |s: &str| Expr::<BigRational>::parse(s).unwrap().eval();
+    assert_eq!(eval("1/3 + 1/6"), Ok(q(1, 2)));
+    assert_eq!(eval("0.1 + 0.2"), Ok(q(3, 10)));
+    assert_eq!(eval("-1.25e-3 * 8"), Ok(q(-1, 100)));
+    assert_eq!(eval("7/2 % 1"), Ok(q(1, 2)));
+    assert_eq!(eval("(2/3)^-2 + 1e20"), Ok(q(400000000000000000009, 4)));
+    // literals keep the digits that `f64` rounds away
+    assert_eq!(eval("0.1000000000000000000001 - 0.1"), Ok(q(1, 10i128.pow(22))));
+    assert_eq!(eval("123456789012345678901234567890"), Ok(BigRational::from_integer("123456789012345678901234567890".parse().unwrap())));
+    assert_eq!(eval("2.50000000000000000001e-1 * 4"), Ok(q(10i128.pow(21) + 4, 10i128.pow(21))));
+    assert_eq!(ex!("0.1000000000000000000001 - 0.1").eval(), Ok(0.0));
+    // values without a finite rational are errors rather than zero
+    assert_eq!(eval("1e1001 > 0"), Err(EvalError::Inexact("1e1001".to_string())));
+    assert_eq!(eval("inf"), Err(EvalError::Inexact("inf".to_string())));
+    assert_eq!(eval("10^2000000000"), Err(EvalError::Inexact("^".to_string())));
+    assert_eq!(eval("1^2000000000 + 2^-3"), Ok(q(9, 8)));
+    assert_eq!(eval("sqrt(9/4) + (-8)^(1/3) + hypot(3, 4)"), Ok(q(9, 2)));
+    assert_eq!(
+        Expr::<BigRational>::parse("floor(-7/2); round(5/2); rem_euclid(-7, 3); div_euclid(7, -2); 1/3 < 0.34").unwrap().evals(),
+        Ok(vec![q(-4, 1), q(3, 1), q(2, 1), q(-3, 1), q(1, 1)])
+    );
+
+    // non-rational results and functions fail unless the float fallback is enabled
+    assert_eq!(eval("sin(0)"), Err(EvalError::UnsupportedFunction("sin".to_string())));
+    assert_eq!(eval("sqrt(2)"), Err(EvalError::Inexact("sqrt".to_string())));
+    assert_eq!(eval("2^0.5"), Err(EvalError::Inexact("^".to_string())));
+    assert_eq!(eval("1 / (3 - 3)"), Err(EvalError::Inexact("/".to_string())));
+    let mut expr = Expr::<BigRational>::parse("sqrt(x) * 2; ln(x - 2); 1 / 4").unwrap();
+    expr.set_var("x", q(2, 1)).set_float_fallback(true);
+    assert_eq!(expr.eval_index(0), Ok(BigRational::from_f64(2f64.sqrt()) * q(2, 1)));
+    assert_eq!(expr.eval_index(1), Err(EvalError::Inexact("ln".to_string())));
+    assert_eq!(expr.eval_index(2), Ok(q(1, 4)));
+
+    let mut ctx = Context::<BigRational>::default();
+    ctx.set_value("rate", q(1, 20)).set_float_fallback(true);
+    assert_eq!(ctx.eval("100 * (1 + rate)^2"), Ok(q(441, 4)));
+    assert_eq!(ctx.eval("exp(0)"), Ok(q(1, 1)));
+}